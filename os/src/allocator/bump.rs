@@ -24,6 +24,16 @@ impl BumpAllocator {
         self.heap_end = heap_start + heap_size;
         self.next = heap_start;
     }
+
+    /// Bytes up to the next free address. Freed memory only comes back
+    /// once every allocation is gone.
+    pub fn used(&self) -> usize {
+        self.next - self.heap_start
+    }
+
+    pub fn allocations(&self) -> usize {
+        self.allocations
+    }
 }
 
 unsafe impl GlobalAlloc for Locked<BumpAllocator> {
@@ -37,6 +37,12 @@ pub fn init_heap(
     Ok(())
 }
 
+/// Bytes of the heap in use and the number of live allocations.
+pub fn usage() -> (usize, usize) {
+    let heap = super::ALLOCATOR.lock();
+    (heap.used(), heap.allocations())
+}
+
 pub struct Locked<A> {
     inner: spin::Mutex<A>,
 }
@@ -0,0 +1,267 @@
+//Sector cache: an LRU buffer cache between the filesystems and the block devices.
+//Writes are held as dirty sectors and written back on `flush`, on eviction
+//and periodically from the timer interrupt.
+
+use crate::drivers::block;
+use crate::timer;
+use crate::println;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use spin::Mutex;
+use lazy_static::lazy_static;
+
+pub const CACHE_SECTORS: usize = 128;
+
+// 5 seconds at the 50Hz the PIT is programmed with in `crate::init`.
+pub const FLUSH_INTERVAL: usize = 250;
+
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Stats {
+    pub hits: u64,
+    pub misses: u64,
+    pub writebacks: u64,
+    pub evictions: u64,
+}
+
+struct Slot {
+    dev: usize,
+    lba: u64,
+    data: Vec<u8>,
+    dirty: bool,
+    last_used: u64,
+}
+
+pub struct Cache {
+    slots: Vec<Slot>,
+    index: BTreeMap<(usize, u64), usize>,
+    clock: u64,
+    dirty: usize,
+    last_flush: usize,
+    writeback: bool,
+    pub stats: Stats,
+}
+
+lazy_static! {
+    pub static ref CACHE: Mutex<Cache> = Mutex::new(Cache {
+        slots: Vec::with_capacity(CACHE_SECTORS),
+        index: BTreeMap::new(),
+        clock: 0,
+        dirty: 0,
+        last_flush: 0,
+        writeback: false,
+        stats: Default::default(),
+    });
+}
+
+impl Cache {
+    fn touch(&mut self, slot: usize) {
+        self.clock += 1;
+        self.slots[slot].last_used = self.clock;
+    }
+
+    // Returns a slot for (dev, lba), evicting the least recently used sector
+    // (and writing it back first if it is dirty) once the cache is full.
+    fn take_slot(&mut self, dev: usize, lba: u64) -> Result<usize, block::Error> {
+        let slot = if self.slots.len() < CACHE_SECTORS {
+            self.slots.push(Slot {
+                dev: dev,
+                lba: lba,
+                data: Vec::new(),
+                dirty: false,
+                last_used: 0,
+            });
+            self.slots.len() - 1
+        } else {
+            let mut lru = 0;
+            for i in 1..self.slots.len() {
+                if self.slots[i].last_used < self.slots[lru].last_used {
+                    lru = i;
+                }
+            }
+
+            self.write_back(lru)?;
+            let old = (self.slots[lru].dev, self.slots[lru].lba);
+            if self.index.get(&old) == Some(&lru) {
+                self.index.remove(&old);
+            }
+            self.stats.evictions += 1;
+            lru
+        };
+
+        self.slots[slot].dev = dev;
+        self.slots[slot].lba = lba;
+        self.slots[slot].dirty = false;
+        self.index.insert((dev, lba), slot);
+        self.touch(slot);
+        Ok(slot)
+    }
+
+    fn write_back(&mut self, slot: usize) -> Result<(), block::Error> {
+        if !self.slots[slot].dirty {
+            return Ok(());
+        }
+
+        let s = &self.slots[slot];
+        block::write(s.dev, s.lba, &s.data)?;
+        self.slots[slot].dirty = false;
+        self.dirty -= 1;
+        self.stats.writebacks += 1;
+        Ok(())
+    }
+
+    fn flush(&mut self, dev: Option<usize>) -> Result<(), block::Error> {
+        // The index is ordered by (device, lba), so sectors go out in disk order.
+        let slots: Vec<usize> = self.index.iter()
+            .filter(|(k, _)| dev.map_or(true, |d| k.0 == d))
+            .map(|(_, v)| *v)
+            .collect();
+
+        let mut res = Ok(());
+        for slot in slots {
+            if let Err(e) = self.write_back(slot) {
+                res = Err(e);
+            }
+        }
+        self.last_flush = timer::TIMER.lock().ticks;
+        res
+    }
+}
+
+pub fn read(dev: usize, lba: u64) -> Result<Vec<u8>, block::Error> {
+    let mut c = CACHE.lock();
+
+    if let Some(&slot) = c.index.get(&(dev, lba)) {
+        c.stats.hits += 1;
+        c.touch(slot);
+        return Ok(c.slots[slot].data.clone());
+    }
+
+    c.stats.misses += 1;
+    let data = block::read(dev, lba)?;
+    let slot = c.take_slot(dev, lba)?;
+    c.slots[slot].data.clear();
+    c.slots[slot].data.extend_from_slice(&data);
+    Ok(data)
+}
+
+/// Reads `count` consecutive sectors. Cached sectors come from the cache (so
+/// dirty ones are seen); each run of uncached sectors is read from the device
+/// in one go and not cached, so one large read doesn't evict everything else.
+pub fn read_many(dev: usize, lba: u64, count: u64) -> Result<Vec<u8>, block::Error> {
+    let mut buf: Vec<u8> = Vec::with_capacity(count as usize * 512);
+    let mut i = 0;
+
+    while i < count {
+        {
+            let mut c = CACHE.lock();
+            if let Some(&slot) = c.index.get(&(dev, lba + i)) {
+                c.stats.hits += 1;
+                c.touch(slot);
+                buf.extend_from_slice(&c.slots[slot].data);
+                i += 1;
+                continue;
+            }
+        }
+
+        let mut n = 1;
+        {
+            let c = CACHE.lock();
+            while i + n < count && !c.index.contains_key(&(dev, lba + i + n)) {
+                n += 1;
+            }
+        }
+        CACHE.lock().stats.misses += n;
+        buf.extend_from_slice(&block::read_many(dev, lba + i, n)?);
+        i += n;
+    }
+
+    Ok(buf)
+}
+
+pub fn write(dev: usize, lba: u64, buf: &[u8]) -> Result<(), block::Error> {
+    let d = block::get(dev).ok_or(block::Error::NoDevice)?;
+    if d.read_only {
+        return Err(block::Error::ReadOnly);
+    }
+    if lba >= d.sectors {
+        return Err(block::Error::OutOfRange);
+    }
+    if buf.len() != d.sector_size {
+        return Err(block::Error::BadBuffer);
+    }
+
+    let mut c = CACHE.lock();
+    let slot = match c.index.get(&(dev, lba)) {
+        Some(&s) => {
+            c.stats.hits += 1;
+            c.touch(s);
+            s
+        },
+        None => {
+            c.stats.misses += 1;
+            c.take_slot(dev, lba)?
+        },
+    };
+
+    if !c.slots[slot].dirty {
+        c.slots[slot].dirty = true;
+        c.dirty += 1;
+    }
+    c.slots[slot].data.clear();
+    c.slots[slot].data.extend_from_slice(buf);
+    Ok(())
+}
+
+/// Writes every dirty sector back to its device.
+pub fn flush() -> Result<(), block::Error> {
+    CACHE.lock().flush(None)
+}
+
+/// Writes back the dirty sectors of one device, e.g. before it is unmounted.
+pub fn flush_device(dev: usize) -> Result<(), block::Error> {
+    CACHE.lock().flush(Some(dev))
+}
+
+/// Drops every cached sector of `dev` without writing it back.
+pub fn invalidate(dev: usize) {
+    let mut c = CACHE.lock();
+    let keys: Vec<(usize, u64)> = c.index.keys().filter(|k| k.0 == dev).cloned().collect();
+    for k in keys {
+        let slot = c.index.remove(&k).unwrap();
+        if c.slots[slot].dirty {
+            c.slots[slot].dirty = false;
+            c.dirty -= 1;
+        }
+        c.slots[slot].last_used = 0;
+    }
+}
+
+/// Lets the timer write dirty sectors back. Only enabled once booting is done,
+/// after which all disk I/O happens with interrupts disabled (from the
+/// keyboard handler) and cannot be interleaved with a timer flush.
+pub fn enable_writeback() {
+    CACHE.lock().writeback = true;
+}
+
+/// Called from the timer interrupt.
+pub fn tick() {
+    let ticks = timer::TIMER.lock().ticks;
+
+    if let Some(mut c) = CACHE.try_lock() {
+        if c.writeback && c.dirty > 0 && ticks - c.last_flush >= FLUSH_INTERVAL {
+            if let Err(e) = c.flush(None) {
+                println!("[CACHE] write-back failed: {}", e);
+            }
+        }
+    }
+}
+
+pub fn stats() -> Stats {
+    CACHE.lock().stats
+}
+
+/// Returns (cached sectors, dirty sectors).
+pub fn usage() -> (usize, usize) {
+    let c = CACHE.lock();
+    (c.index.len(), c.dirty)
+}
@@ -3,12 +3,18 @@ use alloc::collections::BTreeMap;
 use alloc::slice::SliceConcatExt;
 use spin::Mutex;
 use lazy_static::lazy_static;
-use alloc::string::String;
+use alloc::string::{String, ToString};
+use alloc::format;
 use crate::vga_buffer;
 use crate::println;
 use crate::drivers::cmos;
+use crate::drivers::block;
+use crate::drivers::partition;
 use crate::vfs;
 use crate::console;
+use crate::cache;
+use crate::wfs;
+use crate::time;
 use bit_field::BitField;
 use crate::print;
 
@@ -54,14 +60,14 @@ pub fn init() {
 
     let ls = Command {
         name: String::from("ls"),
-        desc: String::from("list files"),
+        desc: String::from("list files (-l for details, -a to show hidden files)"),
         func: ls_fn,
     };
     init_command(String::from("ls"), ls);
 
     let read = Command {
         name: String::from("read"),
-        desc: String::from("get contents of a file"),
+        desc: String::from("get contents of a file (read <file> [offset] [length])"),
         func: read_fn,
     };
     init_command(String::from("read"), read);
@@ -96,17 +102,94 @@ pub fn init() {
 
     let del = Command {
         name: String::from("del"),
-        desc: String::from("delete file"),
+        desc: String::from("delete files (del -r to delete directories with their contents)"),
         func: del_fn,
     };
     init_command(String::from("del"), del);
 
+    let rmdir = Command {
+        name: String::from("rmdir"),
+        desc: String::from("delete empty directories"),
+        func: rmdir_fn,
+    };
+    init_command(String::from("rmdir"), rmdir);
+
     let cd = Command {
         name: String::from("cd"),
         desc: String::from("change current directory"),
         func: cd_fn,
     };
     init_command(String::from("cd"), cd);
+
+    let lsblk = Command {
+        name: String::from("lsblk"),
+        desc: String::from("list block devices and partitions"),
+        func: lsblk_fn,
+    };
+    init_command(String::from("lsblk"), lsblk);
+
+    let sync = Command {
+        name: String::from("sync"),
+        desc: String::from("write cached sectors back to disk"),
+        func: sync_fn,
+    };
+    init_command(String::from("sync"), sync);
+
+    let cache = Command {
+        name: String::from("cache"),
+        desc: String::from("show sector cache statistics"),
+        func: cache_fn,
+    };
+    init_command(String::from("cache"), cache);
+
+    let halt = Command {
+        name: String::from("halt"),
+        desc: String::from("unmount filesystems and stop the machine"),
+        func: halt_fn,
+    };
+    init_command(String::from("halt"), halt);
+
+    let ren = Command {
+        name: String::from("ren"),
+        desc: String::from("rename file"),
+        func: ren_fn,
+    };
+    init_command(String::from("ren"), ren);
+
+    let fsck = Command {
+        name: String::from("fsck"),
+        desc: String::from("check the wFS volume, -r to repair"),
+        func: fsck_fn,
+    };
+    init_command(String::from("fsck"), fsck);
+
+    let wfs = Command {
+        name: String::from("wfs"),
+        desc: String::from("show the wFS volume format, 'wfs upgrade' to upgrade it"),
+        func: wfs_fn,
+    };
+    init_command(String::from("wfs"), wfs);
+
+    let attr = Command {
+        name: String::from("attr"),
+        desc: String::from("show or change attributes (attr <file> [+r|-r|+s|-s|+h|-h]...)"),
+        func: attr_fn,
+    };
+    init_command(String::from("attr"), attr);
+
+    let user = Command {
+        name: String::from("user"),
+        desc: String::from("show or change the current user (0 is the system)"),
+        func: user_fn,
+    };
+    init_command(String::from("user"), user);
+
+    let ln = Command {
+        name: String::from("ln"),
+        desc: String::from("link a file under another name (ln [-s] <target> <name>, -s for a symbolic link)"),
+        func: ln_fn,
+    };
+    init_command(String::from("ln"), ln);
 /*
     let mv = Command {
         name: String::from("mv"),
@@ -120,13 +203,20 @@ pub fn init_command(n: String, c: Command) {
     COMMANDS.lock().insert(String::from(n), c);
 }
 
+// The table isn't locked while the command runs, so commands can look at it.
 pub fn get_command(name: String, args: Vec<String>) {
-    match COMMANDS.lock().get(&name) {
-        Some(com) => (com.func)(args),
+    let func = COMMANDS.lock().get(&name).map(|c| c.func);
+    match func {
+        Some(f) => f(args),
         None => println!("command not found: {}", name),
     }
 }
 
+/// Names and descriptions of the registered commands.
+pub fn list() -> Vec<(String, String)> {
+    COMMANDS.lock().values().map(|c| (c.name.clone(), c.desc.clone())).collect()
+}
+
 pub fn clear_fn(args: Vec<String>) {
     vga_buffer::WRITER.lock().clear_screen();
 }
@@ -139,7 +229,6 @@ pub fn echo_fn(args: Vec<String>) {
 }
 
 pub fn help_fn(args: Vec<String>) {
-    unsafe { COMMANDS.force_unlock(); }
     if args.len() > 1 {
         match COMMANDS.lock().get(&args[1]) {
             Some(com) => {
@@ -161,33 +250,77 @@ pub fn time_fn(args: Vec<String>) {
 
 pub fn ls_fn(args: Vec<String>) {
     let mut node = console::get_cdir();
+    let mut long = false;
+    let mut all = false;
+
+    for a in args.iter().skip(1) {
+        if a.len() > 1 && a.starts_with('-') && a.chars().skip(1).all(|c| c == 'l' || c == 'a') {
+            long |= a.contains('l');
+            all |= a.contains('a');
+            continue;
+        }
 
-    if args.len() > 1 {
-    match vfs::node_from_local_path(&console::get_cdir(), args[1].clone()) {
+        match vfs::node_from_local_path(&console::get_cdir(), a.clone()) {
             Ok(n) => node = n,
             Err(e) => {
-                println!("file not found: {}", &args[1]);
+                println!("{}", e.at(a));
                 return;
             },
         }
     }
     
-    let mut children: Vec<vfs::FsNode> = Vec::new();
-
-    match node.get_children() {
-        Ok(v) => children = v,
-        Err(e) => println!("could not get children: {}", vfs::sfn(node.name)),
+    // Only the long listing needs what is in the children themselves.
+    if long {
+        match node.get_children() {
+            Ok(children) => {
+                for c in children.iter().filter(|c| all || !c.attributes.get_bit(vfs::ATTR_HDN)) {
+                    let mut name = c.name.clone();
+                    if c.attributes.get_bit(vfs::ATTR_DIR) {
+                        name.push('/');
+                    }
+                    if let Ok(target) = c.read_link() {
+                        name = format!("{} -> {}", name, target);
+                    }
+                    println!("{} {:>8} {} {}", attr_string(c.attributes), c.size, time::format(c.t_edit), name);
+                }
+            },
+            Err(e) => println!("{}", e.at(&node.name)),
+        }
+        return;
     }
 
-    for c in children.iter() {
-        if c.attributes.get_bit(vfs::ATTR_DIR) {
-            println!("{}/", vfs::sfn(c.name));
-        } else {
-            println!("{}", vfs::sfn(c.name));
-        }
+    match node.list() {
+        Ok(children) => {
+            for c in children.iter().filter(|c| all || !c.attributes.get_bit(vfs::ATTR_HDN)) {
+                let mark = if c.attributes.get_bit(vfs::ATTR_DIR) {
+                    "/"
+                } else if c.attributes.get_bit(vfs::ATTR_LNK) {
+                    "@"
+                } else {
+                    ""
+                };
+                println!("{}{}", c.name, mark);
+            }
+        },
+        Err(e) => println!("{}", e.at(&node.name)),
     }
 }
 
+fn attr_string(attributes: u8) -> String {
+    let mut s = String::new();
+    s.push(if attributes.get_bit(vfs::ATTR_DIR) {
+        'd'
+    } else if attributes.get_bit(vfs::ATTR_LNK) {
+        'l'
+    } else {
+        '-'
+    });
+    s.push(if attributes.get_bit(vfs::ATTR_RO) { 'r' } else { '-' });
+    s.push(if attributes.get_bit(vfs::ATTR_SYS) { 's' } else { '-' });
+    s.push(if attributes.get_bit(vfs::ATTR_HDN) { 'h' } else { '-' });
+    s
+}
+
 pub fn read_fn(args: Vec<String>) {
     if args.len() <= 1 {
         println!("please specify a file");
@@ -203,25 +336,32 @@ pub fn read_fn(args: Vec<String>) {
             match n.open() {
                 Ok(()) => {},
                 Err(e) => {
-                    println!("could not open file: {}", &args[1]);
+                    println!("{}", e.at(&args[1]));
                     return;
                 },
             }
-            match n.read() {
+            let res = if args.len() > 2 {
+                let offset = args[2].parse::<u64>().unwrap_or(0);
+                let len = args.get(3).and_then(|l| l.parse::<usize>().ok()).unwrap_or(usize::MAX);
+                n.read_at(offset, len)
+            } else {
+                n.read()
+            };
+            match res {
                 Ok(buf) => {
                     for b in buf.iter() {
                         print!("{}", *b as char);
                     }
                 },
-                Err(e) => println!("could not read file: {}", &args[1]),
+                Err(e) => println!("{}", e.at(&args[1])),
             }
             println!();
             match n.close() {
                 Ok(()) => {},
-                Err(e) => println!("could not close file: {}", &args[1]),
+                Err(e) => println!("{}", e.at(&args[1])),
             }
         },
-        Err(e) => println!("file not found: {}", &args[1]),
+        Err(e) => println!("{}", e.at(&args[1])),
     }
 
 }
@@ -234,24 +374,32 @@ pub fn info_fn(args: Vec<String>) {
 
     match vfs::node_from_local_path(&console::get_cdir(), args[1].clone()) {
         Ok(n) => {
-            println!("{}", vfs::sfn(n.name));
+            println!("{}", n.name);
             println!("owner: {}", n.owner);
             println!("size: {}B", n.size);
-            println!("created: {}", n.t_creation);
-            println!("edited: {}", n.t_edit);
+            println!("attributes: {}", attr_string(n.attributes));
+            println!("created: {}", time::format(n.t_creation));
+            println!("edited: {}", time::format(n.t_edit));
         },
-        Err(e) => println!("file not found: {}", &args[1]),
+        Err(e) => println!("{}", e.at(&args[1])),
     }
 }
 
 pub fn pcd_fn(args: Vec<String>) {
-    println!("{}/", vfs::sfn(console::get_cdir().name));
+    println!("{}/", console::get_cdir().name);
 }
 
 pub fn mkf_fn(args: Vec<String>) {
-    match vfs::create_node(console::get_cdir().id, args[1].clone(), 0, 0, 0) {
+    if args.len() <= 1 {
+        println!("please specify a file name");
+        return;
+    }
+
+    let cdir = console::get_cdir();
+    match vfs::create_node(cdir.id, args[1].clone(), 0, vfs::current_user(), cdir.device) {
         Ok(n) => return,
-        Err(e) => println!("could not create file"),
+        Err(vfs::Error::PermissionDenied) => println!("{}", vfs::Error::PermissionDenied.at(&cdir.name)),
+        Err(e) => println!("{}", e.at(&args[1])),
     }
 }
 
@@ -268,7 +416,7 @@ pub fn write_fn(args: Vec<String>) {
     let text = a.join(" ").into_bytes();
 
 
-    match vfs::node_from_local_path(&console::get_cdir(), path) {
+    match vfs::node_from_local_path(&console::get_cdir(), path.clone()) {
         Ok(mut n) => {
             if n.attributes.get_bit(vfs::ATTR_DIR) {
                 println!("cannot write to directory");
@@ -277,50 +425,238 @@ pub fn write_fn(args: Vec<String>) {
             match n.open() {
                 Ok(()) => {},
                 Err(e) => {
-                    println!("could not open file: {}", &a[1]);
+                    println!("{}", e.at(&path));
                     return;
                 },
             }
             match n.append(text) {
                 Ok(()) => {},
-                Err(e) => println!("could not write to file: {}", &a[1]),
+                Err(e) => println!("{}", e.at(&path)),
             }
             match n.close() {
                 Ok(()) => {},
-                Err(e) => println!("could not close file: {}", &a[1]),
+                Err(e) => println!("{}", e.at(&path)),
             }
         },
-        Err(e) => println!("file not found: {}", &a[1]),
+        Err(e) => println!("{}", e.at(&path)),
     }
 }
 
 pub fn del_fn(args: Vec<String>) {
+    let recursive = args.len() > 1 && args[1] == "-r";
+    let paths = &args[if recursive { 2 } else { 1 }..];
+    if paths.is_empty() {
+        println!("please specify a file");
+        return;
+    }
+
+    for p in paths {
+        match vfs::link_from_local_path(&console::get_cdir(), p.clone()) {
+            Ok(n) if n.attributes.get_bit(vfs::ATTR_DIR) && !recursive => {
+                println!("{} is a directory, use rmdir or del -r", p);
+            },
+            Ok(n) => remove(&n, p, recursive),
+            Err(e) => println!("{}", e.at(p)),
+        }
+    }
+}
+
+pub fn rmdir_fn(args: Vec<String>) {
     if args.len() <= 1 {
-        println!("Please specify a file!");
+        println!("please specify a directory");
         return;
     }
-    
-    match vfs::node_from_local_path(&console::get_cdir(), args[1].clone()) {
+
+    for p in &args[1..] {
+        match vfs::link_from_local_path(&console::get_cdir(), p.clone()) {
+            Ok(n) if !n.attributes.get_bit(vfs::ATTR_DIR) => println!("not a directory: {}", p),
+            Ok(n) => remove(&n, p, false),
+            Err(e) => println!("{}", e.at(p)),
+        }
+    }
+}
+
+fn remove(n: &vfs::FsNode, path: &str, recursive: bool) {
+    match vfs::remove(n, recursive) {
+        Ok(()) => {},
+        Err(vfs::Error::NotEmpty) => println!("directory is not empty, use del -r: {}", path),
+        Err(vfs::Error::Busy) => println!("{} is in use (the root, the current directory or an open file)", path),
+        Err(e) => println!("{}", e.at(path)),
+    }
+}
+
+pub fn fsck_fn(args: Vec<String>) {
+    let repair = args.len() > 1 && args[1] == "-r";
+    if repair && wfs::is_read_only() {
+        println!("volume is read-only, checking without repairing");
+    }
+    let r = wfs::check(repair);
+
+    if r.problems == 0 {
+        println!("{} entries checked, no problems found.", r.entries);
+    } else if repair {
+        println!("{} entries checked, {} problems found, {} fixed.", r.entries, r.problems, r.fixed);
+    } else {
+        println!("{} entries checked, {} problems found. Run fsck -r to repair.", r.entries, r.problems);
+    }
+}
+
+pub fn wfs_fn(args: Vec<String>) {
+    if args.len() > 1 && args[1] == "upgrade" {
+        match wfs::upgrade() {
+            Ok(()) => println!("volume upgraded to format version {}", wfs::disk::FORMAT_VERSION),
+            Err(vfs::Error::IllegalOperation) => println!("volume is already at format version {}", wfs::disk::FORMAT_VERSION),
+            Err(vfs::Error::NameTooLong) => println!("upgrade failed: a name doesn't fit the new format"),
+            Err(e) => println!("upgrade failed: {}", e),
+        }
+        return;
+    }
+
+    let (version, block_size) = {
+        let info = wfs::WFS_INFO.lock();
+        (info.version, info.bytes_per_block)
+    };
+    println!("format version: {} (current {})", version, wfs::disk::FORMAT_VERSION);
+    println!("block size: {}B", block_size);
+    println!("mounted: {}", if wfs::is_read_only() { "read-only" } else { "read-write" });
+}
+
+pub fn ren_fn(args: Vec<String>) {
+    if args.len() != 3 {
+        println!("please specify a file and a new name");
+        return;
+    }
+    if let Err(e) = vfs::check_name(&args[2]) {
+        println!("{}", e.at(&args[2]));
+        return;
+    }
+
+    match vfs::link_from_local_path(&console::get_cdir(), args[1].clone()) {
         Ok(mut n) => {
             match n.open() {
                 Ok(()) => {},
                 Err(e) => {
-                    println!("could not open file: {}", &args[1]);
+                    println!("{}", e.at(&args[1]));
                     return;
                 },
             }
-            match n.delete() {
+            match n.rename(args[2].clone()) {
                 Ok(()) => {},
-                Err(e) => println!("could not delete file: {}", &args[1]),
+                Err(e @ vfs::Error::AlreadyExists) | Err(e @ vfs::Error::NameTooLong) => println!("{}", e.at(&args[2])),
+                Err(e) => println!("{}", e.at(&args[1])),
             }
             match n.close() {
                 Ok(()) => {},
-                Err(e) => println!("could not close file: {}", &args[1]),
+                Err(e) => println!("{}", e.at(&args[1])),
             }
         },
-        Err(e) => println!("file not found: {}", &args[1]),
+        Err(e) => println!("{}", e.at(&args[1])),
+    }
+}
+
+pub fn attr_fn(args: Vec<String>) {
+    if args.len() <= 1 {
+        println!("please specify a file");
+        return;
     }
 
+    let mut n = match vfs::node_from_local_path(&console::get_cdir(), args[1].clone()) {
+        Ok(n) => n,
+        Err(e) => {
+            println!("{}", e.at(&args[1]));
+            return;
+        },
+    };
+    if args.len() == 2 {
+        println!("{} owner {} {}", attr_string(n.attributes), n.owner, n.name);
+        return;
+    }
+
+    let mut attributes = n.attributes;
+    for a in args.iter().skip(2) {
+        let set = match a.chars().next() {
+            Some('+') => true,
+            Some('-') => false,
+            _ => {
+                println!("attributes are changed with +r, -r, +s, -s, +h or -h: {}", a);
+                return;
+            },
+        };
+        for c in a.chars().skip(1) {
+            match c {
+                'r' => attributes.set_bit(vfs::ATTR_RO, set),
+                's' => attributes.set_bit(vfs::ATTR_SYS, set),
+                'h' => attributes.set_bit(vfs::ATTR_HDN, set),
+                _ => {
+                    println!("unknown attribute: {}", c);
+                    return;
+                },
+            };
+        }
+    }
+
+    match n.set_attributes(attributes) {
+        Ok(()) => println!("{} owner {} {}", attr_string(n.attributes), n.owner, n.name),
+        Err(e) => println!("{}", e.at(&args[1])),
+    }
+}
+
+pub fn user_fn(args: Vec<String>) {
+    if args.len() <= 1 {
+        println!("user {}", vfs::current_user());
+        return;
+    }
+    match args[1].parse::<u8>() {
+        Ok(u) => vfs::set_user(u),
+        Err(_) => println!("users are numbered 0 to 255: {}", &args[1]),
+    }
+}
+
+pub fn ln_fn(args: Vec<String>) {
+    let symbolic = args.len() > 1 && args[1] == "-s";
+    let rest = &args[if symbolic { 2 } else { 1 }..];
+    if rest.len() != 2 {
+        println!("please specify a target and a name for the link");
+        return;
+    }
+    let (target, link) = (&rest[0], &rest[1]);
+
+    // The link goes into the directory its path names.
+    let cdir = console::get_cdir();
+    let (dir, name) = match link.rfind('/') {
+        Some(i) => (vfs::node_from_local_path(&cdir, link[..i].to_string()), link[i + 1..].to_string()),
+        None => (Ok(cdir), link.clone()),
+    };
+    let dir = match dir {
+        Ok(d) if d.attributes.get_bit(vfs::ATTR_DIR) => d,
+        Ok(_) => {
+            println!("{}", vfs::Error::ParentNotDirectory.at(link));
+            return;
+        },
+        Err(e) => {
+            println!("{}", e.at(link));
+            return;
+        },
+    };
+
+    let res = if symbolic {
+        vfs::create_symlink(&dir, name, target.clone(), vfs::current_user()).map(|_| ())
+    } else {
+        match vfs::node_from_local_path(&console::get_cdir(), target.clone()) {
+            Ok(t) => vfs::create_link(&dir, name, &t),
+            Err(e) => {
+                println!("{}", e.at(target));
+                return;
+            },
+        }
+    };
+
+    match res {
+        Ok(()) => {},
+        Err(vfs::Error::PermissionDenied) => println!("{}", vfs::Error::PermissionDenied.at(&dir.name)),
+        Err(vfs::Error::IllegalOperation) => println!("directories and files on other devices can't be hard linked: {}", target),
+        Err(e) => println!("{}", e.at(link)),
+    }
 }
 
 pub fn cd_fn(args: Vec<String>) {
@@ -339,8 +675,57 @@ pub fn cd_fn(args: Vec<String>) {
             console::set_cdir(n);
             return;
         },
-        Err(e) => println!("file not found: {}", &args[1]),
+        Err(e) => println!("{}", e.at(&args[1])),
+    }
+}
+
+pub fn lsblk_fn(args: Vec<String>) {
+    for d in block::BLOCK_DEVICES.lock().iter() {
+        let kb = d.sectors * d.sector_size as u64 / 1024;
+        print!("{}  {}KiB", d.name, kb);
+        match d.part_type {
+            partition::PartType::Mbr(id) => print!("  type 0x{:02x}", id),
+            partition::PartType::Gpt(guid) => print!("  type {}", partition::guid_to_string(&guid)),
+            partition::PartType::None => {},
+        }
+        if d.part_type.is_wfs() {
+            print!(" (wFS)");
+        }
+        if d.read_only {
+            print!(" ro");
+        }
+        println!();
+    }
+}
+
+pub fn sync_fn(args: Vec<String>) {
+    match cache::flush() {
+        Ok(()) => {},
+        Err(e) => println!("sync failed: {}", e),
+    }
+}
+
+pub fn cache_fn(args: Vec<String>) {
+    let s = cache::stats();
+    let (used, dirty) = cache::usage();
+    let total = s.hits + s.misses;
+
+    println!("sectors: {}/{} ({} dirty)", used, cache::CACHE_SECTORS, dirty);
+    println!("hits: {}  misses: {}  hit rate: {}%", s.hits, s.misses, if total == 0 { 0 } else { s.hits * 100 / total });
+    println!("write-backs: {}  evictions: {}", s.writebacks, s.evictions);
+}
+
+pub fn halt_fn(args: Vec<String>) {
+    match wfs::unmount() {
+        Ok(()) => {},
+        Err(e) => println!("could not unmount A: {}", e),
+    }
+    match cache::flush() {
+        Ok(()) => {},
+        Err(e) => println!("sync failed: {}", e),
     }
+    println!("It is now safe to turn off your computer.");
+    crate::hlt_loop();
 }
 
 /*
@@ -10,6 +10,7 @@ use spin::Mutex;
 use crate::stdin;
 use crate::commands;
 use crate::vfs;
+use crate::cache;
 
 pub struct Console {
     cdir: Option<vfs::FsNode>,
@@ -26,6 +27,7 @@ lazy_static! {
 
 pub fn init() {
     CONSOLE.lock().cdir = Some(vfs::get_root(0).unwrap());
+    cache::enable_writeback();
 
     println!("wOS v0.1.0    {}", cmos::RTC.lock().get_datetime());
     //wfs::demo();
@@ -34,7 +36,7 @@ pub fn init() {
 }
 
 pub fn prompt() {
-    print!("{}/", vfs::sfn(CONSOLE.lock().cdir.unwrap().name));
+    print!("{}/", get_cdir().name);
 
     vga_buffer::set_color(vga_buffer::Color::LightCyan, vga_buffer::Color::Black);
     print!(" >>> ");
@@ -57,7 +59,7 @@ pub fn process_command(com: String) {
 
 
 pub fn get_cdir() -> vfs::FsNode {
-    CONSOLE.lock().cdir.unwrap()
+    CONSOLE.lock().cdir.clone().unwrap()
 }
 
 pub fn set_cdir(node: vfs::FsNode) {
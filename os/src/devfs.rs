@@ -0,0 +1,302 @@
+//devfs: drivers as files, mounted at DEV:.
+//Every block device (disks, partitions, CD-ROMs) is a file as large as the
+//device, read and written through the sector cache. The others are streams:
+//serial, console, null, zero and random.
+
+use crate::vfs;
+use crate::drivers::block;
+use crate::cache;
+use crate::serial;
+use crate::vga_buffer;
+use crate::time;
+use crate::print;
+use crate::println;
+use spin::Mutex;
+use lazy_static::lazy_static;
+use bit_field::BitField;
+use alloc::string::String;
+use alloc::vec::Vec;
+use alloc::vec;
+
+/// Device name devfs is mounted as.
+pub const DEVICE: &str = "DEV:";
+
+const ROOT_ID: u64 = 1;
+const BLOCK_BASE: u64 = 16;
+
+/// Bytes a stream gives for a plain read; read_at takes any length up to
+/// STREAM_MAX.
+pub const STREAM_CHUNK: usize = 512;
+pub const STREAM_MAX: usize = 64 * 1024;
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+enum Dev {
+    Null,
+    Zero,
+    Random,
+    Serial,
+    Console,
+    Block(usize),
+}
+
+const STREAMS: [(u64, &str, Dev); 5] = [
+    (2, "null", Dev::Null),
+    (3, "zero", Dev::Zero),
+    (4, "random", Dev::Random),
+    (5, "serial", Dev::Serial),
+    (6, "console", Dev::Console),
+];
+
+lazy_static! {
+    static ref DEV_ID: Mutex<usize> = Mutex::new(0);
+    // xorshift64* state, seeded at mount.
+    static ref RANDOM: Mutex<u64> = Mutex::new(0);
+}
+
+pub fn init() {
+    let dev = match vfs::install_device(String::from(DEVICE), vfs::System::DevFS) {
+        Ok(d) => d,
+        Err(e) => {
+            println!("[DEVFS] Could not mount {} ({}).", DEVICE, e);
+            return;
+        },
+    };
+    *DEV_ID.lock() = dev;
+    mix(time::now() ^ unsafe { core::arch::x86_64::_rdtsc() });
+
+    let n = STREAMS.len() + block::BLOCK_DEVICES.lock().len();
+    println!("[DEVFS] Mounted {} devices as {}.", n, DEVICE);
+}
+
+// VFS functions
+
+pub fn get_root(dev_id: usize) -> Result<vfs::FsNode, vfs::Error> {
+    Ok(vfs::FsNode {
+        name: String::from(DEVICE),
+        device: dev_id,
+        parent_id: 0,
+        id: ROOT_ID,
+        attributes: *0u8.set_bit(vfs::ATTR_DIR, true).set_bit(vfs::ATTR_SYS, true),
+        t_creation: 0,
+        t_edit: 0,
+        owner: vfs::SYSTEM_USER,
+        size: 0,
+        open: false,
+    })
+}
+
+pub fn find_node(parent_id: u64, name: String, dev_id: usize) -> Result<vfs::FsNode, vfs::Error> {
+    if parent_id != ROOT_ID {
+        return Err(vfs::Error::ParentNotDirectory);
+    }
+    match nodes().into_iter().find(|(_, n, _)| *n == name) {
+        Some((id, _, d)) => node(id, d, dev_id),
+        None => Err(vfs::Error::FileNotFound),
+    }
+}
+
+pub fn find_node_by_id(id: u64, dev_id: usize) -> Result<vfs::FsNode, vfs::Error> {
+    if id == ROOT_ID {
+        return get_root(dev_id);
+    }
+    node(id, dev(id)?, dev_id)
+}
+
+pub fn get_parent(id: u64, dev_id: usize) -> Result<vfs::FsNode, vfs::Error> {
+    if id != ROOT_ID {
+        dev(id)?;
+    }
+    get_root(dev_id)
+}
+
+pub fn get_children(id: u64, dev_id: usize) -> Result<Vec<vfs::FsNode>, vfs::Error> {
+    if id != ROOT_ID {
+        return Err(vfs::Error::IllegalOperation);
+    }
+    nodes().into_iter().map(|(id, _, d)| node(id, d, dev_id)).collect()
+}
+
+/// A whole block device if it fits in memory, or one chunk of a stream.
+pub fn read_node(id: u64) -> Result<Vec<u8>, vfs::Error> {
+    match dev(id)? {
+        Dev::Block(b) => {
+            let size = size_of(b)?;
+            if size > vfs::READ_MAX as u64 {
+                return Err(vfs::Error::NoSpace);
+            }
+            read_block(b, 0, size as usize)
+        },
+        _ => read_node_at(id, 0, STREAM_CHUNK),
+    }
+}
+
+/// Up to `len` bytes from `offset`. Streams ignore the offset.
+pub fn read_node_at(id: u64, offset: u64, len: usize) -> Result<Vec<u8>, vfs::Error> {
+    let len = len.min(STREAM_MAX);
+    match dev(id)? {
+        Dev::Null => Ok(Vec::new()),
+        Dev::Zero => Ok(vec![0; len]),
+        Dev::Random => Ok((0..len).map(|_| random() as u8).collect()),
+        Dev::Serial => {
+            let mut buf = serial::receive();
+            buf.truncate(len);
+            Ok(buf)
+        },
+        Dev::Console => {
+            let mut buf = vga_buffer::screen_text().into_bytes();
+            buf.truncate(len);
+            Ok(buf)
+        },
+        Dev::Block(b) => {
+            let size = size_of(b)?;
+            if offset >= size {
+                return Ok(Vec::new());
+            }
+            read_block(b, offset, len.min((size - offset) as usize))
+        },
+    }
+}
+
+/// Writes `buf` from the start of a block device, or to a stream. Writing
+/// to random stirs it.
+pub fn write_node(id: u64, buf: Vec<u8>) -> Result<(), vfs::Error> {
+    match dev(id)? {
+        Dev::Block(b) => write_block(b, 0, &buf),
+        _ => append_node(id, buf),
+    }
+}
+
+/// Streams only: a block device has no end to write after.
+pub fn append_node(id: u64, buf: Vec<u8>) -> Result<(), vfs::Error> {
+    match dev(id)? {
+        Dev::Null | Dev::Zero => Ok(()),
+        Dev::Random => {
+            for chunk in buf.chunks(8) {
+                mix(chunk.iter().fold(0u64, |acc, &b| acc << 8 | b as u64));
+            }
+            Ok(())
+        },
+        Dev::Serial => {
+            serial::send(&buf);
+            Ok(())
+        },
+        Dev::Console => {
+            print!("{}", String::from_utf8_lossy(&buf));
+            Ok(())
+        },
+        Dev::Block(_) => Err(vfs::Error::IllegalOperation),
+    }
+}
+
+//devfs specific functions
+
+// Streams first, then the block devices in the order they were found.
+fn nodes() -> Vec<(u64, String, Dev)> {
+    let mut ret: Vec<(u64, String, Dev)> = STREAMS.iter().map(|&(id, n, d)| (id, String::from(n), d)).collect();
+    for b in block::BLOCK_DEVICES.lock().iter() {
+        ret.push((BLOCK_BASE + b.index as u64, b.name.clone(), Dev::Block(b.index)));
+    }
+    ret
+}
+
+fn dev(id: u64) -> Result<Dev, vfs::Error> {
+    if let Some(&(_, _, d)) = STREAMS.iter().find(|(i, _, _)| *i == id) {
+        return Ok(d);
+    }
+    if id >= BLOCK_BASE && block::get((id - BLOCK_BASE) as usize).is_some() {
+        return Ok(Dev::Block((id - BLOCK_BASE) as usize));
+    }
+    Err(vfs::Error::FileNotFound)
+}
+
+fn node(id: u64, d: Dev, dev_id: usize) -> Result<vfs::FsNode, vfs::Error> {
+    let (name, size, read_only) = match d {
+        Dev::Block(b) => {
+            let bd = block::get(b).ok_or(vfs::Error::FileNotFound)?;
+            (bd.name, bd.sectors * bd.sector_size as u64, bd.read_only || bd.kind == block::Kind::Atapi)
+        },
+        _ => {
+            let &(_, n, _) = STREAMS.iter().find(|(i, _, _)| *i == id).ok_or(vfs::Error::FileNotFound)?;
+            (String::from(n), 0, false)
+        },
+    };
+
+    // Only the system may use devices directly; they can't be renamed or
+    // deleted because devfs has no such operations.
+    Ok(vfs::FsNode {
+        name: name,
+        device: dev_id,
+        parent_id: ROOT_ID,
+        id: id,
+        attributes: *0u8.set_bit(vfs::ATTR_RO, read_only),
+        t_creation: 0,
+        t_edit: 0,
+        owner: vfs::SYSTEM_USER,
+        size: size,
+        open: false,
+    })
+}
+
+fn size_of(b: usize) -> Result<u64, vfs::Error> {
+    let d = block::get(b).ok_or(vfs::Error::FileNotFound)?;
+    Ok(d.sectors * d.sector_size as u64)
+}
+
+fn read_block(b: usize, offset: u64, len: usize) -> Result<Vec<u8>, vfs::Error> {
+    let ss = block::get(b).ok_or(vfs::Error::FileNotFound)?.sector_size as u64;
+    if len == 0 {
+        return Ok(Vec::new());
+    }
+    let first = offset / ss;
+    let last = (offset + len as u64 - 1) / ss;
+    let mut buf = cache::read_many(b, first, last - first + 1).map_err(|e| vfs::Error::io(b, e))?;
+    let start = (offset % ss) as usize;
+    buf.truncate(start + len);
+    buf.drain(..start);
+    Ok(buf)
+}
+
+// Sectors only partly covered by `buf` keep the rest of their contents.
+fn write_block(b: usize, offset: u64, buf: &[u8]) -> Result<(), vfs::Error> {
+    let d = block::get(b).ok_or(vfs::Error::FileNotFound)?;
+    let ss = d.sector_size as u64;
+    if offset + buf.len() as u64 > d.sectors * ss {
+        return Err(vfs::Error::NoSpace);
+    }
+
+    let mut pos = 0;
+    while pos < buf.len() {
+        let at = offset + pos as u64;
+        let lba = at / ss;
+        let start = (at % ss) as usize;
+        let n = (ss as usize - start).min(buf.len() - pos);
+
+        let mut sector = if n == ss as usize {
+            vec![0; ss as usize]
+        } else {
+            cache::read(b, lba).map_err(|e| vfs::Error::io(b, e))?
+        };
+        sector[start..start + n].copy_from_slice(&buf[pos..pos + n]);
+        cache::write(b, lba, &sector).map_err(|e| vfs::Error::io(b, e))?;
+        pos += n;
+    }
+    Ok(())
+}
+
+fn mix(seed: u64) {
+    let mut state = RANDOM.lock();
+    *state ^= seed;
+    if *state == 0 {
+        *state = 0x9E3779B97F4A7C15;
+    }
+}
+
+fn random() -> u64 {
+    let mut state = RANDOM.lock();
+    let mut x = *state;
+    x ^= x >> 12;
+    x ^= x << 25;
+    x ^= x >> 27;
+    *state = x;
+    x.wrapping_mul(0x2545F4914F6CDD1D)
+}
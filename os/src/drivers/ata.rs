@@ -3,7 +3,7 @@ use crate::println;
 use crate::print;
 use bit_field::BitField;
 use alloc::vec::Vec;
-use alloc::string::String;
+use alloc::string::{String, ToString};
 use spin::Mutex;
 use lazy_static::lazy_static;
 use crate::timer;
@@ -148,69 +148,247 @@ pub struct AtaHandler {
     pub master: bool,
 }
 
+pub struct AtapiHandler {
+    pub detected: bool,
+    pub primary: bool,
+    pub master: bool,
+    pub total_blocks: u32,
+    pub block_size: u32,
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum DriveKind {
+    None,
+    Ata,
+    Atapi,
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Error {
+    NoDevice,
+    Timeout,
+    DeviceFault,
+    DeviceError(u8),
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Error::NoDevice => write!(f, "no drive"),
+            Error::Timeout => write!(f, "drive timed out"),
+            Error::DeviceFault => write!(f, "drive fault"),
+            Error::DeviceError(e) => write!(f, "drive error {:#04x}", e),
+        }
+    }
+}
+
 lazy_static! {
     pub static ref ATA_HANDLER: Mutex<AtaHandler> = Mutex::new(AtaHandler {
         detected: false,
         total_sectors: 0,
         master: true,
     });
+
+    pub static ref ATA_SLAVE_HANDLER: Mutex<AtaHandler> = Mutex::new(AtaHandler {
+        detected: false,
+        total_sectors: 0,
+        master: false,
+    });
+
+    pub static ref ATAPI_HANDLER: Mutex<AtapiHandler> = Mutex::new(AtapiHandler {
+        detected: false,
+        primary: false,
+        master: true,
+        total_blocks: 0,
+        block_size: ATAPI_SECTOR_SIZE as u32,
+    });
 }
 
-pub fn init() {
-    unsafe {
-        let mut drives = 0;
+pub const ATAPI_SECTOR_SIZE: usize = 2048;
+
+const ATAPI_READ_CAPACITY: u8 = 0x25;
+const ATAPI_READ10: u8 = 0x28;
+
+// Upper bound on status polls before a command is given up on. ATAPI drives
+// without a disc never raise DRQ, so polling forever would hang the kernel.
+const POLL_LIMIT: usize = 1_000_000;
+
+struct Ports {
+    data: u16,
+    features: u16,
+    sector_count: u16,
+    lbal: u16,
+    lbam: u16,
+    lbah: u16,
+    drivesel: u16,
+    status: u16,
+    command: u16,
+    altstatus: u16,
+}
 
-        io::outb(DRIVESEL, 0xE0);
-        io::outb(SECTOR_COUNT, 0);
-        io::outb(LBAL, 0);
-        io::outb(LBAM, 0);
-        io::outb(LBAH, 0);
-        io::outb(COMMAND, ATACommand::IdentifyDevice as u8);
+fn ports(primary: bool) -> Ports {
+    if primary {
+        Ports {
+            data: DATA,
+            features: FEATURES,
+            sector_count: SECTOR_COUNT,
+            lbal: LBAL,
+            lbam: LBAM,
+            lbah: LBAH,
+            drivesel: DRIVESEL,
+            status: STATUS,
+            command: COMMAND,
+            altstatus: ALTSTATUS,
+        }
+    } else {
+        Ports {
+            data: DATA2,
+            features: FEATURES2,
+            sector_count: SECTOR_COUNT2,
+            lbal: LBAL2,
+            lbam: LBAM2,
+            lbah: LBAH2,
+            drivesel: DRIVESEL2,
+            status: STATUS2,
+            command: COMMAND2,
+            altstatus: ALTSTATUS2,
+        }
+    }
+}
 
-        delay();
-        while io::inb(STATUS).get_bit(BSY) { crate::hlt_loop(); }
+pub fn init() {
+    let (mut master, mut slave) = (false, false);
 
-        if io::inb(STATUS) == 0 || io::inb(STATUS) == 0xFF {
-            println!("[ATA] master not found.");
-        } else {
+    match probe(true, true) {
+        DriveKind::Ata => {
             println!("[ATA] master found");
-            drives += 1;
+            master = true;
+        },
+        DriveKind::Atapi => {
+            println!("[ATA] master is an ATAPI device");
+            set_atapi(true, true);
+        },
+        DriveKind::None => println!("[ATA] master not found."),
+    }
+
+    match probe(true, false) {
+        DriveKind::Ata => {
+            println!("[ATA] slave found");
+            slave = true;
+        },
+        DriveKind::Atapi => {
+            println!("[ATA] slave is an ATAPI device");
+            set_atapi(true, false);
+        },
+        DriveKind::None => println!("[ATA] slave not found."),
+    }
+
+    // QEMU attaches `-cdrom` images to the secondary channel, so only look
+    // for packet devices there.
+    for &master in [true, false].iter() {
+        if probe(false, master) == DriveKind::Atapi {
+            println!("[ATAPI] secondary {} found", if master { "master" } else { "slave" });
+            set_atapi(false, master);
         }
+    }
 
-        io::outb(DRIVESEL, 0xF0);
-        io::outb(SECTOR_COUNT, 0);
-        io::outb(LBAL, 0);
-        io::outb(LBAM, 0);
-        io::outb(LBAH, 0);
-        io::outb(COMMAND, ATACommand::IdentifyDevice as u8);
+    if ATAPI_HANDLER.lock().detected {
+        identify_atapi();
+    }
+
+    if !master && !slave {
+        println!("[ATA] no drives found. Aborting.\n");
+        return;
+    }
+
+    if master {
+        identify_drive(true);
+    }
+    if slave {
+        identify_drive(false);
+    }
+}
+
+/// The handler of the primary channel's master or slave drive.
+pub fn handler(master: bool) -> &'static Mutex<AtaHandler> {
+    if master { &ATA_HANDLER } else { &ATA_SLAVE_HANDLER }
+}
+
+/// Issues IDENTIFY DEVICE to a drive and works out what, if anything, is attached.
+/// Packet devices abort the command and leave their signature in LBAM/LBAH.
+fn probe(primary: bool, master: bool) -> DriveKind {
+    let p = ports(primary);
+
+    unsafe {
+        io::outb(p.drivesel, if master { 0xA0 } else { 0xB0 });
+        select_delay(primary);
+        io::outb(p.sector_count, 0);
+        io::outb(p.lbal, 0);
+        io::outb(p.lbam, 0);
+        io::outb(p.lbah, 0);
+        io::outb(p.command, ATACommand::IdentifyDevice as u8);
 
         delay();
-        while io::inb(STATUS).get_bit(BSY) { crate::hlt_loop(); }
+        let status = io::inb(p.status);
+        if status == 0 || status == 0xFF {
+            return DriveKind::None;
+        }
 
-        if io::inb(STATUS) == 0 || io::inb(STATUS) == 0xFF {
-            println!("[ATA] slave not found.");
-        } else {
-            println!("[ATA] slave found");
-            drives += 1;
+        let mut busy = true;
+        for _ in 0..POLL_LIMIT {
+            if !io::inb(p.status).get_bit(BSY) {
+                busy = false;
+                break;
+            }
+        }
+        if busy {
+            return DriveKind::None;
         }
 
-        if drives < 1 {
-            println!("[ATA] no drives found. Aborting.\n");
-            return;
+        let (mid, high) = (io::inb(p.lbam), io::inb(p.lbah));
+        if (mid == 0x14 && high == 0xEB) || (mid == 0x69 && high == 0x96) {
+            return DriveKind::Atapi;
+        }
+        if mid != 0 || high != 0 {
+            return DriveKind::None;
         }
 
-        io::outb(DRIVESEL, 0xE0);
+        // Drain the identify data so the drive is ready for the next command.
+        if io::inb(p.status).get_bit(DRQ) {
+            for _ in 0..256 {
+                io::inw(p.data);
+            }
+        }
+    }
 
-        delay();
-        while io::inb(STATUS).get_bit(BSY) { crate::hlt_loop(); }
+    DriveKind::Ata
+}
 
-        identify_drive();
+fn set_atapi(primary: bool, master: bool) {
+    let mut handler = ATAPI_HANDLER.lock();
+    if handler.detected {
+        return;
     }
+    handler.detected = true;
+    handler.primary = primary;
+    handler.master = master;
 }
 
-pub fn identify_drive() {
+pub fn identify_drive(master: bool) {
     unsafe {
-        while io::inb(STATUS).get_bit(BSY) { crate::hlt_loop(); }
+        io::outb(DRIVESEL, if master { 0xE0 } else { 0xF0 });
+        select_delay(true);
+        io::outb(SECTOR_COUNT, 0);
+        io::outb(LBAL, 0);
+        io::outb(LBAM, 0);
+        io::outb(LBAH, 0);
+        io::outb(COMMAND, ATACommand::IdentifyDevice as u8);
+
+        delay();
+        if let Err(e) = wait_not_busy(true) {
+            println!("[ATA] Identify failed: {}", e);
+            return;
+        }
 
         let mut raw: [u16; 256] = [0; 256];
         if io::inb(STATUS).get_bit(DRQ) && !io::inb(STATUS).get_bit(ERR) {
@@ -226,32 +404,143 @@ pub fn identify_drive() {
             let (lobytes, hibytes) = (raw[60].to_le_bytes(), raw[61].to_le_bytes());
             u32::from_le_bytes([lobytes[0], lobytes[1], hibytes[0], hibytes[1]])
         };
-        ATA_HANDLER.lock().total_sectors = total_sectors_lba28 as usize;
-
-	    let model_number = {
-		    let mut bytes: Vec<u8> = Vec::new();
-		    for i in 27..47 {
-		        let part = raw[i].to_le_bytes();
-		        bytes.push(part[0]);
-		        bytes.push(part[1]);
-		    }
-		    for i in (0..bytes.len()).step_by(2) {
-		        let tmp = bytes[i];
-		        bytes[i] = bytes[i + 1];
-		        bytes[i + 1] = tmp;
-		    }
-		    String::from_utf8(bytes).unwrap()
-    	};
-		println!("[ATA] Model: {}", model_number);
-
-        ATA_HANDLER.lock().detected = true;
+        handler(master).lock().total_sectors = total_sectors_lba28 as usize;
+
+        println!("[ATA] Model: {}", model_number(&raw));
+
+        handler(master).lock().detected = true;
+    }
+}
+
+pub fn identify_atapi() {
+    let (primary, master) = {
+        let h = ATAPI_HANDLER.lock();
+        (h.primary, h.master)
+    };
+    let p = ports(primary);
+
+    unsafe {
+        io::outb(p.drivesel, if master { 0xA0 } else { 0xB0 });
+        select_delay(primary);
+        io::outb(p.command, ATACommand::IdentifyPacketDevice as u8);
+
+        delay();
+        if wait_drq(primary).is_err() {
+            println!("[ATAPI] identify failed");
+            return;
+        }
+
+        let mut raw: [u16; 256] = [0; 256];
+        for i in raw.iter_mut() {
+            *i = io::inw(p.data);
+        }
+        println!("[ATAPI] Model: {}", model_number(&raw));
+    }
+
+    match atapi_capacity() {
+        Ok((blocks, size)) => {
+            println!("[ATAPI] {} blocks of {} bytes", blocks, size);
+            let mut h = ATAPI_HANDLER.lock();
+            h.total_blocks = blocks;
+            h.block_size = size;
+        },
+        Err(_) => println!("[ATAPI] no medium"),
+    }
+}
+
+fn model_number(raw: &[u16; 256]) -> String {
+    let mut bytes: Vec<u8> = Vec::new();
+    for i in 27..47 {
+        let part = raw[i].to_le_bytes();
+        bytes.push(part[1]);
+        bytes.push(part[0]);
+    }
+    String::from_utf8_lossy(&bytes).trim_end().to_string()
+}
+
+/// Sends a 12 byte SCSI packet to the ATAPI drive and reads back at most
+/// `len` bytes of the response with PIO.
+fn atapi_packet(packet: [u8; 12], len: usize) -> Result<Vec<u8>, Error> {
+    let (detected, primary, master) = {
+        let h = ATAPI_HANDLER.lock();
+        (h.detected, h.primary, h.master)
+    };
+    if !detected {
+        return Err(Error::NoDevice);
+    }
+    let p = ports(primary);
+
+    let mut res: Vec<u8> = Vec::with_capacity(len);
+    unsafe {
+        io::outb(p.drivesel, if master { 0xA0 } else { 0xB0 });
+        select_delay(primary);
+
+        io::outb(p.features, 0x00);
+        io::outb(p.lbam, (len & 0xFF) as u8);
+        io::outb(p.lbah, ((len >> 8) & 0xFF) as u8);
+        io::outb(p.command, ATACommand::Packet as u8);
+
+        delay();
+        wait_drq(primary)?;
+
+        for i in 0..6 {
+            io::outw(p.data, u16::from_le_bytes([packet[i * 2], packet[i * 2 + 1]]));
+        }
+
+        while res.len() < len {
+            delay();
+            wait_drq(primary)?;
+
+            let count = (io::inb(p.lbah) as usize) << 8 | io::inb(p.lbam) as usize;
+            if count == 0 {
+                break;
+            }
+            for _ in 0..(count + 1) / 2 {
+                let word = io::inw(p.data).to_le_bytes();
+                if res.len() < len {
+                    res.push(word[0]);
+                }
+                if res.len() < len {
+                    res.push(word[1]);
+                }
+            }
+        }
+
+        wait_not_busy(primary)?;
     }
+
+    Ok(res)
+}
+
+/// Returns the number of blocks and the block size of the inserted medium.
+pub fn atapi_capacity() -> Result<(u32, u32), Error> {
+    let packet = [ATAPI_READ_CAPACITY, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    let raw = atapi_packet(packet, 8)?;
+    if raw.len() < 8 {
+        return Err(Error::DeviceFault);
+    }
+
+    let last = u32::from_be_bytes([raw[0], raw[1], raw[2], raw[3]]);
+    let size = u32::from_be_bytes([raw[4], raw[5], raw[6], raw[7]]);
+    Ok((last + 1, size))
+}
+
+/// Reads one 2048 byte sector from the ATAPI drive with READ(10).
+pub fn atapi_read(lba: u32) -> Result<Vec<u8>, Error> {
+    let l = lba.to_be_bytes();
+    let packet = [ATAPI_READ10, 0, l[0], l[1], l[2], l[3], 0, 0, 1, 0, 0, 0];
+    let raw = atapi_packet(packet, ATAPI_SECTOR_SIZE)?;
+    if raw.len() < ATAPI_SECTOR_SIZE {
+        return Err(Error::DeviceFault);
+    }
+    Ok(raw)
 }
 
 pub fn pio28_read(master: bool, lba: usize, count: u8) -> [u8; 512] {
 
     for i in 0..500 {}
     unsafe {
+        io::outb(DRIVESEL, drive_select(master, lba));
         io::outb(FEATURES, 0x00);
         io::outb(SECTOR_COUNT, count);
         io::outb(LBAL, lba.get_bits(24..32) as u8);
@@ -283,6 +572,33 @@ pub fn pio28_read(master: bool, lba: usize, count: u8) -> [u8; 512] {
     }
 }
     
+/// Reads `count` consecutive sectors with one command; a count of 0 means 256.
+pub fn pio28_read_many(master: bool, lba: usize, count: u8) -> Result<Vec<u8>, Error> {
+    let n = if count == 0 { 256 } else { count as usize };
+    let mut buf: Vec<u8> = Vec::with_capacity(n * 512);
+
+    unsafe {
+        io::outb(DRIVESEL, drive_select(master, lba));
+        io::outb(FEATURES, 0x00);
+        io::outb(SECTOR_COUNT, count);
+        io::outb(LBAL, lba.get_bits(0..8) as u8);
+        io::outb(LBAM, lba.get_bits(8..16) as u8);
+        io::outb(LBAH, lba.get_bits(16..24) as u8);
+        io::outb(COMMAND, ATACommand::ReadSectors as u8);
+        delay();
+
+        // The drive raises DRQ once per sector.
+        for _ in 0..n {
+            wait_drq(true)?;
+            for _ in 0..256 {
+                buf.extend_from_slice(&io::inw(DATA).to_le_bytes());
+            }
+        }
+    }
+
+    Ok(buf)
+}
+
 pub fn pio28_write(master: bool, lba: usize, count: u8, sec: [u8; 512]) {
 
     for i in 0..500 {}
@@ -295,6 +611,7 @@ pub fn pio28_write(master: bool, lba: usize, count: u8, sec: [u8; 512]) {
             j += 2;
         }
 
+        io::outb(DRIVESEL, drive_select(master, lba));
         io::outb(FEATURES, 0x00);
         io::outb(SECTOR_COUNT, count);
         io::outb(LBAL, lba.get_bits(24..32) as u8);
@@ -320,6 +637,15 @@ pub fn pio28_write(master: bool, lba: usize, count: u8, sec: [u8; 512]) {
     }
 }
 
+// LBA28 keeps bits 24..28 of the address in the drive select register.
+fn drive_select(master: bool, lba: usize) -> u8 {
+    let mut sel: u8 = 0xE0 | lba.get_bits(24..28) as u8;
+    if !master {
+        sel |= 0x10;
+    }
+    sel
+}
+
 #[no_mangle]
 fn delay() {
     for _ in 0..100 {}
@@ -333,6 +659,51 @@ fn delay() {
 //    timer::wait(1); */
 }
 
+// The spec asks for 400ns after a drive select; four reads of the alternate
+// status register take at least that long.
+fn select_delay(primary: bool) {
+    let p = ports(primary);
+    unsafe {
+        for _ in 0..4 {
+            io::inb(p.altstatus);
+        }
+    }
+}
+
+fn wait_not_busy(primary: bool) -> Result<(), Error> {
+    let p = ports(primary);
+    for _ in 0..POLL_LIMIT {
+        let status = unsafe { io::inb(p.status) };
+        if !status.get_bit(BSY) {
+            if status.get_bit(ERR) {
+                return Err(Error::DeviceError(unsafe { io::inb(p.features) }));
+            }
+            if status.get_bit(DF) {
+                return Err(Error::DeviceFault);
+            }
+            return Ok(());
+        }
+    }
+    Err(Error::Timeout)
+}
+
+fn wait_drq(primary: bool) -> Result<(), Error> {
+    let p = ports(primary);
+    for _ in 0..POLL_LIMIT {
+        let status = unsafe { io::inb(p.status) };
+        if status.get_bit(ERR) {
+            return Err(Error::DeviceError(unsafe { io::inb(p.features) }));
+        }
+        if status.get_bit(DF) {
+            return Err(Error::DeviceFault);
+        }
+        if !status.get_bit(BSY) && status.get_bit(DRQ) {
+            return Ok(());
+        }
+    }
+    Err(Error::Timeout)
+}
+
 fn flush_cache() {
     unsafe {
         io::outb(COMMAND, ATACommand::FlushCache as u8);
@@ -0,0 +1,169 @@
+use crate::drivers::ata;
+use crate::drivers::partition;
+use crate::println;
+use alloc::string::String;
+use alloc::vec::Vec;
+use alloc::format;
+use spin::Mutex;
+use lazy_static::lazy_static;
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Kind {
+    Ata { master: bool },
+    Atapi,
+    Partition { disk: usize, start: u64 },
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Error {
+    NoDevice,
+    OutOfRange,
+    ReadOnly,
+    BadBuffer,
+    Io(ata::Error),
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Error::NoDevice => write!(f, "no such block device"),
+            Error::OutOfRange => write!(f, "sector out of range"),
+            Error::ReadOnly => write!(f, "device is read-only"),
+            Error::BadBuffer => write!(f, "buffer is not a whole number of sectors"),
+            Error::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct BlockDevice {
+    pub name: String,
+    pub kind: Kind,
+    pub index: usize,
+    pub sectors: u64,
+    pub sector_size: usize,
+    pub read_only: bool,
+    pub part_type: partition::PartType,
+}
+
+lazy_static! {
+    pub static ref BLOCK_DEVICES: Mutex<Vec<BlockDevice>> = Mutex::new(Vec::new());
+}
+
+/// Registers the detected drives and every partition found on them.
+pub fn init() {
+    for (i, &master) in [true, false].iter().enumerate() {
+        let (detected, sectors) = {
+            let h = ata::handler(master).lock();
+            (h.detected, h.total_sectors as u64)
+        };
+        if detected {
+            let disk = register(format!("hd{}", i), Kind::Ata { master: master }, sectors, 512, false, partition::PartType::None);
+            partition::scan(disk);
+        }
+    }
+
+    if ata::ATAPI_HANDLER.lock().detected {
+        let (blocks, size) = {
+            let h = ata::ATAPI_HANDLER.lock();
+            (h.total_blocks as u64, h.block_size as usize)
+        };
+        register(String::from("cd0"), Kind::Atapi, blocks, size, true, partition::PartType::None);
+    }
+
+    for d in BLOCK_DEVICES.lock().iter() {
+        println!("[BLK] {}: {} sectors of {}B{}", d.name, d.sectors, d.sector_size, if d.read_only { " (ro)" } else { "" });
+    }
+}
+
+pub fn register(name: String, kind: Kind, sectors: u64, sector_size: usize, read_only: bool, part_type: partition::PartType) -> usize {
+    let mut devices = BLOCK_DEVICES.lock();
+    let index = devices.len();
+    devices.push(BlockDevice {
+        name: name,
+        kind: kind,
+        index: index,
+        sectors: sectors,
+        sector_size: sector_size,
+        read_only: read_only,
+        part_type: part_type,
+    });
+    index
+}
+
+pub fn get(dev: usize) -> Option<BlockDevice> {
+    BLOCK_DEVICES.lock().get(dev).cloned()
+}
+
+pub fn find(name: &str) -> Option<usize> {
+    BLOCK_DEVICES.lock().iter().position(|d| d.name == name)
+}
+
+/// Reads one sector. `lba` is relative to the start of the device, so a
+/// partition's sector 0 is its first sector rather than the disk's.
+pub fn read(dev: usize, lba: u64) -> Result<Vec<u8>, Error> {
+    let d = get(dev).ok_or(Error::NoDevice)?;
+    if lba >= d.sectors {
+        return Err(Error::OutOfRange);
+    }
+
+    match d.kind {
+        Kind::Ata { master } => Ok(ata::pio28_read(master, lba as usize, 1).to_vec()),
+        Kind::Atapi => ata::atapi_read(lba as u32).map_err(Error::Io),
+        Kind::Partition { disk, start } => read(disk, start + lba),
+    }
+}
+
+/// Reads `count` consecutive sectors, with as few device commands as the
+/// driver allows.
+pub fn read_many(dev: usize, lba: u64, count: u64) -> Result<Vec<u8>, Error> {
+    let d = get(dev).ok_or(Error::NoDevice)?;
+    if lba + count > d.sectors {
+        return Err(Error::OutOfRange);
+    }
+
+    match d.kind {
+        Kind::Ata { master } => {
+            let mut buf: Vec<u8> = Vec::with_capacity(count as usize * 512);
+            let mut done = 0;
+            while done < count {
+                let n = (count - done).min(255);
+                buf.extend_from_slice(&ata::pio28_read_many(master, (lba + done) as usize, n as u8).map_err(Error::Io)?);
+                done += n;
+            }
+            Ok(buf)
+        },
+        Kind::Atapi => {
+            let mut buf: Vec<u8> = Vec::with_capacity(count as usize * d.sector_size);
+            for i in 0..count {
+                buf.extend_from_slice(&ata::atapi_read((lba + i) as u32).map_err(Error::Io)?);
+            }
+            Ok(buf)
+        },
+        Kind::Partition { disk, start } => read_many(disk, start + lba, count),
+    }
+}
+
+pub fn write(dev: usize, lba: u64, buf: &[u8]) -> Result<(), Error> {
+    let d = get(dev).ok_or(Error::NoDevice)?;
+    if lba >= d.sectors {
+        return Err(Error::OutOfRange);
+    }
+    if d.read_only {
+        return Err(Error::ReadOnly);
+    }
+    if buf.len() != d.sector_size {
+        return Err(Error::BadBuffer);
+    }
+
+    match d.kind {
+        Kind::Ata { master } => {
+            let mut sec = [0u8; 512];
+            sec.copy_from_slice(buf);
+            ata::pio28_write(master, lba as usize, 1, sec);
+            Ok(())
+        },
+        Kind::Atapi => Err(Error::ReadOnly),
+        Kind::Partition { disk, start } => write(disk, start + lba, buf),
+    }
+}
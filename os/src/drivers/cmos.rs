@@ -2,6 +2,7 @@ use lazy_static::lazy_static;
 use spin::Mutex;
 use crate::io;
 use alloc::string::{ToString, String};
+use crate::time;
 pub const CURRENT_YEAR: usize = 2020;
 pub const CMOS_ADDR: u16 = 0x70;
 pub const CMOS_DATA: u16 = 0x71;
@@ -132,6 +133,18 @@ impl RtcHandler {
         r
     }
 
+    pub fn get_unix_time(&mut self) -> u64 {
+        self.read_rtc();
+        time::to_unix(time::DateTime {
+            year: self.year as u64,
+            month: self.month as u64,
+            day: self.day as u64,
+            hour: self.hour as u64,
+            minute: self.minute as u64,
+            second: self.second as u64,
+        })
+    }
+
     pub fn get_datetime(&mut self) -> String {
         let mut r = self.get_date();
         r.push_str(&String::from(" "));
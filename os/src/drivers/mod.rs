@@ -1,3 +1,4 @@
 pub mod ata;
+pub mod block;
 pub mod cmos;
-
+pub mod partition;
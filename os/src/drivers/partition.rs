@@ -0,0 +1,233 @@
+use crate::drivers::block;
+use crate::println;
+use alloc::string::String;
+use alloc::vec::Vec;
+use alloc::format;
+use core::convert::TryInto;
+
+pub const MBR_SIG: [u8; 2] = [0x55, 0xAA];
+pub const GPT_SIG: [u8; 8] = [b'E', b'F', b'I', b' ', b'P', b'A', b'R', b'T'];
+
+pub const MBR_EMPTY: u8 = 0x00;
+pub const MBR_EXTENDED: u8 = 0x05;
+pub const MBR_EXTENDED_LBA: u8 = 0x0F;
+pub const MBR_GPT_PROTECTIVE: u8 = 0xEE;
+
+/// MBR system id registered for wFS partitions.
+pub const MBR_WFS: u8 = 0x77;
+
+/// GPT partition type GUID registered for wFS partitions,
+/// 7753f377-5fa7-4c3f-9b0a-2e5746535f57, in on-disk (mixed endian) order.
+pub const GPT_WFS: [u8; 16] = [
+    0x77, 0xf3, 0x53, 0x77, 0xa7, 0x5f, 0x3f, 0x4c,
+    0x9b, 0x0a, 0x2e, 0x57, 0x46, 0x53, 0x5f, 0x57,
+];
+
+const MBR_TABLE: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+const GPT_MAX_ENTRIES: u32 = 128;
+const GPT_REVISION: u32 = 0x0001_0000;
+const GPT_HEADER_MIN: usize = 92;
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum PartType {
+    None,
+    Mbr(u8),
+    Gpt([u8; 16]),
+}
+
+impl PartType {
+    pub fn is_wfs(&self) -> bool {
+        match *self {
+            PartType::Mbr(id) => id == MBR_WFS,
+            PartType::Gpt(guid) => guid == GPT_WFS,
+            PartType::None => false,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Partition {
+    pub start: u64,
+    pub sectors: u64,
+    pub part_type: PartType,
+}
+
+/// Looks for a partition table on `disk` and registers every partition as a
+/// block device named after the disk (`hd0p1`, `hd0p2`, ...).
+pub fn scan(disk: usize) {
+    let parts = match read_table(disk) {
+        Some(p) => p,
+        None => return,
+    };
+
+    let name = match block::get(disk) {
+        Some(d) => d.name,
+        None => return,
+    };
+
+    for (i, p) in parts.iter().enumerate() {
+        block::register(format!("{}p{}", name, i + 1), block::Kind::Partition { disk: disk, start: p.start },
+            p.sectors, 512, false, p.part_type);
+    }
+}
+
+/// Returns the partitions of `disk`, or `None` if it has no partition table.
+pub fn read_table(disk: usize) -> Option<Vec<Partition>> {
+    let mbr = block::read(disk, 0).ok()?;
+    if mbr[510..512] != MBR_SIG {
+        return None;
+    }
+
+    let entries = mbr_entries(&mbr);
+    if entries.iter().any(|p| p.part_type == PartType::Mbr(MBR_GPT_PROTECTIVE)) {
+        match gpt_entries(disk) {
+            Some(p) => return Some(p),
+            None => println!("[PART] protective MBR without a valid GPT"),
+        }
+    }
+
+    let total = block::get(disk).map(|d| d.sectors).unwrap_or(0);
+    mbr_table(entries, total)
+}
+
+// A boot sector that merely ends in 55AA (e.g. a FAT volume without a
+// partition table) has no entries, or no sane ones; it isn't a table.
+fn mbr_table(entries: Vec<Partition>, total: u64) -> Option<Vec<Partition>> {
+    if entries.is_empty() || entries.iter().any(|p| p.start == 0 || p.start + p.sectors > total) {
+        return None;
+    }
+    Some(entries)
+}
+
+fn mbr_entries(mbr: &[u8]) -> Vec<Partition> {
+    let mut res: Vec<Partition> = Vec::new();
+
+    for i in 0..4 {
+        let e = &mbr[MBR_TABLE + i * MBR_ENTRY_SIZE..MBR_TABLE + (i + 1) * MBR_ENTRY_SIZE];
+        let id = e[4];
+        if id == MBR_EMPTY || id == MBR_EXTENDED || id == MBR_EXTENDED_LBA {
+            continue;
+        }
+
+        res.push(Partition {
+            start: u32::from_le_bytes(e[8..12].try_into().expect("")) as u64,
+            sectors: u32::from_le_bytes(e[12..16].try_into().expect("")) as u64,
+            part_type: PartType::Mbr(id),
+        });
+    }
+
+    res
+}
+
+// The header must carry its signature, revision 1.0 and a matching CRC32;
+// the table and every partition must lie on the disk.
+fn gpt_entries(disk: usize) -> Option<Vec<Partition>> {
+    let total = block::get(disk)?.sectors;
+    let header = block::read(disk, 1).ok()?;
+    if header[0..8] != GPT_SIG || u32::from_le_bytes(header[8..12].try_into().expect("")) != GPT_REVISION {
+        return None;
+    }
+
+    let header_size = u32::from_le_bytes(header[12..16].try_into().expect("")) as usize;
+    if header_size < GPT_HEADER_MIN || header_size > 512 {
+        return None;
+    }
+    let mut h = header[..header_size].to_vec();
+    h[16..20].copy_from_slice(&[0; 4]);
+    if crc32(&h) != u32::from_le_bytes(header[16..20].try_into().expect("")) {
+        return None;
+    }
+
+    let table_lba = u64::from_le_bytes(header[72..80].try_into().expect(""));
+    let count = u32::from_le_bytes(header[80..84].try_into().expect(""));
+    let entry_size = u32::from_le_bytes(header[84..88].try_into().expect("")) as usize;
+    if entry_size < 128 || entry_size > 512 || 512 % entry_size != 0 {
+        return None;
+    }
+
+    let per_sector = 512 / entry_size;
+    let count = if count > GPT_MAX_ENTRIES { GPT_MAX_ENTRIES } else { count } as usize;
+    let table_sectors = ((count + per_sector - 1) / per_sector) as u64;
+    if table_lba < 2 || table_lba + table_sectors > total {
+        return None;
+    }
+
+    let mut res: Vec<Partition> = Vec::new();
+    let mut sec: Vec<u8> = Vec::new();
+    for i in 0..count {
+        if i % per_sector == 0 {
+            sec = block::read(disk, table_lba + (i / per_sector) as u64).ok()?;
+        }
+
+        let off = (i % per_sector) * entry_size;
+        let e = &sec[off..off + entry_size];
+        let guid: [u8; 16] = e[0..16].try_into().expect("");
+        if guid == [0; 16] {
+            continue;
+        }
+
+        let first = u64::from_le_bytes(e[32..40].try_into().expect(""));
+        let last = u64::from_le_bytes(e[40..48].try_into().expect(""));
+        if first == 0 || last < first || last >= total {
+            continue;
+        }
+
+        res.push(Partition {
+            start: first,
+            sectors: last - first + 1,
+            part_type: PartType::Gpt(guid),
+        });
+    }
+
+    Some(res)
+}
+
+// CRC-32 as used by GPT (IEEE, reflected, polynomial 0xEDB88320).
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { crc >> 1 ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/// Formats an on-disk GUID the usual way (first three fields are little endian).
+pub fn guid_to_string(g: &[u8; 16]) -> String {
+    format!("{:02x}{:02x}{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
+        g[3], g[2], g[1], g[0], g[5], g[4], g[7], g[6], g[8], g[9], g[10], g[11], g[12], g[13], g[14], g[15])
+}
+
+// -----TESTS-----
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_unpartitioned_fat() {
+    serial_print!("test_unpartitioned_fat... ");
+
+    // A FAT boot sector as mkfs.fat writes it to a whole disk: the partition
+    // table area is zero and the sector still ends in 55AA.
+    let mut sec = [0u8; 512];
+    sec[0..3].copy_from_slice(&[0xEB, 0x3C, 0x90]);
+    sec[3..11].copy_from_slice(b"mkfs.fat");
+    sec[11..13].copy_from_slice(&512u16.to_le_bytes());
+    sec[13] = 4;
+    sec[54..62].copy_from_slice(b"FAT16   ");
+    sec[510..512].copy_from_slice(&MBR_SIG);
+    assert!(mbr_table(mbr_entries(&sec), 8192).is_none());
+
+    // One partition from sector 2048 makes it a table.
+    sec[MBR_TABLE + 4] = MBR_WFS;
+    sec[MBR_TABLE + 8..MBR_TABLE + 12].copy_from_slice(&2048u32.to_le_bytes());
+    sec[MBR_TABLE + 12..MBR_TABLE + 16].copy_from_slice(&4096u32.to_le_bytes());
+    let parts = mbr_table(mbr_entries(&sec), 8192).unwrap();
+    assert_eq!(parts.len(), 1);
+    assert_eq!((parts[0].start, parts[0].sectors), (2048, 4096));
+
+    serial_println!("[ok]");
+}
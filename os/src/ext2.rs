@@ -0,0 +1,457 @@
+//ext2: the second extended filesystem, read-only.
+//Every disk or partition holding an ext2 volume is mounted at boot, at the
+//next free drive letter from C:. Node ids are inode numbers.
+
+use crate::vfs;
+use crate::drivers::block;
+use crate::drivers::partition;
+use crate::cache;
+use crate::println;
+use spin::Mutex;
+use lazy_static::lazy_static;
+use bit_field::BitField;
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use alloc::vec;
+use core::convert::TryInto;
+
+const SECTOR: u64 = 512;
+const SUPERBLOCK: u64 = 1024;
+const EXT2_MAGIC: u16 = 0xEF53;
+const ROOT_INODE: u64 = 2;
+const GOOD_OLD_INODE_SIZE: u64 = 128;
+const GROUP_DESC_SIZE: u64 = 32;
+
+// Incompatible features we can read. Anything else (compression, journal
+// replay, extents, 64 bit block numbers) means the volume is left alone.
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+const INCOMPAT_SUPPORTED: u32 = INCOMPAT_FILETYPE;
+
+const S_IFMT: u16 = 0xF000;
+const S_IFDIR: u16 = 0x4000;
+const S_IFLNK: u16 = 0xA000;
+
+const DIRECT_BLOCKS: usize = 12;
+const INLINE_LINK: u64 = 60;
+
+#[derive(Clone)]
+pub struct Volume {
+    pub name: String,
+    pub dev: usize,
+    pub label: String,
+    pub block_size: u64,
+    incompat: u32,
+    blocks: u32,
+    inodes: u32,
+    first_data_block: u32,
+    inodes_per_group: u32,
+    inode_size: u64,
+    filetype: bool,
+}
+
+#[derive(Clone)]
+struct Inode {
+    mode: u16,
+    size: u64,
+    mtime: u32,
+    sectors: u32,
+    file_acl: u32,
+    block: [u32; 15],
+}
+
+impl Inode {
+    fn is_dir(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+
+    fn is_symlink(&self) -> bool {
+        self.mode & S_IFMT == S_IFLNK
+    }
+}
+
+// A name in a directory.
+struct Entry {
+    name: String,
+    inode: u64,
+}
+
+lazy_static! {
+    // Volumes by VFS device index.
+    static ref VOLUMES: Mutex<BTreeMap<usize, Volume>> = Mutex::new(BTreeMap::new());
+}
+
+pub fn init() {
+    let devices = block::BLOCK_DEVICES.lock().clone();
+
+    for d in devices.iter() {
+        // Partitioned disks are mounted through their partitions.
+        match d.kind {
+            block::Kind::Atapi => continue,
+            block::Kind::Ata { .. } if partition::read_table(d.index).is_some() => continue,
+            _ => {},
+        }
+
+        let mut v = match parse_superblock(d.index) {
+            Some(v) => v,
+            None => continue,
+        };
+        if v.incompat & !INCOMPAT_SUPPORTED != 0 {
+            println!("[EXT2] {} uses unsupported features ({:#x}). Not mounting.", d.name, v.incompat & !INCOMPAT_SUPPORTED);
+            continue;
+        }
+
+        let letter = match vfs::free_letter() {
+            Some(l) => l,
+            None => {
+                println!("[EXT2] No drive letter left for {}.", d.name);
+                return;
+            },
+        };
+        let id = match vfs::install_device(letter.to_string(), vfs::System::Ext2) {
+            Ok(i) => i,
+            Err(e) => {
+                println!("[EXT2] Could not mount {} ({}).", d.name, e);
+                continue;
+            },
+        };
+
+        println!("[EXT2] Mounted '{}' on {} as {} (read-only).", v.label, d.name, letter);
+        v.name = letter;
+        VOLUMES.lock().insert(id, v);
+    }
+}
+
+/// Whether `dev` holds an ext2 volume, so it isn't taken for an empty disk.
+pub fn is_volume(dev: usize) -> bool {
+    parse_superblock(dev).is_some()
+}
+
+// VFS functions
+
+pub fn get_root(dev_id: usize) -> Result<vfs::FsNode, vfs::Error> {
+    let v = volume(dev_id)?;
+    let root = read_inode(&v, ROOT_INODE)?;
+    let mut node = node_from_inode(&root, ROOT_INODE, v.name, 0, dev_id);
+    node.attributes.set_bit(vfs::ATTR_SYS, true);
+    Ok(node)
+}
+
+pub fn find_node(parent_id: u64, name: String, dev_id: usize) -> Result<vfs::FsNode, vfs::Error> {
+    let v = volume(dev_id)?;
+    let parent = read_inode(&v, parent_id)?;
+    if !parent.is_dir() {
+        return Err(vfs::Error::ParentNotDirectory);
+    }
+
+    let e = read_dir(&v, &parent)?.into_iter().find(|e| e.name == name).ok_or(vfs::Error::FileNotFound)?;
+    let inode = read_inode(&v, e.inode)?;
+    Ok(node_from_inode(&inode, e.inode, e.name, parent_id, dev_id))
+}
+
+/// Only directories can be found by id: a file doesn't know which
+/// directory (or directories, with hard links) hold it.
+pub fn find_node_by_id(id: u64, dev_id: usize) -> Result<vfs::FsNode, vfs::Error> {
+    let v = volume(dev_id)?;
+    if id == ROOT_INODE {
+        return get_root(dev_id);
+    }
+
+    let inode = read_inode(&v, id)?;
+    if !inode.is_dir() {
+        return Err(vfs::Error::FileNotFound);
+    }
+    let parent_id = parent_of(&v, &inode)?;
+    let parent = read_inode(&v, parent_id)?;
+    let e = read_dir(&v, &parent)?.into_iter().find(|e| e.inode == id).ok_or(vfs::Error::FileNotFound)?;
+    Ok(node_from_inode(&inode, id, e.name, parent_id, dev_id))
+}
+
+pub fn get_parent(id: u64, dev_id: usize) -> Result<vfs::FsNode, vfs::Error> {
+    let v = volume(dev_id)?;
+    let inode = read_inode(&v, id)?;
+    if !inode.is_dir() {
+        return Err(vfs::Error::FileNotFound);
+    }
+    find_node_by_id(parent_of(&v, &inode)?, dev_id)
+}
+
+pub fn get_children(id: u64, dev_id: usize) -> Result<Vec<vfs::FsNode>, vfs::Error> {
+    let v = volume(dev_id)?;
+    let dir = read_inode(&v, id)?;
+    if !dir.is_dir() {
+        return Err(vfs::Error::IllegalOperation);
+    }
+
+    let mut ret = Vec::new();
+    for e in read_dir(&v, &dir)? {
+        if e.name == "." || e.name == ".." {
+            continue;
+        }
+        let inode = read_inode(&v, e.inode)?;
+        ret.push(node_from_inode(&inode, e.inode, e.name, id, dev_id));
+    }
+    Ok(ret)
+}
+
+/// The contents of a file, or the target of a symbolic link.
+pub fn read_node(id: u64, dev_id: usize) -> Result<Vec<u8>, vfs::Error> {
+    let v = volume(dev_id)?;
+    let inode = read_inode(&v, id)?;
+    if inode.is_dir() {
+        return Err(vfs::Error::IllegalOperation);
+    }
+    read_data(&v, &inode)
+}
+
+//ext2 specific functions
+
+fn parse_superblock(dev: usize) -> Option<Volume> {
+    let d = block::get(dev)?;
+    if d.sector_size as u64 != SECTOR {
+        return None;
+    }
+    let sb = cache::read_many(dev, SUPERBLOCK / SECTOR, 2).ok()?;
+    if u16_at(&sb, 56) != EXT2_MAGIC {
+        return None;
+    }
+
+    let inodes = u32_at(&sb, 0);
+    let blocks = u32_at(&sb, 4);
+    let first_data_block = u32_at(&sb, 20);
+    let log_block_size = u32_at(&sb, 24);
+    let blocks_per_group = u32_at(&sb, 32);
+    let inodes_per_group = u32_at(&sb, 40);
+    let revision = u32_at(&sb, 76);
+    if log_block_size > 6 || blocks_per_group == 0 || inodes_per_group == 0 || first_data_block >= blocks {
+        return None;
+    }
+    let block_size = 1024u64 << log_block_size;
+    if blocks as u64 * block_size > d.sectors * SECTOR {
+        return None;
+    }
+
+    // Revision 0 has fixed size inodes and no feature flags.
+    let (inode_size, incompat) = match revision {
+        0 => (GOOD_OLD_INODE_SIZE, 0),
+        _ => (u16_at(&sb, 88) as u64, u32_at(&sb, 96)),
+    };
+    if inode_size < GOOD_OLD_INODE_SIZE || !inode_size.is_power_of_two() || inode_size > block_size {
+        return None;
+    }
+    let groups = (blocks - first_data_block) as u64 / blocks_per_group as u64 + 1;
+    if inodes as u64 > groups * inodes_per_group as u64 {
+        return None;
+    }
+
+    let label = String::from_utf8_lossy(&sb[120..136]).trim_end_matches('\0').to_string();
+
+    Some(Volume {
+        name: String::new(),
+        dev: dev,
+        label: if label.is_empty() { String::from("NO NAME") } else { label },
+        block_size: block_size,
+        incompat: incompat,
+        blocks: blocks,
+        inodes: inodes,
+        first_data_block: first_data_block,
+        inodes_per_group: inodes_per_group,
+        inode_size: inode_size,
+        filetype: incompat & INCOMPAT_FILETYPE != 0,
+    })
+}
+
+fn volume(dev_id: usize) -> Result<Volume, vfs::Error> {
+    VOLUMES.lock().get(&dev_id).cloned().ok_or(vfs::Error::DeviceNotFound)
+}
+
+// `len` bytes from byte `offset` of the volume.
+fn read_bytes(v: &Volume, offset: u64, len: usize) -> Result<Vec<u8>, vfs::Error> {
+    let first = offset / SECTOR;
+    let count = (offset % SECTOR + len as u64 + SECTOR - 1) / SECTOR;
+    let buf = cache::read_many(v.dev, first, count).map_err(|e| vfs::Error::io(v.dev, e))?;
+    let start = (offset % SECTOR) as usize;
+    Ok(buf[start..start + len].to_vec())
+}
+
+// Block 0 in a block map is a hole and reads as zeros.
+fn read_block(v: &Volume, b: u32) -> Result<Vec<u8>, vfs::Error> {
+    if b == 0 {
+        return Ok(vec![0; v.block_size as usize]);
+    }
+    if b >= v.blocks {
+        return Err(vfs::Error::ReadError);
+    }
+    read_bytes(v, b as u64 * v.block_size, v.block_size as usize)
+}
+
+fn read_inode(v: &Volume, n: u64) -> Result<Inode, vfs::Error> {
+    if n == 0 || n > v.inodes as u64 {
+        return Err(vfs::Error::FileNotFound);
+    }
+    let group = (n - 1) / v.inodes_per_group as u64;
+    let index = (n - 1) % v.inodes_per_group as u64;
+
+    // The group descriptors start in the block after the superblock.
+    let desc = read_bytes(v, (v.first_data_block as u64 + 1) * v.block_size + group * GROUP_DESC_SIZE, GROUP_DESC_SIZE as usize)?;
+    let table = u32_at(&desc, 8);
+    if table == 0 || table >= v.blocks {
+        return Err(vfs::Error::ReadError);
+    }
+
+    let raw = read_bytes(v, table as u64 * v.block_size + index * v.inode_size, GOOD_OLD_INODE_SIZE as usize)?;
+    let mode = u16_at(&raw, 0);
+    if mode == 0 {
+        return Err(vfs::Error::FileNotFound);
+    }
+
+    // Regular files keep the high half of the size where directories had
+    // their ACL.
+    let mut size = u32_at(&raw, 4) as u64;
+    if mode & S_IFMT != S_IFDIR {
+        size |= (u32_at(&raw, 108) as u64) << 32;
+    }
+
+    let mut block = [0u32; 15];
+    for (i, b) in block.iter_mut().enumerate() {
+        *b = u32_at(&raw, 40 + i * 4);
+    }
+
+    Ok(Inode {
+        mode: mode,
+        size: size,
+        mtime: u32_at(&raw, 16),
+        sectors: u32_at(&raw, 28),
+        file_acl: u32_at(&raw, 104),
+        block: block,
+    })
+}
+
+// Blocks holding the data of `inode`, in order: twelve direct ones, then
+// those behind the single, double and triple indirect blocks.
+fn data_blocks(v: &Volume, inode: &Inode) -> Result<Vec<u32>, vfs::Error> {
+    let n = ((inode.size + v.block_size - 1) / v.block_size) as usize;
+    let mut ret: Vec<u32> = Vec::new();
+
+    for &b in inode.block[..DIRECT_BLOCKS].iter() {
+        if ret.len() == n {
+            return Ok(ret);
+        }
+        ret.push(b);
+    }
+    for level in 1..=3 {
+        if ret.len() == n {
+            return Ok(ret);
+        }
+        indirect_blocks(v, inode.block[DIRECT_BLOCKS - 1 + level], level as u32, n, &mut ret)?;
+    }
+
+    Ok(ret)
+}
+
+fn indirect_blocks(v: &Volume, b: u32, level: u32, n: usize, ret: &mut Vec<u32>) -> Result<(), vfs::Error> {
+    let per_block = (v.block_size / 4) as usize;
+
+    // A hole in the map covers everything below it.
+    if b == 0 {
+        let span = per_block.pow(level);
+        let holes = span.min(n - ret.len());
+        ret.extend(core::iter::repeat(0).take(holes));
+        return Ok(());
+    }
+
+    let buf = read_block(v, b)?;
+    for i in 0..per_block {
+        if ret.len() == n {
+            break;
+        }
+        let c = u32_at(&buf, i * 4);
+        if level == 1 {
+            ret.push(c);
+        } else {
+            indirect_blocks(v, c, level - 1, n, ret)?;
+        }
+    }
+    Ok(())
+}
+
+fn read_data(v: &Volume, inode: &Inode) -> Result<Vec<u8>, vfs::Error> {
+    // Short link targets are kept in the block map itself.
+    let acl_sectors = if inode.file_acl != 0 { (v.block_size / SECTOR) as u32 } else { 0 };
+    if inode.is_symlink() && inode.size < INLINE_LINK && inode.sectors == acl_sectors {
+        let mut raw: Vec<u8> = inode.block.iter().flat_map(|b| b.to_le_bytes().to_vec()).collect();
+        raw.truncate(inode.size as usize);
+        return Ok(raw);
+    }
+
+    // The file is read in one piece, so it may take only part of the heap.
+    if inode.size > vfs::READ_MAX as u64 {
+        return Err(vfs::Error::NoSpace);
+    }
+
+    let size = inode.size as usize;
+    let mut ret: Vec<u8> = Vec::with_capacity(size);
+    for b in data_blocks(v, inode)? {
+        let block = read_block(v, b)?;
+        let n = (size - ret.len()).min(block.len());
+        ret.extend_from_slice(&block[..n]);
+    }
+    Ok(ret)
+}
+
+// Entries never cross a block; an entry's length covers the free space
+// after it, and deleted entries have inode 0.
+fn read_dir(v: &Volume, dir: &Inode) -> Result<Vec<Entry>, vfs::Error> {
+    let data = read_data(v, dir)?;
+    let mut ret = Vec::new();
+
+    let mut off = 0;
+    while off + 8 <= data.len() {
+        let inode = u32_at(&data, off) as u64;
+        let rec_len = u16_at(&data, off + 4) as usize;
+        // Without the filetype feature the name length is 16 bits.
+        let name_len = if v.filetype { data[off + 6] as usize } else { u16_at(&data, off + 6) as usize };
+        if rec_len < 8 || rec_len % 4 != 0 || off + rec_len > data.len() || 8 + name_len > rec_len {
+            return Err(vfs::Error::ReadError);
+        }
+
+        if inode != 0 {
+            ret.push(Entry {
+                name: String::from_utf8_lossy(&data[off + 8..off + 8 + name_len]).to_string(),
+                inode: inode,
+            });
+        }
+        off += rec_len;
+    }
+
+    Ok(ret)
+}
+
+fn parent_of(v: &Volume, dir: &Inode) -> Result<u64, vfs::Error> {
+    read_dir(v, dir)?.into_iter().find(|e| e.name == "..").map(|e| e.inode).ok_or(vfs::Error::ReadError)
+}
+
+fn node_from_inode(inode: &Inode, id: u64, name: String, parent_id: u64, dev_id: usize) -> vfs::FsNode {
+    vfs::FsNode {
+        name: name,
+        device: dev_id,
+        parent_id: parent_id,
+        id: id,
+        attributes: *0u8.set_bit(vfs::ATTR_RO, true)
+            .set_bit(vfs::ATTR_DIR, inode.is_dir())
+            .set_bit(vfs::ATTR_LNK, inode.is_symlink()),
+        t_creation: inode.mtime as u64,
+        t_edit: inode.mtime as u64,
+        owner: 0,
+        size: inode.size,
+        open: false,
+    }
+}
+
+fn u16_at(b: &[u8], off: usize) -> u16 {
+    u16::from_le_bytes(b[off..off + 2].try_into().unwrap())
+}
+
+fn u32_at(b: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes(b[off..off + 4].try_into().unwrap())
+}
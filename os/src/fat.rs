@@ -0,0 +1,1160 @@
+//FAT12/16/32 with VFAT long file names.
+//Every disk or partition holding a FAT volume is mounted at boot, at the next
+//free drive letter from C:.
+
+use crate::vfs;
+use crate::drivers::block;
+use crate::drivers::partition;
+use crate::cache;
+use crate::println;
+use crate::time;
+use spin::Mutex;
+use lazy_static::lazy_static;
+use bit_field::BitField;
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use alloc::format;
+use core::convert::TryInto;
+
+const SECTOR: u64 = 512;
+const ENTRY: usize = 32;
+// A directory holds at most 65536 entries, so an index fits in 16 bits.
+const MAX_ENTRIES: usize = 65536;
+
+const FAT_RO: u8 = 0x01;
+const FAT_HIDDEN: u8 = 0x02;
+const FAT_SYSTEM: u8 = 0x04;
+const FAT_VOLUME: u8 = 0x08;
+const FAT_DIR: u8 = 0x10;
+const FAT_ARCHIVE: u8 = 0x20;
+const FAT_LFN: u8 = 0x0F;
+
+const ENTRY_FREE: u8 = 0xE5;
+const ENTRY_END: u8 = 0x00;
+// A short name starting with 0xE5 stores 0x05 instead.
+const ENTRY_KANJI: u8 = 0x05;
+const LFN_LAST: u8 = 0x40;
+const LFN_CHARS: [usize; 13] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+const LFN_MAX_SLOTS: usize = 20;
+
+// Byte 12 of a short entry: the base name or extension is shown lower case.
+const CASE_BASE: u8 = 0x08;
+const CASE_EXT: u8 = 0x10;
+
+const DOT: [u8; 11] = *b".          ";
+const DOTDOT: [u8; 11] = *b"..         ";
+
+const FSINFO_SIG1: u32 = 0x41615252;
+const FSINFO_SIG2: u32 = 0x61417272;
+const FSINFO_UNKNOWN: u32 = 0xFFFFFFFF;
+
+// Directories are named by their first cluster, the root by 1 (no cluster
+// has that number). A file is named by its directory and the index of its
+// short entry there, so it changes when the file is renamed.
+const ROOT_ID: u64 = 1;
+const FILE_TAG: u64 = 1 << 63;
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+impl FatType {
+    pub fn bits(&self) -> u32 {
+        match *self {
+            FatType::Fat12 => 12,
+            FatType::Fat16 => 16,
+            FatType::Fat32 => 32,
+        }
+    }
+
+    fn end_of_chain(&self) -> u32 {
+        match *self {
+            FatType::Fat12 => 0xFFF,
+            FatType::Fat16 => 0xFFFF,
+            FatType::Fat32 => 0x0FFFFFFF,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Volume {
+    pub name: String,
+    pub dev: usize,
+    pub fat_type: FatType,
+    pub label: String,
+    pub clusters: u32,
+    pub cluster_size: u64,
+    sectors_per_cluster: u64,
+    fat_start: u64,
+    fat_sectors: u64,
+    fats: u64,
+    root_start: u64,
+    root_sectors: u64,
+    root_cluster: u32,
+    data_start: u64,
+    fsinfo: u64,
+    next_free: u32,
+}
+
+// A name in a directory: its short entry, at `index`, and the long name
+// entries in the `slots` entries before it.
+#[derive(Clone)]
+struct Record {
+    name: String,
+    index: usize,
+    slots: usize,
+    entry: [u8; ENTRY],
+}
+
+impl Record {
+    fn is_dir(&self) -> bool {
+        self.entry[11] & FAT_DIR != 0
+    }
+
+    fn cluster(&self) -> u32 {
+        (u16_at(&self.entry, 20) as u32) << 16 | u16_at(&self.entry, 26) as u32
+    }
+
+    fn size(&self) -> u32 {
+        u32_at(&self.entry, 28)
+    }
+
+    // Long and short name are both looked up, ignoring case like DOS does.
+    fn matches(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name) || short_string(&self.entry).eq_ignore_ascii_case(name)
+    }
+}
+
+lazy_static! {
+    // Volumes by VFS device index.
+    static ref VOLUMES: Mutex<BTreeMap<usize, Volume>> = Mutex::new(BTreeMap::new());
+}
+
+pub fn init() {
+    let devices = block::BLOCK_DEVICES.lock().clone();
+
+    for d in devices.iter() {
+        // Partitioned disks are mounted through their partitions.
+        match d.kind {
+            block::Kind::Atapi => continue,
+            block::Kind::Ata { .. } if partition::read_table(d.index).is_some() => continue,
+            _ => {},
+        }
+
+        let mut v = match parse_boot(d.index) {
+            Some(v) => v,
+            None => continue,
+        };
+
+        let letter = match vfs::free_letter() {
+            Some(l) => l,
+            None => {
+                println!("[FAT] No drive letter left for {}.", d.name);
+                return;
+            },
+        };
+        let id = match vfs::install_device(letter.to_string(), vfs::System::FAT) {
+            Ok(i) => i,
+            Err(e) => {
+                println!("[FAT] Could not mount {} ({}).", d.name, e);
+                continue;
+            },
+        };
+
+        println!("[FAT] Mounted FAT{} volume '{}' on {} as {}.", v.fat_type.bits(), v.label, d.name, letter);
+        v.name = letter;
+        VOLUMES.lock().insert(id, v);
+    }
+}
+
+/// Whether `dev` holds a FAT volume, so it isn't taken for an empty disk.
+pub fn is_volume(dev: usize) -> bool {
+    parse_boot(dev).is_some()
+}
+
+// VFS functions
+
+pub fn get_root(dev_id: usize) -> Result<vfs::FsNode, vfs::Error> {
+    let v = volume(dev_id)?;
+    Ok(vfs::FsNode {
+        name: v.name,
+        device: dev_id,
+        parent_id: 0,
+        id: ROOT_ID,
+        attributes: *0u8.set_bit(vfs::ATTR_DIR, true).set_bit(vfs::ATTR_SYS, true),
+        t_creation: 0,
+        t_edit: 0,
+        owner: 0,
+        size: 0,
+        open: false,
+    })
+}
+
+pub fn find_node(parent_id: u64, name: String, dev_id: usize) -> Result<vfs::FsNode, vfs::Error> {
+    let v = volume(dev_id)?;
+    let (_, r) = lookup(&v, parent_id, &name)?;
+    Ok(node_from_record(&r, parent_id, dev_id))
+}
+
+pub fn find_node_by_id(id: u64, dev_id: usize) -> Result<vfs::FsNode, vfs::Error> {
+    let v = volume(dev_id)?;
+    if id == ROOT_ID {
+        return get_root(dev_id);
+    }
+
+    if id & FILE_TAG != 0 {
+        let parent_id = (id & !FILE_TAG) >> 16;
+        let index = (id & 0xFFFF) as usize;
+        let r = read_dir(&v, dir_cluster(&v, parent_id)?)?.into_iter().find(|r| r.index == index && !r.is_dir())
+            .ok_or(vfs::Error::FileNotFound)?;
+        return Ok(node_from_record(&r, parent_id, dev_id));
+    }
+
+    let c = id as u32;
+    let parent = parent_of(&v, c)?;
+    let r = read_dir(&v, parent)?.into_iter().find(|r| r.is_dir() && r.cluster() == c)
+        .ok_or(vfs::Error::FileNotFound)?;
+    Ok(node_from_record(&r, dir_id(parent), dev_id))
+}
+
+pub fn get_parent(id: u64, dev_id: usize) -> Result<vfs::FsNode, vfs::Error> {
+    let v = volume(dev_id)?;
+    if id == ROOT_ID {
+        return get_root(dev_id);
+    }
+    if id & FILE_TAG != 0 {
+        return find_node_by_id((id & !FILE_TAG) >> 16, dev_id);
+    }
+    find_node_by_id(dir_id(parent_of(&v, id as u32)?), dev_id)
+}
+
+pub fn get_children(id: u64, dev_id: usize) -> Result<Vec<vfs::FsNode>, vfs::Error> {
+    let v = volume(dev_id)?;
+    let dir = dir_cluster(&v, id).map_err(|_| vfs::Error::IllegalOperation)?;
+    Ok(read_dir(&v, dir)?.iter().map(|r| node_from_record(r, id, dev_id)).collect())
+}
+
+pub fn read_node(parent_id: u64, name: String, dev_id: usize) -> Result<Vec<u8>, vfs::Error> {
+    let v = volume(dev_id)?;
+    let (_, r) = lookup(&v, parent_id, &name)?;
+    if r.is_dir() {
+        return Err(vfs::Error::IllegalOperation);
+    }
+
+    let size = r.size() as usize;
+    if size > vfs::READ_MAX {
+        return Err(vfs::Error::NoSpace);
+    }
+    let mut ret: Vec<u8> = Vec::with_capacity(size);
+    if r.cluster() != 0 {
+        for c in chain(&v, r.cluster())? {
+            if ret.len() >= size {
+                break;
+            }
+            ret.extend_from_slice(&read_cluster(&v, c)?);
+        }
+    }
+    if ret.len() < size {
+        return Err(vfs::Error::ReadError);
+    }
+    ret.truncate(size);
+    Ok(ret)
+}
+
+/// Up to `len` bytes from `offset`, following the chain only as far as it
+/// has to.
+pub fn read_node_at(parent_id: u64, name: String, offset: u64, len: usize, dev_id: usize) -> Result<Vec<u8>, vfs::Error> {
+    let v = volume(dev_id)?;
+    let (_, r) = lookup(&v, parent_id, &name)?;
+    if r.is_dir() {
+        return Err(vfs::Error::IllegalOperation);
+    }
+
+    let size = r.size() as u64;
+    if offset >= size || len == 0 {
+        return Ok(Vec::new());
+    }
+    let end = offset.saturating_add(len as u64).min(size);
+    let cs = v.cluster_size;
+    let (first, last) = (offset / cs, (end - 1) / cs);
+
+    let mut ret: Vec<u8> = Vec::with_capacity((end - offset) as usize);
+    let mut c = r.cluster();
+    for i in 0..=last {
+        if !valid_cluster(&v, c) {
+            return Err(vfs::Error::ReadError);
+        }
+        if i >= first {
+            let data = read_cluster(&v, c)?;
+            let from = if i == first { (offset % cs) as usize } else { 0 };
+            let to = (end - i * cs).min(cs) as usize;
+            ret.extend_from_slice(&data[from..to]);
+        }
+        if i < last {
+            c = get_fat(&v, c)?;
+        }
+    }
+    Ok(ret)
+}
+
+pub fn write_node(parent_id: u64, name: String, buf: Vec<u8>, dev_id: usize) -> Result<(), vfs::Error> {
+    let v = volume(dev_id)?;
+    let (dir, r) = lookup(&v, parent_id, &name)?;
+    if r.is_dir() {
+        return Err(vfs::Error::IllegalOperation);
+    }
+    if buf.len() > u32::MAX as usize {
+        return Err(vfs::Error::NoSpace);
+    }
+
+    // The new data goes to new clusters, so running out of space leaves the
+    // old data as it was.
+    let new = alloc_clusters(&v, clusters_for(&v, buf.len()))?;
+    for (c, data) in new.iter().zip(buf.chunks(v.cluster_size as usize)) {
+        write_cluster(&v, *c, data)?;
+    }
+
+    let mut e = r.entry;
+    set_cluster(&mut e, new.first().cloned().unwrap_or(0));
+    e[28..32].copy_from_slice(&(buf.len() as u32).to_le_bytes());
+    set_modified(&mut e);
+    write_entries(&v, dir, r.index, &[e])?;
+
+    if r.cluster() != 0 {
+        free_chain(&v, r.cluster())?;
+    }
+    Ok(())
+}
+
+pub fn append_node(parent_id: u64, name: String, buf: Vec<u8>, dev_id: usize) -> Result<(), vfs::Error> {
+    let v = volume(dev_id)?;
+    let (dir, r) = lookup(&v, parent_id, &name)?;
+    if r.is_dir() {
+        return Err(vfs::Error::IllegalOperation);
+    }
+    let size = r.size() as usize;
+    if size + buf.len() > u32::MAX as usize {
+        return Err(vfs::Error::NoSpace);
+    }
+
+    let cs = v.cluster_size as usize;
+    let mut clusters = if r.cluster() != 0 { chain(&v, r.cluster())? } else { Vec::new() };
+    if clusters.len() < clusters_for(&v, size) {
+        return Err(vfs::Error::ReadError);
+    }
+    clusters.truncate(clusters_for(&v, size));
+
+    // Fill up the last cluster, then add new ones.
+    let mut done = 0;
+    if size % cs != 0 {
+        let last = *clusters.last().ok_or(vfs::Error::ReadError)?;
+        let mut data = read_cluster(&v, last)?;
+        done = buf.len().min(cs - size % cs);
+        data[size % cs..size % cs + done].copy_from_slice(&buf[..done]);
+        write_cluster(&v, last, &data)?;
+    }
+
+    let new = alloc_clusters(&v, clusters_for(&v, buf.len() - done))?;
+    for (c, data) in new.iter().zip(buf[done..].chunks(cs)) {
+        write_cluster(&v, *c, data)?;
+    }
+
+    let mut e = r.entry;
+    if let Some(&first) = new.first() {
+        match clusters.last() {
+            Some(&last) => set_fat(&v, last, first)?,
+            None => set_cluster(&mut e, first),
+        }
+    }
+    e[28..32].copy_from_slice(&((size + buf.len()) as u32).to_le_bytes());
+    set_modified(&mut e);
+    write_entries(&v, dir, r.index, &[e])
+}
+
+/// Creates a file or directory. FAT has no owners; everything on it belongs
+/// to the system.
+pub fn create_node(parent_id: u64, name: String, attributes: u8, _owner: u8, dev_id: usize) -> Result<vfs::FsNode, vfs::Error> {
+    let v = volume(dev_id)?;
+    check_name(&name)?;
+    let dir = dir_cluster(&v, parent_id)?;
+    let records = read_dir(&v, dir)?;
+    if records.iter().any(|r| r.matches(&name)) {
+        return Err(vfs::Error::AlreadyExists);
+    }
+
+    let is_dir = attributes.get_bit(vfs::ATTR_DIR);
+    let mut e = [0u8; ENTRY];
+    e[11] = fat_attributes(attributes) | if is_dir { 0 } else { FAT_ARCHIVE };
+    set_created(&mut e);
+    set_modified(&mut e);
+
+    let mut cluster = 0;
+    if is_dir {
+        cluster = alloc_clusters(&v, 1)?[0];
+        if let Err(err) = init_dir(&v, cluster, dir, &e) {
+            let _ = free_chain(&v, cluster);
+            return Err(err);
+        }
+        set_cluster(&mut e, cluster);
+    }
+
+    let r = match add_entry(&v, dir, &name, e) {
+        Ok(r) => r,
+        Err(err) => {
+            if cluster != 0 {
+                let _ = free_chain(&v, cluster);
+            }
+            return Err(err);
+        },
+    };
+    Ok(node_from_record(&r, parent_id, dev_id))
+}
+
+/// Deletes a file or an empty directory.
+pub fn delete_node(parent_id: u64, name: String, dev_id: usize) -> Result<(), vfs::Error> {
+    let v = volume(dev_id)?;
+    let (dir, r) = lookup(&v, parent_id, &name)?;
+    if r.is_dir() {
+        if !valid_cluster(&v, r.cluster()) {
+            return Err(vfs::Error::ReadError);
+        }
+        if !read_dir(&v, r.cluster())?.is_empty() {
+            return Err(vfs::Error::NotEmpty);
+        }
+    }
+
+    remove_entry(&v, dir, &r)?;
+    if r.cluster() != 0 {
+        free_chain(&v, r.cluster())?;
+    }
+    Ok(())
+}
+
+pub fn rename_node(parent_id: u64, name: String, new_name: String, dev_id: usize) -> Result<(), vfs::Error> {
+    let v = volume(dev_id)?;
+    check_name(&new_name)?;
+    let (dir, r) = lookup(&v, parent_id, &name)?;
+    if read_dir(&v, dir)?.iter().any(|o| o.index != r.index && o.matches(&new_name)) {
+        return Err(vfs::Error::AlreadyExists);
+    }
+
+    // The new name may need more entries, so it is added before the old one
+    // goes; nothing moves the entries of the old name meanwhile.
+    add_entry(&v, dir, &new_name, r.entry)?;
+    remove_entry(&v, dir, &r)
+}
+
+pub fn set_attributes(parent_id: u64, name: String, attributes: u8, dev_id: usize) -> Result<(), vfs::Error> {
+    let v = volume(dev_id)?;
+    let (dir, r) = lookup(&v, parent_id, &name)?;
+    let mut e = r.entry;
+    e[11] = fat_attributes(attributes) | (e[11] & (FAT_DIR | FAT_ARCHIVE));
+    write_entries(&v, dir, r.index, &[e])
+}
+
+//FAT specific functions
+
+fn parse_boot(dev: usize) -> Option<Volume> {
+    let d = block::get(dev)?;
+    if d.sector_size as u64 != SECTOR {
+        return None;
+    }
+    let b = cache::read(dev, 0).ok()?;
+    if b[510..512] != partition::MBR_SIG || (b[0] != 0xEB && b[0] != 0xE9) {
+        return None;
+    }
+
+    let bytes_per_sector = u16_at(&b, 11) as u64;
+    let sectors_per_cluster = b[13] as u64;
+    let reserved = u16_at(&b, 14) as u64;
+    let fats = b[16] as u64;
+    let root_entries = u16_at(&b, 17) as u64;
+    let total = if u16_at(&b, 19) != 0 { u16_at(&b, 19) as u64 } else { u32_at(&b, 32) as u64 };
+    let fat_sectors = if u16_at(&b, 22) != 0 { u16_at(&b, 22) as u64 } else { u32_at(&b, 36) as u64 };
+    if bytes_per_sector != SECTOR || sectors_per_cluster == 0 || !sectors_per_cluster.is_power_of_two()
+        || reserved == 0 || fats == 0 || fat_sectors == 0 || total > d.sectors {
+        return None;
+    }
+
+    let root_sectors = (root_entries * ENTRY as u64 + SECTOR - 1) / SECTOR;
+    let data_start = reserved + fats * fat_sectors + root_sectors;
+    if data_start >= total {
+        return None;
+    }
+    let clusters = (total - data_start) / sectors_per_cluster;
+
+    // The type follows from the number of clusters alone.
+    let fat_type = if clusters < 4085 {
+        FatType::Fat12
+    } else if clusters < 65525 {
+        FatType::Fat16
+    } else {
+        FatType::Fat32
+    };
+    if (fat_type == FatType::Fat32) != (root_entries == 0) {
+        return None;
+    }
+    if fat_sectors * SECTOR * 8 / (fat_type.bits() as u64) < clusters + 2 {
+        return None;
+    }
+
+    let (root_cluster, ext) = match fat_type {
+        FatType::Fat32 => (u32_at(&b, 44), 64),
+        _ => (0, 36),
+    };
+    if fat_type == FatType::Fat32 && (root_cluster < 2 || root_cluster as u64 >= clusters + 2) {
+        return None;
+    }
+    let label = if b[ext + 2] == 0x29 {
+        String::from_utf8_lossy(&b[ext + 7..ext + 18]).trim_end().to_string()
+    } else {
+        String::from("NO NAME")
+    };
+
+    let mut fsinfo = 0;
+    if fat_type == FatType::Fat32 {
+        let s = u16_at(&b, 48) as u64;
+        if s != 0 && s < reserved {
+            if let Ok(i) = cache::read(dev, s) {
+                if u32_at(&i, 0) == FSINFO_SIG1 && u32_at(&i, 484) == FSINFO_SIG2 {
+                    fsinfo = s;
+                }
+            }
+        }
+    }
+
+    Some(Volume {
+        name: String::new(),
+        dev: dev,
+        fat_type: fat_type,
+        label: label,
+        clusters: clusters as u32,
+        cluster_size: sectors_per_cluster * SECTOR,
+        sectors_per_cluster: sectors_per_cluster,
+        fat_start: reserved,
+        fat_sectors: fat_sectors,
+        fats: fats,
+        root_start: reserved + fats * fat_sectors,
+        root_sectors: root_sectors,
+        root_cluster: root_cluster,
+        data_start: data_start,
+        fsinfo: fsinfo,
+        next_free: 2,
+    })
+}
+
+fn volume(dev_id: usize) -> Result<Volume, vfs::Error> {
+    VOLUMES.lock().get(&dev_id).cloned().ok_or(vfs::Error::DeviceNotFound)
+}
+
+fn read_sector(v: &Volume, lba: u64) -> Result<Vec<u8>, vfs::Error> {
+    cache::read(v.dev, lba).map_err(|e| vfs::Error::io(v.dev, e))
+}
+
+fn write_sector(v: &Volume, lba: u64, buf: &[u8]) -> Result<(), vfs::Error> {
+    cache::write(v.dev, lba, buf).map_err(|e| vfs::Error::io(v.dev, e))
+}
+
+// The FAT
+
+fn cluster_lba(v: &Volume, c: u32) -> u64 {
+    v.data_start + (c as u64 - 2) * v.sectors_per_cluster
+}
+
+fn valid_cluster(v: &Volume, c: u32) -> bool {
+    c >= 2 && c < v.clusters + 2
+}
+
+// Offset of entry `n` in a FAT and the number of bytes holding it.
+fn fat_offset(v: &Volume, n: u32) -> (u64, usize) {
+    match v.fat_type {
+        FatType::Fat12 => (n as u64 * 3 / 2, 2),
+        FatType::Fat16 => (n as u64 * 2, 2),
+        FatType::Fat32 => (n as u64 * 4, 4),
+    }
+}
+
+fn get_fat(v: &Volume, n: u32) -> Result<u32, vfs::Error> {
+    let (off, len) = fat_offset(v, n);
+    let mut bytes: Vec<u8> = Vec::with_capacity(len);
+    while bytes.len() < len {
+        let o = off + bytes.len() as u64;
+        let sec = read_sector(v, v.fat_start + o / SECTOR)?;
+        let start = (o % SECTOR) as usize;
+        let n = (len - bytes.len()).min(SECTOR as usize - start);
+        bytes.extend_from_slice(&sec[start..start + n]);
+    }
+
+    Ok(match v.fat_type {
+        FatType::Fat12 => {
+            let x = u16_at(&bytes, 0) as u32;
+            if n & 1 == 1 { x >> 4 } else { x & 0xFFF }
+        },
+        FatType::Fat16 => u16_at(&bytes, 0) as u32,
+        FatType::Fat32 => u32_at(&bytes, 0) & 0x0FFFFFFF,
+    })
+}
+
+// Sets entry `n` in every copy of the FAT.
+fn set_fat(v: &Volume, n: u32, value: u32) -> Result<(), vfs::Error> {
+    let (off, len) = fat_offset(v, n);
+
+    for copy in 0..v.fats {
+        let base = v.fat_start + copy * v.fat_sectors;
+        let mut i = 0;
+        while i < len {
+            let o = off + i as u64;
+            let lba = base + o / SECTOR;
+            let mut sec = read_sector(v, lba)?;
+            let mut pos = (o % SECTOR) as usize;
+            while i < len && pos < SECTOR as usize {
+                sec[pos] = fat_byte(v, n, value, sec[pos], i);
+                i += 1;
+                pos += 1;
+            }
+            write_sector(v, lba, &sec)?;
+        }
+    }
+    Ok(())
+}
+
+// Byte `i` of FAT entry `n` holding `value`, `old` being what is stored
+// there. FAT12 entries share a byte, FAT32 keeps the top four bits.
+fn fat_byte(v: &Volume, n: u32, value: u32, old: u8, i: usize) -> u8 {
+    match v.fat_type {
+        FatType::Fat12 => {
+            let x = if n & 1 == 1 { (value << 4) as u16 } else { value as u16 & 0xFFF };
+            let keep: u16 = if n & 1 == 1 { 0x000F } else { 0xF000 };
+            let b = x.to_le_bytes()[i];
+            (old & keep.to_le_bytes()[i]) | b
+        },
+        FatType::Fat16 => (value as u16).to_le_bytes()[i],
+        FatType::Fat32 => {
+            let b = (value & 0x0FFFFFFF).to_le_bytes()[i];
+            if i == 3 { (old & 0xF0) | b } else { b }
+        },
+    }
+}
+
+fn is_end(v: &Volume, c: u32) -> bool {
+    c >= v.fat_type.end_of_chain() - 7
+}
+
+// The clusters of a chain in order. A chain running into a free or bad
+// cluster, or into itself, is damaged.
+fn chain(v: &Volume, first: u32) -> Result<Vec<u32>, vfs::Error> {
+    let mut ret: Vec<u32> = Vec::new();
+    let mut c = first;
+    loop {
+        if !valid_cluster(v, c) || ret.len() > v.clusters as usize {
+            return Err(vfs::Error::ReadError);
+        }
+        ret.push(c);
+        c = get_fat(v, c)?;
+        if is_end(v, c) {
+            return Ok(ret);
+        }
+    }
+}
+
+fn clusters_for(v: &Volume, bytes: usize) -> usize {
+    (bytes + v.cluster_size as usize - 1) / v.cluster_size as usize
+}
+
+// Allocates a chain of `n` free clusters, or none at all.
+fn alloc_clusters(v: &Volume, n: usize) -> Result<Vec<u32>, vfs::Error> {
+    if n == 0 {
+        return Ok(Vec::new());
+    }
+
+    let hint = VOLUMES.lock().values().find(|o| o.dev == v.dev).map(|o| o.next_free).unwrap_or(2);
+    let start = if valid_cluster(v, hint) { hint } else { 2 };
+    let mut free: Vec<u32> = Vec::with_capacity(n);
+    for i in 0..v.clusters {
+        let c = 2 + (start - 2 + i) % v.clusters;
+        if get_fat(v, c)? == 0 {
+            free.push(c);
+            if free.len() == n {
+                break;
+            }
+        }
+    }
+    if free.len() < n {
+        return Err(vfs::Error::NoSpace);
+    }
+
+    for (i, &c) in free.iter().enumerate() {
+        let next = if i + 1 < n { free[i + 1] } else { v.fat_type.end_of_chain() };
+        set_fat(v, c, next)?;
+    }
+
+    let last = free[n - 1];
+    if let Some(o) = VOLUMES.lock().values_mut().find(|o| o.dev == v.dev) {
+        o.next_free = last + 1;
+    }
+    update_fsinfo(v, -(n as i64), last)?;
+    Ok(free)
+}
+
+fn free_chain(v: &Volume, first: u32) -> Result<(), vfs::Error> {
+    let clusters = chain(v, first)?;
+    for &c in clusters.iter() {
+        set_fat(v, c, 0)?;
+    }
+    update_fsinfo(v, clusters.len() as i64, FSINFO_UNKNOWN)
+}
+
+// Keeps the FAT32 free cluster count, where it is known, and the hint for
+// the next free cluster up to date.
+fn update_fsinfo(v: &Volume, change: i64, next: u32) -> Result<(), vfs::Error> {
+    if v.fsinfo == 0 {
+        return Ok(());
+    }
+
+    let mut sec = read_sector(v, v.fsinfo)?;
+    let free = u32_at(&sec, 488);
+    if free != FSINFO_UNKNOWN {
+        let free = (free as i64 + change).max(0).min(v.clusters as i64) as u32;
+        sec[488..492].copy_from_slice(&free.to_le_bytes());
+    }
+    if next != FSINFO_UNKNOWN {
+        sec[492..496].copy_from_slice(&next.to_le_bytes());
+    }
+    write_sector(v, v.fsinfo, &sec)
+}
+
+fn read_cluster(v: &Volume, c: u32) -> Result<Vec<u8>, vfs::Error> {
+    cache::read_many(v.dev, cluster_lba(v, c), v.sectors_per_cluster).map_err(|e| vfs::Error::io(v.dev, e))
+}
+
+// Writes `data` to cluster `c`, padded with zeros.
+fn write_cluster(v: &Volume, c: u32, data: &[u8]) -> Result<(), vfs::Error> {
+    let lba = cluster_lba(v, c);
+    for i in 0..v.sectors_per_cluster {
+        let mut sec = [0u8; SECTOR as usize];
+        let start = (i * SECTOR) as usize;
+        if start < data.len() {
+            let end = data.len().min(start + SECTOR as usize);
+            sec[..end - start].copy_from_slice(&data[start..end]);
+        }
+        write_sector(v, lba + i, &sec)?;
+    }
+    Ok(())
+}
+
+// Directories. A directory is its first cluster, 0 being the root; on FAT12
+// and FAT16 the root is a fixed area before the data instead.
+
+fn dir_id(c: u32) -> u64 {
+    if c == 0 { ROOT_ID } else { c as u64 }
+}
+
+fn dir_cluster(v: &Volume, id: u64) -> Result<u32, vfs::Error> {
+    if id == ROOT_ID {
+        return Ok(0);
+    }
+    if id & FILE_TAG != 0 {
+        return Err(vfs::Error::ParentNotDirectory);
+    }
+    if !valid_cluster(v, id as u32) {
+        return Err(vfs::Error::FileNotFound);
+    }
+    Ok(id as u32)
+}
+
+fn dir_sectors(v: &Volume, dir: u32) -> Result<Vec<u64>, vfs::Error> {
+    if dir == 0 && v.fat_type != FatType::Fat32 {
+        return Ok((v.root_start..v.root_start + v.root_sectors).collect());
+    }
+
+    let first = if dir == 0 { v.root_cluster } else { dir };
+    let mut ret: Vec<u64> = Vec::new();
+    for c in chain(v, first)? {
+        let lba = cluster_lba(v, c);
+        ret.extend(lba..lba + v.sectors_per_cluster);
+    }
+    Ok(ret)
+}
+
+fn read_dir_raw(v: &Volume, dir: u32) -> Result<Vec<u8>, vfs::Error> {
+    let mut ret: Vec<u8> = Vec::new();
+    for lba in dir_sectors(v, dir)? {
+        ret.extend_from_slice(&read_sector(v, lba)?);
+    }
+    Ok(ret)
+}
+
+// Every name in the directory except "." and "..". Long names whose
+// entries are out of order or don't match the short entry are ignored.
+fn read_dir(v: &Volume, dir: u32) -> Result<Vec<Record>, vfs::Error> {
+    let raw = read_dir_raw(v, dir)?;
+
+    let mut ret: Vec<Record> = Vec::new();
+    // Long name so far, its checksum, the slot expected next and the first slot.
+    let mut lfn: Option<(Vec<u16>, u8, u8, usize)> = None;
+    for (i, e) in raw.chunks(ENTRY).enumerate() {
+        if e[0] == ENTRY_END {
+            break;
+        }
+        if e[0] == ENTRY_FREE {
+            lfn = None;
+            continue;
+        }
+
+        if e[11] & 0x3F == FAT_LFN {
+            let seq = e[0] & 0x1F;
+            if e[0] & LFN_LAST != 0 && seq >= 1 && seq as usize <= LFN_MAX_SLOTS {
+                lfn = Some((alloc::vec![0xFFFF; seq as usize * 13], e[13], seq, i));
+            }
+            lfn = match lfn.take() {
+                Some((mut name, sum, next, start)) if next == seq && sum == e[13] && seq >= 1 => {
+                    for (k, &off) in LFN_CHARS.iter().enumerate() {
+                        name[(seq as usize - 1) * 13 + k] = u16_at(e, off);
+                    }
+                    Some((name, sum, next - 1, start))
+                },
+                _ => None,
+            };
+            continue;
+        }
+        if e[11] & FAT_VOLUME != 0 {
+            lfn = None;
+            continue;
+        }
+
+        let entry: [u8; ENTRY] = e.try_into().unwrap();
+        if entry[0..11] == DOT || entry[0..11] == DOTDOT {
+            lfn = None;
+            continue;
+        }
+
+        let (name, slots) = match lfn.take() {
+            Some((name, sum, 0, start)) if sum == lfn_checksum(&entry[0..11]) => {
+                let end = name.iter().position(|&c| c == 0 || c == 0xFFFF).unwrap_or(name.len());
+                let s: String = core::char::decode_utf16(name[..end].iter().cloned())
+                    .map(|c| c.unwrap_or(core::char::REPLACEMENT_CHARACTER)).collect();
+                (s, i - start)
+            },
+            _ => (short_string(&entry), 0),
+        };
+        ret.push(Record { name: name, index: i, slots: slots, entry: entry });
+    }
+
+    Ok(ret)
+}
+
+fn lookup(v: &Volume, parent_id: u64, name: &str) -> Result<(u32, Record), vfs::Error> {
+    let dir = dir_cluster(v, parent_id)?;
+    let r = read_dir(v, dir)?.into_iter().find(|r| r.matches(name)).ok_or(vfs::Error::FileNotFound)?;
+    Ok((dir, r))
+}
+
+// The parent of a directory, from its ".." entry.
+fn parent_of(v: &Volume, c: u32) -> Result<u32, vfs::Error> {
+    if !valid_cluster(v, c) {
+        return Err(vfs::Error::FileNotFound);
+    }
+    let sec = read_sector(v, cluster_lba(v, c))?;
+    if sec[ENTRY..ENTRY + 11] != DOTDOT {
+        return Err(vfs::Error::ReadError);
+    }
+    let p = (u16_at(&sec, ENTRY + 20) as u32) << 16 | u16_at(&sec, ENTRY + 26) as u32;
+    Ok(if p == v.root_cluster { 0 } else { p })
+}
+
+fn write_entries(v: &Volume, dir: u32, index: usize, entries: &[[u8; ENTRY]]) -> Result<(), vfs::Error> {
+    let sectors = dir_sectors(v, dir)?;
+    let per_sector = SECTOR as usize / ENTRY;
+
+    for (k, e) in entries.iter().enumerate() {
+        let i = index + k;
+        let lba = *sectors.get(i / per_sector).ok_or(vfs::Error::ReadError)?;
+        let mut sec = read_sector(v, lba)?;
+        let off = (i % per_sector) * ENTRY;
+        sec[off..off + ENTRY].copy_from_slice(e);
+        write_sector(v, lba, &sec)?;
+    }
+    Ok(())
+}
+
+// Index of the first of `n` free entries in a row, growing the directory
+// by a cluster if there aren't any.
+fn free_entries(v: &Volume, dir: u32, n: usize) -> Result<usize, vfs::Error> {
+    loop {
+        let raw = read_dir_raw(v, dir)?;
+        let mut run = 0;
+        let mut end = false;
+        for (i, e) in raw.chunks(ENTRY).enumerate() {
+            end = end || e[0] == ENTRY_END;
+            if end || e[0] == ENTRY_FREE {
+                run += 1;
+                if run == n {
+                    return Ok(i + 1 - n);
+                }
+            } else {
+                run = 0;
+            }
+        }
+
+        let count = raw.len() / ENTRY;
+        if (dir == 0 && v.fat_type != FatType::Fat32) || count + v.cluster_size as usize / ENTRY > MAX_ENTRIES {
+            return Err(vfs::Error::NoSpace);
+        }
+        let first = if dir == 0 { v.root_cluster } else { dir };
+        let last = *chain(v, first)?.last().unwrap();
+        let c = alloc_clusters(v, 1)?[0];
+        write_cluster(v, c, &[])?;
+        set_fat(v, last, c)?;
+    }
+}
+
+// Adds `name` for the short entry `entry` to the directory, with a long name
+// where the name isn't a plain 8.3 one.
+fn add_entry(v: &Volume, dir: u32, name: &str, entry: [u8; ENTRY]) -> Result<Record, vfs::Error> {
+    let records = read_dir(v, dir)?;
+    let (short, case, long) = short_name(name, &records);
+
+    let mut e = entry;
+    e[0..11].copy_from_slice(&short);
+    e[12] = case;
+
+    let mut entries = if long { lfn_entries(name, lfn_checksum(&short)) } else { Vec::new() };
+    let slots = entries.len();
+    entries.push(e);
+
+    let start = free_entries(v, dir, entries.len())?;
+    write_entries(v, dir, start, &entries)?;
+    Ok(Record { name: name.to_string(), index: start + slots, slots: slots, entry: e })
+}
+
+fn remove_entry(v: &Volume, dir: u32, r: &Record) -> Result<(), vfs::Error> {
+    let raw = read_dir_raw(v, dir)?;
+    let mut entries: Vec<[u8; ENTRY]> = Vec::new();
+    for i in r.index - r.slots..=r.index {
+        let mut e: [u8; ENTRY] = raw[i * ENTRY..(i + 1) * ENTRY].try_into().unwrap();
+        e[0] = ENTRY_FREE;
+        entries.push(e);
+    }
+    write_entries(v, dir, r.index - r.slots, &entries)
+}
+
+// Writes "." and ".." to the new directory `c` inside `parent`.
+fn init_dir(v: &Volume, c: u32, parent: u32, entry: &[u8; ENTRY]) -> Result<(), vfs::Error> {
+    let mut data = [0u8; 2 * ENTRY];
+    for (i, (name, cluster)) in [(DOT, c), (DOTDOT, parent)].iter().enumerate() {
+        let mut e = *entry;
+        e[0..11].copy_from_slice(name);
+        e[11] = FAT_DIR;
+        set_cluster(&mut e, *cluster);
+        data[i * ENTRY..(i + 1) * ENTRY].copy_from_slice(&e);
+    }
+    write_cluster(v, c, &data)
+}
+
+// Names
+
+// FAT names can't hold these, nor end in a space or dot.
+fn check_name(name: &str) -> Result<(), vfs::Error> {
+    vfs::check_name(name)?;
+    if name.chars().any(|c| (c as u32) < 0x20 || "\"*/:<>?\\|".contains(c)) || name.ends_with(' ') || name.ends_with('.') {
+        return Err(vfs::Error::InvalidName);
+    }
+    if name.encode_utf16().count() > LFN_MAX_SLOTS * 13 - 5 {
+        return Err(vfs::Error::NameTooLong);
+    }
+    Ok(())
+}
+
+fn short_char(c: char) -> Option<u8> {
+    if c.is_ascii_alphanumeric() || "$%'-_@~`!(){}^#&".contains(c) {
+        Some(c.to_ascii_uppercase() as u8)
+    } else {
+        None
+    }
+}
+
+// Case flag for a part of a name stored as an 8.3 name: all upper case,
+// all lower case (`flag`), or None if mixed.
+fn case_of(part: &str, flag: u8) -> Option<u8> {
+    if !part.chars().any(|c| c.is_ascii_lowercase()) {
+        Some(0)
+    } else if !part.chars().any(|c| c.is_ascii_uppercase()) {
+        Some(flag)
+    } else {
+        None
+    }
+}
+
+// The short name for `name` in a directory holding `records`, its case flags
+// and whether a long name is needed as well. Names that are 8.3 already are
+// kept, others get a numbered tail: "Long File.text" becomes LONGFI~1.TEX.
+fn short_name(name: &str, records: &[Record]) -> ([u8; 11], u8, bool) {
+    let mut short = [b' '; 11];
+
+    let (base, ext) = match name.rfind('.') {
+        Some(i) => (&name[..i], &name[i + 1..]),
+        None => (name, ""),
+    };
+    if !base.is_empty() && base.len() <= 8 && ext.len() <= 3 && !base.contains('.')
+        && base.chars().chain(ext.chars()).all(|c| short_char(c).is_some()) {
+        if let (Some(b), Some(e)) = (case_of(base, CASE_BASE), case_of(ext, CASE_EXT)) {
+            for (i, c) in base.chars().chain(core::iter::repeat(' ').take(8 - base.len())).chain(ext.chars()).enumerate() {
+                short[i] = short_char(c).unwrap_or(b' ');
+            }
+            return (short, b | e, false);
+        }
+    }
+
+    let trimmed = name.trim_start_matches('.');
+    let (base, ext) = match trimmed.rfind('.') {
+        Some(i) => (&trimmed[..i], &trimmed[i + 1..]),
+        None => (trimmed, ""),
+    };
+    let base: Vec<u8> = base.chars().filter(|&c| c != ' ' && c != '.').map(|c| short_char(c).unwrap_or(b'_')).take(8).collect();
+    let ext: Vec<u8> = ext.chars().filter(|&c| c != ' ').map(|c| short_char(c).unwrap_or(b'_')).take(3).collect();
+    short[8..8 + ext.len()].copy_from_slice(&ext);
+
+    for n in 1..1_000_000 {
+        let tail = format!("~{}", n);
+        let keep = base.len().min(8 - tail.len());
+        let mut s = short;
+        s[..8].copy_from_slice(b"        ");
+        s[..keep].copy_from_slice(&base[..keep]);
+        s[keep..keep + tail.len()].copy_from_slice(tail.as_bytes());
+        if !records.iter().any(|r| r.entry[0..11] == s) {
+            return (s, 0, true);
+        }
+    }
+    (short, 0, true)
+}
+
+fn short_string(e: &[u8]) -> String {
+    let part = |bytes: &[u8], lower: bool| -> String {
+        bytes.iter().map(|&b| {
+            let c = b as char;
+            if lower { c.to_ascii_lowercase() } else { c }
+        }).collect::<String>().trim_end().to_string()
+    };
+
+    let mut first = e[0..8].to_vec();
+    if first[0] == ENTRY_KANJI {
+        first[0] = ENTRY_FREE;
+    }
+    let base = part(&first, e[12] & CASE_BASE != 0);
+    let ext = part(&e[8..11], e[12] & CASE_EXT != 0);
+    if ext.is_empty() { base } else { base + "." + &ext }
+}
+
+fn lfn_checksum(short: &[u8]) -> u8 {
+    short.iter().fold(0u8, |sum, &b| (sum >> 1 | sum << 7).wrapping_add(b))
+}
+
+// Long name entries as they are stored, last part first.
+fn lfn_entries(name: &str, checksum: u8) -> Vec<[u8; ENTRY]> {
+    let units: Vec<u16> = name.encode_utf16().collect();
+    let n = (units.len() + 12) / 13;
+
+    let mut ret: Vec<[u8; ENTRY]> = Vec::with_capacity(n);
+    for seq in (1..=n).rev() {
+        let mut e = [0u8; ENTRY];
+        e[0] = seq as u8 | if seq == n { LFN_LAST } else { 0 };
+        e[11] = FAT_LFN;
+        e[13] = checksum;
+        for (k, &off) in LFN_CHARS.iter().enumerate() {
+            let pos = (seq - 1) * 13 + k;
+            let u = if pos < units.len() { units[pos] } else if pos == units.len() { 0 } else { 0xFFFF };
+            e[off..off + 2].copy_from_slice(&u.to_le_bytes());
+        }
+        ret.push(e);
+    }
+    ret
+}
+
+// Entries
+
+fn fat_attributes(attributes: u8) -> u8 {
+    let mut a = 0;
+    if attributes.get_bit(vfs::ATTR_RO) { a |= FAT_RO; }
+    if attributes.get_bit(vfs::ATTR_HDN) { a |= FAT_HIDDEN; }
+    if attributes.get_bit(vfs::ATTR_SYS) { a |= FAT_SYSTEM; }
+    if attributes.get_bit(vfs::ATTR_DIR) { a |= FAT_DIR; }
+    a
+}
+
+fn vfs_attributes(a: u8) -> u8 {
+    *0u8.set_bit(vfs::ATTR_RO, a & FAT_RO != 0)
+        .set_bit(vfs::ATTR_HDN, a & FAT_HIDDEN != 0)
+        .set_bit(vfs::ATTR_SYS, a & FAT_SYSTEM != 0)
+        .set_bit(vfs::ATTR_DIR, a & FAT_DIR != 0)
+}
+
+fn set_cluster(e: &mut [u8; ENTRY], c: u32) {
+    e[20..22].copy_from_slice(&((c >> 16) as u16).to_le_bytes());
+    e[26..28].copy_from_slice(&(c as u16).to_le_bytes());
+}
+
+fn set_created(e: &mut [u8; ENTRY]) {
+    let (date, t) = fat_time(time::now());
+    e[14..16].copy_from_slice(&t.to_le_bytes());
+    e[16..18].copy_from_slice(&date.to_le_bytes());
+}
+
+fn set_modified(e: &mut [u8; ENTRY]) {
+    let (date, t) = fat_time(time::now());
+    e[18..20].copy_from_slice(&date.to_le_bytes());
+    e[22..24].copy_from_slice(&t.to_le_bytes());
+    e[24..26].copy_from_slice(&date.to_le_bytes());
+}
+
+// Date and time as FAT stores them, in local time; the RTC is taken as
+// local time as well. Dates before 1980 can't be stored.
+fn fat_time(ts: u64) -> (u16, u16) {
+    let dt = time::from_unix(ts);
+    if dt.year < 1980 || dt.year > 2107 {
+        return (0x21, 0);
+    }
+    let date = ((dt.year - 1980) << 9 | dt.month << 5 | dt.day) as u16;
+    let t = (dt.hour << 11 | dt.minute << 5 | dt.second / 2) as u16;
+    (date, t)
+}
+
+fn unix_time(date: u16, t: u16) -> u64 {
+    if date == 0 {
+        return 0;
+    }
+    time::to_unix(time::DateTime {
+        year: 1980 + (date >> 9) as u64,
+        month: (date >> 5 & 0xF) as u64,
+        day: (date & 0x1F) as u64,
+        hour: (t >> 11) as u64,
+        minute: (t >> 5 & 0x3F) as u64,
+        second: (t & 0x1F) as u64 * 2,
+    })
+}
+
+fn node_from_record(r: &Record, parent_id: u64, dev_id: usize) -> vfs::FsNode {
+    vfs::FsNode {
+        name: r.name.clone(),
+        device: dev_id,
+        parent_id: parent_id,
+        id: if r.is_dir() { dir_id(r.cluster()) } else { FILE_TAG | parent_id << 16 | r.index as u64 },
+        attributes: vfs_attributes(r.entry[11]),
+        t_creation: unix_time(u16_at(&r.entry, 16), u16_at(&r.entry, 14)),
+        t_edit: unix_time(u16_at(&r.entry, 24), u16_at(&r.entry, 22)),
+        owner: 0,
+        size: r.size() as u64,
+        open: false,
+    }
+}
+
+fn u16_at(b: &[u8], off: usize) -> u16 {
+    u16::from_le_bytes(b[off..off + 2].try_into().unwrap())
+}
+
+fn u32_at(b: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes(b[off..off + 4].try_into().unwrap())
+}
@@ -1,79 +1,116 @@
-use std::fs::{metadata, File};
-use std::io::prelude::*;
+// Builds the initrd: a ustar archive of a directory, e.g.
+//   rustc geninitrd.rs && ./geninitrd initrd.tar root
+// The contents of the directory become the root of I:.
+
 use std::env;
-use std::mem;
-use std::vec::Vec;
-use std::string::String;
-use std::str;
-
-#[repr(C)]
-#[derive(Debug, Copy, Clone)]
-struct FileHeader {
-    name: [char; 30],
-    size: u32,
-    offset: u32,
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::io::{self, Error, ErrorKind};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::process;
+
+const BLOCK: usize = 512;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 3 {
+        eprintln!("usage: geninitrd <archive> <directory>");
+        process::exit(1);
+    }
+
+    if let Err(e) = gen_img(&args[1], Path::new(&args[2])) {
+        eprintln!("geninitrd: {}", e);
+        process::exit(1);
+    }
 }
 
-fn main() -> std::io::Result<()> {
-    gen_img()?;
+fn gen_img(out: &str, root: &Path) -> io::Result<()> {
+    let mut img: Vec<u8> = Vec::new();
+    add_dir(&mut img, root, "")?;
+
+    // The end of the archive is two zero blocks.
+    img.extend_from_slice(&[0; BLOCK * 2]);
+    File::create(out)?.write_all(&img)?;
+    println!("{}: {} bytes", out, img.len());
     Ok(())
 }
 
-unsafe fn as_u8_slice<T: Sized>(p: &T) -> &[u8] {
-    ::std::slice::from_raw_parts(
-        (p as *const T) as *const u8,
-        mem::size_of::<T>(),
-    )
-}
+// Adds everything in `dir`, sorted so the archive doesn't depend on the
+// order the host lists directories in.
+fn add_dir(img: &mut Vec<u8>, dir: &Path, prefix: &str) -> io::Result<()> {
+    let mut entries: Vec<_> = fs::read_dir(dir)?.collect::<io::Result<_>>()?;
+    entries.sort_by_key(|e| e.file_name());
 
-fn gen_img() -> std::io::Result<()> {
-    
-    let mut args: Vec<String> = env::args().collect();
-    let _t = args.remove(0);
-    let nheaders =  args.len();
-    let mut off = mem::size_of::<FileHeader>() * nheaders + mem::size_of::<u8>();
-
-    let mut headers = vec![FileHeader {
-        name: ['\0'; 30],
-        size: 0,
-        offset: 0,
-        }; nheaders];
-
-    for i in 0..nheaders {
-        let chars: Vec<char> = args[i].chars().collect();
-
-        print!("filename: ");
-        for j in 0..args[i].chars().count() {
-            headers[i].name[j] = chars[j];
-            print!("{}", chars[j]);
+    for e in entries {
+        let name = e.file_name().into_string()
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "file names must be UTF-8"))?;
+        let path = format!("{}{}", prefix, name);
+        let meta = fs::symlink_metadata(e.path())?;
+
+        if meta.file_type().is_symlink() {
+            let target = fs::read_link(e.path())?;
+            let target = target.to_str()
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData, "link targets must be UTF-8"))?;
+            img.extend_from_slice(&header(&path, b'2', 0, meta.mtime(), meta.mode(), target)?);
+            println!("link  {} -> {}", path, target);
+        } else if meta.is_dir() {
+            img.extend_from_slice(&header(&(path.clone() + "/"), b'5', 0, meta.mtime(), meta.mode(), "")?);
+            println!("dir   {}", path);
+            add_dir(img, &e.path(), &(path + "/"))?;
+        } else {
+            let data = fs::read(e.path())?;
+            img.extend_from_slice(&header(&path, b'0', data.len() as u64, meta.mtime(), meta.mode(), "")?);
+            img.extend_from_slice(&data);
+            let pad = (BLOCK - data.len() % BLOCK) % BLOCK;
+            img.extend(std::iter::repeat(0).take(pad));
+            println!("file  {} ({} bytes)", path, data.len());
         }
-        headers[i].name[29] = '\0';
+    }
 
-        headers[i].offset = off as u32;
-        print!("    offset: {}", headers[i].offset);
+    Ok(())
+}
 
-        headers[i].size = metadata(&args[i])?.len() as u32;
-        println!("    size: {}", headers[i].size);
+fn header(path: &str, kind: u8, size: u64, mtime: i64, mode: u32, link: &str) -> io::Result<[u8; BLOCK]> {
+    let mut h = [0u8; BLOCK];
 
-        off += headers[i].size as usize;
+    // Paths over 100 bytes are split at a '/' into prefix and name.
+    let (prefix, name) = if path.len() <= 100 {
+        ("", path)
+    } else {
+        let trimmed = path.trim_end_matches('/');
+        match trimmed[..trimmed.len().min(156)].rfind('/') {
+            Some(i) if path.len() - i - 1 <= 100 => (&path[..i], &path[i + 1..]),
+            _ => return Err(Error::new(ErrorKind::InvalidInput, format!("path too long: {}", path))),
+        }
+    };
+    if link.len() > 100 {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("link target too long: {}", link)));
     }
-    
-    let mut initrd = File::create("initrd.img")?;
-    initrd.write(&[nheaders as u8])?;
 
-    for i in 0..nheaders {
-        let mut bytes: &[u8] = unsafe {as_u8_slice(&headers[i])};
-        initrd.write(bytes);
-    }
+    h[..name.len()].copy_from_slice(name.as_bytes());
+    octal(&mut h[100..108], (mode & 0o7777) as u64);
+    octal(&mut h[108..116], 0);
+    octal(&mut h[116..124], 0);
+    octal(&mut h[124..136], size);
+    octal(&mut h[136..148], mtime.max(0) as u64);
+    h[156] = kind;
+    h[157..157 + link.len()].copy_from_slice(link.as_bytes());
+    h[257..263].copy_from_slice(b"ustar\0");
+    h[263..265].copy_from_slice(b"00");
+    h[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
 
-    for i in 0..nheaders {
-        let mut file = File::open(&args[i])?;
-        let mut contents = String::new();
-        file.read_to_string(&mut contents)?;
-        //println!("{}", contents);
-        initrd.write(contents.as_bytes())?;
-    }
+    // The checksum is taken with its own field as spaces.
+    h[148..156].copy_from_slice(b"        ");
+    let sum: u32 = h.iter().map(|&b| b as u32).sum();
+    octal(&mut h[148..155], sum as u64);
 
-    Ok(())
+    Ok(h)
 }
 
+// Zero padded octal digits, then a NUL.
+fn octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}", value, width = field.len() - 1);
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+    field[field.len() - 1] = 0;
+}
@@ -1 +1 @@
-pub const IMG: &[u8] = include_bytes!("gen_initrd/initrd.img");
+pub const IMG: &[u8] = include_bytes!("gen_initrd/initrd.tar");
@@ -1,110 +1,336 @@
+//InitRD: read-only files built into the kernel image, mounted at I:.
+//The image is a ustar archive, see gen_initrd/spec.txt.
+
 use crate::vfs;
+use crate::println;
 use spin::Mutex;
 use lazy_static::lazy_static;
+use bit_field::BitField;
+use alloc::string::{String, ToString};
 use alloc::vec::Vec;
-use core::mem;
-use alloc::string::String;
-use core::ptr;
+use alloc::vec;
 
 pub mod initrd_img;
 
-#[repr(C)]
+/// Device name the initrd is mounted as.
+pub const DEVICE: &str = "I:";
+
+const BLOCK: usize = 512;
+const USTAR_SIG: [u8; 5] = [b'u', b's', b't', b'a', b'r'];
+
+const TYPE_FILE: u8 = b'0';
+const TYPE_OLD_FILE: u8 = 0;
+const TYPE_CONTIGUOUS: u8 = b'7';
+const TYPE_HARD_LINK: u8 = b'1';
+const TYPE_SYMLINK: u8 = b'2';
+const TYPE_DIR: u8 = b'5';
+
+// Offsets into a header.
+const NAME: usize = 0;
+const SIZE: usize = 124;
+const MTIME: usize = 136;
+const CHKSUM: usize = 148;
+const TYPEFLAG: usize = 156;
+const LINKNAME: usize = 157;
+const MAGIC: usize = 257;
+const PREFIX: usize = 345;
+
 pub struct Initrd {
-    pub nfiles: u8,
-    pub file_headers: Vec<FileHeader>,
-    pub root: vfs::FsNode,
-    pub root_nodes: Vec<vfs::FsNode>,
-    pub nroot_nodes: u32,
+    pub mounted: bool,
+    pub dev: usize,
+    entries: Vec<Entry>,
 }
 
-#[repr(C)]
-#[derive(Debug, Copy, Clone)]
-pub struct FileHeader {
-    name: [char; 30],
-    size: u32,
-    offset: u32,
+// Every file, directory and link of the archive; entry n has id n + 1, so
+// the root is 1 and its parent 0, like on wFS. Data is the range of the
+// image holding the file, or the link target of a symbolic link.
+#[derive(Clone)]
+struct Entry {
+    name: String,
+    parent: u64,
+    attributes: u8,
+    time: u64,
+    offset: usize,
+    size: usize,
 }
 
 lazy_static! {
-    pub static ref INITRD: Mutex<Initrd> = Mutex::new(Initrd { 
-        nfiles: 0,
-        file_headers: Vec::new(),
-        root: vfs::FsNode {
-            name: String::from("Init"),
-            system: vfs::System::Initrd,
-            flags: vfs::FS_DIR,
-            inode: 0,
-            length: 0,
-            children: Vec::new(),
-            parent: ptr::null_mut(),
-        },
-        root_nodes: Vec::new(),
-        nroot_nodes: 0,
+    pub static ref INITRD: Mutex<Initrd> = Mutex::new(Initrd {
+        mounted: false,
+        dev: 0,
+        entries: Vec::new(),
     });
 }
 
-pub fn read(node: &vfs::FsNode) -> &'static [u8] {
-    let header = INITRD.lock().file_headers[node.inode as usize];
-    let buf = &initrd_img::IMG[(header.offset as usize)..header.offset as usize + header.size  as usize];
-    buf
+pub fn init() {
+    let entries = match parse(initrd_img::IMG) {
+        Ok(e) => e,
+        Err(e) => {
+            println!("[INITRD] Invalid image ({}). Not mounting.", e);
+            return;
+        },
+    };
+    let n = entries.len() - 1;
+
+    let dev = match vfs::install_device(String::from(DEVICE), vfs::System::Initrd) {
+        Ok(d) => d,
+        Err(e) => {
+            println!("[INITRD] Could not mount {} ({}).", DEVICE, e);
+            return;
+        },
+    };
+
+    let mut initrd = INITRD.lock();
+    initrd.entries = entries;
+    initrd.dev = dev;
+    initrd.mounted = true;
+    println!("[INITRD] Mounted {} files and directories as {}.", n, DEVICE);
 }
 
-pub fn init() {
-    INITRD.lock().nfiles = initrd_img::IMG[0];
- 
-    INITRD.lock().nroot_nodes = initrd_img::IMG[0] as u32;
-
-    vfs::add_child(&mut vfs::FS_ROOT.lock().node, &mut INITRD.lock().root);
-
-    let mut offset = 1;
-    for i in 0..INITRD.lock().nroot_nodes {
-        let header_size = mem::size_of::<FileHeader>();
-        let buffer = &initrd_img::IMG[offset..offset + header_size];
-        
-        let ptr: *const FileHeader = unsafe { mem::transmute(buffer.as_ptr()) };
-        let header: FileHeader = unsafe { *ptr };
-
-        unsafe { INITRD.force_unlock() };
-        INITRD.lock().file_headers.push(header);
-
-
-        let node = vfs::FsNode {
-            name: String::from(&osfn(header.name)),
-            system: vfs::System::Initrd,
-            flags: vfs::FS_FILE,
-            inode: i as u32,
-            length: header.size,
-            children: Vec::new(),
-            parent: ptr::null_mut(),
+pub fn get_root(dev_id: usize) -> Result<vfs::FsNode, vfs::Error> {
+    find_node_by_id(1, dev_id)
+}
+
+pub fn find_node(parent_id: u64, name: String, dev_id: usize) -> Result<vfs::FsNode, vfs::Error> {
+    let parent = entry(parent_id).ok_or(vfs::Error::FileNotFound)?;
+    if !parent.attributes.get_bit(vfs::ATTR_DIR) {
+        return Err(vfs::Error::ParentNotDirectory);
+    }
+
+    let initrd = INITRD.lock();
+    match child_named(&initrd.entries, parent_id, &name) {
+        Some(id) => Ok(node_from_entry(&initrd.entries[id as usize - 1], id, dev_id)),
+        None => Err(vfs::Error::FileNotFound),
+    }
+}
+
+pub fn find_node_by_id(id: u64, dev_id: usize) -> Result<vfs::FsNode, vfs::Error> {
+    let e = entry(id).ok_or(vfs::Error::FileNotFound)?;
+    Ok(node_from_entry(&e, id, dev_id))
+}
+
+pub fn get_children(id: u64, dev_id: usize) -> Result<Vec<vfs::FsNode>, vfs::Error> {
+    let dir = entry(id).ok_or(vfs::Error::FileNotFound)?;
+    if !dir.attributes.get_bit(vfs::ATTR_DIR) {
+        return Err(vfs::Error::IllegalOperation);
+    }
+
+    let initrd = INITRD.lock();
+    Ok(initrd.entries.iter().enumerate()
+        .filter(|(_, e)| e.parent == id)
+        .map(|(i, e)| node_from_entry(e, i as u64 + 1, dev_id))
+        .collect())
+}
+
+pub fn get_parent(id: u64, dev_id: usize) -> Result<vfs::FsNode, vfs::Error> {
+    let e = entry(id).ok_or(vfs::Error::FileNotFound)?;
+    if e.parent == 0 {
+        return find_node_by_id(id, dev_id);
+    }
+    find_node_by_id(e.parent, dev_id)
+}
+
+pub fn read_node(id: u64) -> Result<Vec<u8>, vfs::Error> {
+    let e = entry(id).ok_or(vfs::Error::FileNotFound)?;
+    if e.attributes.get_bit(vfs::ATTR_DIR) {
+        return Err(vfs::Error::IllegalOperation);
+    }
+    Ok(initrd_img::IMG[e.offset..e.offset + e.size].to_vec())
+}
+
+//InitRD specific functions
+
+fn parse(img: &[u8]) -> Result<Vec<Entry>, vfs::Error> {
+    let dir = *0u8.set_bit(vfs::ATTR_RO, true).set_bit(vfs::ATTR_DIR, true);
+    let mut entries = vec![Entry {
+        name: String::from(DEVICE),
+        parent: 0,
+        attributes: dir,
+        time: 0,
+        offset: 0,
+        size: 0,
+    }];
+
+    let mut pos = 0;
+    while pos + BLOCK <= img.len() {
+        let h = &img[pos..pos + BLOCK];
+        if h.iter().all(|&b| b == 0) {
+            break;
+        }
+        if h[MAGIC..MAGIC + 5] != USTAR_SIG || octal(&h[CHKSUM..CHKSUM + 8])? != checksum(h) {
+            return Err(vfs::Error::ReadError);
+        }
+
+        let start = pos;
+        let data = pos + BLOCK;
+        let size = octal(&h[SIZE..SIZE + 12])? as usize;
+        if data + size > img.len() {
+            return Err(vfs::Error::ReadError);
+        }
+        let time = octal(&h[MTIME..MTIME + 12])?;
+        let kind = h[TYPEFLAG];
+        pos = data + (size + BLOCK - 1) / BLOCK * BLOCK;
+
+        let path = path_of(h)?;
+        if path.is_empty() {
+            continue;
+        }
+        let (dir_path, name) = match path.rfind('/') {
+            Some(i) => (&path[..i], &path[i + 1..]),
+            None => ("", &path[..]),
+        };
+        let parent = make_dirs(&mut entries, dir_path)?;
+
+        let mut e = Entry {
+            name: name.to_string(),
+            parent: parent,
+            attributes: *0u8.set_bit(vfs::ATTR_RO, true),
+            time: time,
+            offset: data,
+            size: size,
+        };
+        match kind {
+            TYPE_FILE | TYPE_OLD_FILE | TYPE_CONTIGUOUS => {},
+            TYPE_DIR => {
+                e.attributes = dir;
+                e.size = 0;
+            },
+            TYPE_SYMLINK => {
+                e.attributes.set_bit(vfs::ATTR_LNK, true);
+                e.offset = start + LINKNAME;
+                e.size = cstr(&h[LINKNAME..LINKNAME + 100])?.len();
+            },
+            // A hard link shares the data of a file earlier in the archive.
+            TYPE_HARD_LINK => {
+                let target = cstr(&h[LINKNAME..LINKNAME + 100])?;
+                let target = lookup(&entries, target.trim_start_matches("./")).ok_or(vfs::Error::ReadError)?;
+                let t = &entries[target as usize - 1];
+                if t.attributes.get_bit(vfs::ATTR_DIR) {
+                    return Err(vfs::Error::ReadError);
+                }
+                e.attributes = t.attributes;
+                e.offset = t.offset;
+                e.size = t.size;
+            },
+            _ => continue,
+        }
+
+        // A later entry for the same path replaces the earlier one, but a
+        // directory made up for its files keeps its id and children.
+        match child_named(&entries, parent, name) {
+            Some(id) => {
+                let old = &mut entries[id as usize - 1];
+                if old.attributes.get_bit(vfs::ATTR_DIR) != e.attributes.get_bit(vfs::ATTR_DIR) {
+                    return Err(vfs::Error::ReadError);
+                }
+                *old = e;
+            },
+            None => entries.push(e),
+        }
+    }
+
+    Ok(entries)
+}
+
+// The directory `path` (relative to the root), created where missing.
+fn make_dirs(entries: &mut Vec<Entry>, path: &str) -> Result<u64, vfs::Error> {
+    let mut id = 1;
+    for name in path.split('/').filter(|n| !n.is_empty()) {
+        id = match child_named(entries, id, name) {
+            Some(c) if entries[c as usize - 1].attributes.get_bit(vfs::ATTR_DIR) => c,
+            Some(_) => return Err(vfs::Error::ReadError),
+            None => {
+                entries.push(Entry {
+                    name: name.to_string(),
+                    parent: id,
+                    attributes: *0u8.set_bit(vfs::ATTR_RO, true).set_bit(vfs::ATTR_DIR, true),
+                    time: 0,
+                    offset: 0,
+                    size: 0,
+                });
+                entries.len() as u64
+            },
         };
-        INITRD.lock().root_nodes.push(node);
-        offset += header_size;
     }
+    Ok(id)
+}
 
-    for i in 0..INITRD.lock().root_nodes.len() {
-        unsafe { INITRD.force_unlock() }
-        let n = &mut INITRD.lock().root_nodes[i as usize];
-        vfs::add_child(&mut vfs::FS_ROOT.lock().node, n);
+fn lookup(entries: &[Entry], path: &str) -> Option<u64> {
+    let mut id = 1;
+    for name in path.split('/').filter(|n| !n.is_empty()) {
+        id = child_named(entries, id, name)?;
     }
+    Some(id)
+}
+
+fn child_named(entries: &[Entry], parent: u64, name: &str) -> Option<u64> {
+    entries.iter().position(|e| e.parent == parent && e.name == name).map(|i| i as u64 + 1)
+}
 
-    for n in INITRD.lock().root_nodes.iter() {
-        unsafe { INITRD.force_unlock() }
-        vfs::reparent(
-            vfs::get_node_from_path(n.name.clone()).unwrap(),
-            vfs::get_node_from_path(String::from("Init")).unwrap(),
-        );
+// prefix/name without a leading "./" or trailing '/'. Every part must be a
+// valid VFS name.
+fn path_of(h: &[u8]) -> Result<String, vfs::Error> {
+    let name = cstr(&h[NAME..NAME + 100])?;
+    let prefix = cstr(&h[PREFIX..PREFIX + 155])?;
+    let mut path = if prefix.is_empty() { name.to_string() } else { prefix.to_string() + "/" + name };
+
+    while path.starts_with("./") {
+        path = path[2..].to_string();
+    }
+    let path = path.trim_end_matches('/');
+    if path.is_empty() || path == "." {
+        return Ok(String::new());
     }
+    for part in path.split('/') {
+        vfs::check_name(part).map_err(|_| vfs::Error::ReadError)?;
+    }
+    Ok(path.to_string())
+}
+
+// Text up to the first NUL.
+fn cstr(field: &[u8]) -> Result<&str, vfs::Error> {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    core::str::from_utf8(&field[..end]).map_err(|_| vfs::Error::InvalidName)
 }
 
-pub fn osfn(name: [char; 30]) -> String {
-    let mut res = String::new();
-    for i in 0..30 {
-        if name[i] == '\0' {
-           break;
-        } else {
-            res.push(name[i]);
+// Octal digits, maybe after spaces, ending at a NUL or space.
+fn octal(field: &[u8]) -> Result<u64, vfs::Error> {
+    let mut ret: u64 = 0;
+    for &b in field.iter().skip_while(|&&b| b == b' ') {
+        match b {
+            b'0'..=b'7' => ret = ret.checked_mul(8).ok_or(vfs::Error::ReadError)? + (b - b'0') as u64,
+            0 | b' ' => break,
+            _ => return Err(vfs::Error::ReadError),
         }
     }
-    res
+    Ok(ret)
+}
+
+// Sum of the header bytes with the checksum field taken as spaces.
+fn checksum(h: &[u8]) -> u64 {
+    h.iter().enumerate()
+        .map(|(i, &b)| if i >= CHKSUM && i < CHKSUM + 8 { b' ' as u64 } else { b as u64 })
+        .sum()
+}
+
+fn entry(id: u64) -> Option<Entry> {
+    if id == 0 {
+        return None;
+    }
+    INITRD.lock().entries.get(id as usize - 1).cloned()
 }
 
+fn node_from_entry(e: &Entry, id: u64, dev_id: usize) -> vfs::FsNode {
+    vfs::FsNode {
+        name: e.name.clone(),
+        device: dev_id,
+        parent_id: e.parent,
+        id: id,
+        attributes: e.attributes,
+        t_creation: e.time,
+        t_edit: e.time,
+        owner: 0,
+        size: e.size as u64,
+        open: false,
+    }
+}
@@ -6,6 +6,8 @@ use crate::pic::ChainedPics;
 use spin;
 use crate::stdin;
 use crate::timer;
+use crate::cache;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
@@ -29,6 +31,25 @@ impl InterruptIndex {
     }
 }
 
+// Interrupts taken per handled PIC line, in the order of IRQ_NAMES.
+static IRQ_COUNTS: [AtomicUsize; 4] = [AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0)];
+const IRQ_NAMES: [(InterruptIndex, &str); 4] = [
+    (InterruptIndex::Timer, "timer"),
+    (InterruptIndex::Keyboard, "keyboard"),
+    (InterruptIndex::PrimaryATA, "primary ata"),
+    (InterruptIndex::SecondaryATA, "secondary ata"),
+];
+
+/// IRQ line, name and number of interrupts taken so far for every handled
+/// hardware interrupt.
+pub fn irq_counts() -> [(u8, &'static str, usize); 4] {
+    let mut ret = [(0, "", 0); 4];
+    for (i, &(index, name)) in IRQ_NAMES.iter().enumerate() {
+        ret[i] = (index.as_u8() - PIC_1_OFFSET, name, IRQ_COUNTS[i].load(Ordering::Relaxed));
+    }
+    ret
+}
+
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
@@ -80,7 +101,9 @@ extern "x86-interrupt" fn double_fault_handler(
 extern "x86-interrupt" fn timer_interrupt_handler(
     _stack_frame: &mut InterruptStackFrame)
 {
+    IRQ_COUNTS[0].fetch_add(1, Ordering::Relaxed);
     timer::tick();
+    cache::tick();
     unsafe {
         PICS.lock()
             .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
@@ -90,6 +113,7 @@ extern "x86-interrupt" fn timer_interrupt_handler(
 extern "x86-interrupt" fn keyboard_interrupt_handler(
     _stack_frame: &mut InterruptStackFrame)
 {
+    IRQ_COUNTS[1].fetch_add(1, Ordering::Relaxed);
     use x86_64::instructions::port::Port;
     use pc_keyboard::{Keyboard, ScancodeSet1, KeyCode, DecodedKey, layouts};
     use spin::Mutex;
@@ -128,6 +152,7 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(
 extern "x86-interrupt" fn primary_ata_handler(
     _stack_frame: &mut InterruptStackFrame)
 {
+    IRQ_COUNTS[2].fetch_add(1, Ordering::Relaxed);
     unsafe {
         PICS.lock()
             .notify_end_of_interrupt(InterruptIndex::PrimaryATA.as_u8());
@@ -137,6 +162,7 @@ extern "x86-interrupt" fn primary_ata_handler(
 extern "x86-interrupt" fn secondary_ata_handler(
     _stack_frame: &mut InterruptStackFrame)
 {
+    IRQ_COUNTS[3].fetch_add(1, Ordering::Relaxed);
     unsafe {
         PICS.lock()
             .notify_end_of_interrupt(InterruptIndex::SecondaryATA.as_u8());
@@ -0,0 +1,480 @@
+//ISO 9660: read-only CD-ROM filesystem (ECMA-119)
+//Joliet names are used when the disc has a Joliet supplementary descriptor.
+
+use crate::vfs;
+use crate::drivers::ata;
+use crate::drivers::block;
+use crate::cache;
+use crate::println;
+use crate::time;
+use spin::Mutex;
+use lazy_static::lazy_static;
+use bit_field::BitField;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+
+const DESCRIPTOR_START: u32 = 16;
+const DESCRIPTOR_LIMIT: u32 = 64;
+const DESC_PRIMARY: u8 = 1;
+const DESC_SUPPLEMENTARY: u8 = 2;
+const DESC_TERMINATOR: u8 = 255;
+const ISO_SIG: [u8; 5] = [b'C', b'D', b'0', b'0', b'1'];
+const ROOT_RECORD: usize = 156;
+
+const FLAG_HIDDEN: usize = 0;
+const FLAG_DIR: usize = 1;
+
+// Directories are scanned once at mount; this keeps a corrupt or malicious
+// image from growing the table without bound.
+const MAX_DIRS: usize = 4096;
+
+pub struct IsoInfo {
+    pub mounted: bool,
+    pub dev: usize,
+    pub joliet: bool,
+    pub volume_id: String,
+    pub block_size: u64,
+    dirs: Vec<Directory>,
+}
+
+// Every directory on the disc, keyed by its node id. The id of a directory is
+// the byte offset of its own "." record, the id of a file is the byte offset
+// of its record inside the parent directory.
+#[derive(Clone)]
+struct Directory {
+    id: u64,
+    parent: u64,
+    extent: u32,
+    size: u32,
+    name: String,
+}
+
+#[derive(Clone)]
+struct Record {
+    location: u64,
+    extent: u32,
+    size: u32,
+    flags: u8,
+    name: String,
+    time: u64,
+}
+
+lazy_static! {
+    pub static ref ISO_INFO: Mutex<IsoInfo> = Mutex::new(IsoInfo {
+        mounted: false,
+        dev: 0,
+        joliet: false,
+        volume_id: String::new(),
+        block_size: ata::ATAPI_SECTOR_SIZE as u64,
+        dirs: Vec::new(),
+    });
+}
+
+pub fn init() {
+    let dev = match block::BLOCK_DEVICES.lock().iter().find(|d| d.kind == block::Kind::Atapi) {
+        Some(d) => d.index,
+        None => return,
+    };
+    ISO_INFO.lock().dev = dev;
+
+    let mut primary: Option<Vec<u8>> = None;
+    let mut joliet: Option<Vec<u8>> = None;
+
+    for lba in DESCRIPTOR_START..DESCRIPTOR_START + DESCRIPTOR_LIMIT {
+        let desc = match read_sector(lba) {
+            Ok(d) => d,
+            Err(_) => {
+                println!("[ISO] Could not read volume descriptors. Aborting.");
+                return;
+            },
+        };
+
+        if desc[1..6] != ISO_SIG {
+            break;
+        }
+
+        match desc[0] {
+            DESC_PRIMARY => primary = Some(desc),
+            DESC_SUPPLEMENTARY => {
+                if desc[88] == b'%' && desc[89] == b'/' && (desc[90] == b'@' || desc[90] == b'C' || desc[90] == b'E') {
+                    joliet = Some(desc);
+                }
+            },
+            DESC_TERMINATOR => break,
+            _ => {},
+        }
+    }
+
+    let pvd = match primary {
+        Some(p) => p,
+        None => {
+            println!("[ISO] No primary volume descriptor found.");
+            return;
+        },
+    };
+
+    let use_joliet = joliet.is_some();
+    let desc = match joliet {
+        Some(j) => j,
+        None => pvd.clone(),
+    };
+
+    let block_size = u16::from_le_bytes(pvd[128..130].try_into().expect("")) as u64;
+    if block_size != ata::ATAPI_SECTOR_SIZE as u64 {
+        println!("[ISO] Unsupported logical block size {}. Aborting.", block_size);
+        return;
+    }
+    let root = parse_record(&desc, ROOT_RECORD, 0, false);
+    let volume_id = String::from_utf8_lossy(&pvd[40..72]).trim_end().into();
+
+    {
+        let mut info = ISO_INFO.lock();
+        info.joliet = use_joliet;
+        info.block_size = block_size;
+        info.volume_id = volume_id;
+        info.dirs.clear();
+    }
+
+    let dev_name = String::from("D:");
+    let root_id = root.extent as u64 * block_size;
+    ISO_INFO.lock().dirs.push(Directory {
+        id: root_id,
+        parent: root_id,
+        extent: root.extent,
+        size: root.size,
+        name: dev_name.clone(),
+    });
+
+    if scan_dirs().is_err() {
+        println!("[ISO] Error while reading the directory tree.");
+    }
+
+    match vfs::install_device(dev_name.clone(), vfs::System::ISO9660) {
+        Ok(_) => {
+            let (volume_id, dirs) = {
+                let mut info = ISO_INFO.lock();
+                info.mounted = true;
+                (info.volume_id.clone(), info.dirs.len())
+            };
+            println!("[ISO] Mounted '{}' ({} directories{})", volume_id, dirs, if use_joliet { ", Joliet" } else { "" });
+        },
+        Err(e) => println!("[ISO] Could not mount {} ({}).", dev_name, e),
+    }
+}
+
+// VFS functions
+
+pub fn get_root(dev_id: usize) -> Result<vfs::FsNode, vfs::Error> {
+    let root = match ISO_INFO.lock().dirs.first() {
+        Some(d) => d.clone(),
+        None => return Err(vfs::Error::DeviceNotFound),
+    };
+    Ok(node_from_dir(&root, dev_id))
+}
+
+pub fn find_node(parent_id: u64, name: String, dev_id: usize) -> Result<vfs::FsNode, vfs::Error> {
+    let parent = find_dir(parent_id).ok_or(vfs::Error::ParentNotDirectory)?;
+
+    for r in read_dir(&parent)? {
+        if r.name.eq_ignore_ascii_case(&name) {
+            return Ok(node_from_record(&r, parent.id, dev_id));
+        }
+    }
+
+    Err(vfs::Error::FileNotFound)
+}
+
+pub fn find_node_by_id(id: u64, dev_id: usize) -> Result<vfs::FsNode, vfs::Error> {
+    if let Some(d) = find_dir(id) {
+        return Ok(node_from_dir(&d, dev_id));
+    }
+
+    let parent = dir_containing(id).ok_or(vfs::Error::FileNotFound)?;
+    let r = record_at(id)?;
+    Ok(node_from_record(&r, parent.id, dev_id))
+}
+
+pub fn get_children(id: u64, dev_id: usize) -> Result<Vec<vfs::FsNode>, vfs::Error> {
+    let dir = find_dir(id).ok_or(vfs::Error::IllegalOperation)?;
+
+    let records = read_dir(&dir)?;
+    let mut ret: Vec<vfs::FsNode> = Vec::with_capacity(records.len());
+    for r in records.iter() {
+        ret.push(node_from_record(r, dir.id, dev_id));
+    }
+
+    Ok(ret)
+}
+
+pub fn get_parent(id: u64, dev_id: usize) -> Result<vfs::FsNode, vfs::Error> {
+    let parent = match find_dir(id) {
+        Some(d) => find_dir(d.parent),
+        None => dir_containing(id),
+    };
+
+    match parent {
+        Some(p) => Ok(node_from_dir(&p, dev_id)),
+        None => Err(vfs::Error::FileNotFound),
+    }
+}
+
+pub fn read_node(id: u64) -> Result<Vec<u8>, vfs::Error> {
+    if find_dir(id).is_some() {
+        return Err(vfs::Error::IllegalOperation);
+    }
+
+    let r = record_at(id)?;
+    if r.size as usize > vfs::READ_MAX {
+        return Err(vfs::Error::NoSpace);
+    }
+    read_extent(r.extent, r.size)
+}
+
+/// Up to `len` bytes from `offset`, reading only the sectors they are in.
+pub fn read_node_at(id: u64, offset: u64, len: usize) -> Result<Vec<u8>, vfs::Error> {
+    if find_dir(id).is_some() {
+        return Err(vfs::Error::IllegalOperation);
+    }
+
+    let r = record_at(id)?;
+    let size = r.size as u64;
+    if offset >= size || len == 0 {
+        return Ok(Vec::new());
+    }
+    let end = offset.saturating_add(len as u64).min(size);
+    let bs = ISO_INFO.lock().block_size;
+
+    let mut ret: Vec<u8> = Vec::with_capacity((end - offset) as usize);
+    let mut pos = offset;
+    while pos < end {
+        let sec = read_sector(r.extent + (pos / bs) as u32)?;
+        let from = (pos % bs) as usize;
+        let n = (bs - pos % bs).min(end - pos) as usize;
+        ret.extend_from_slice(&sec[from..from + n]);
+        pos += n as u64;
+    }
+    Ok(ret)
+}
+
+//ISO specific functions
+
+fn read_sector(lba: u32) -> Result<Vec<u8>, vfs::Error> {
+    let dev = ISO_INFO.lock().dev;
+    cache::read(dev, lba as u64).map_err(|e| vfs::Error::io(dev, e))
+}
+
+fn read_extent(extent: u32, size: u32) -> Result<Vec<u8>, vfs::Error> {
+    let bs = ISO_INFO.lock().block_size as u32;
+    let mut ret: Vec<u8> = Vec::with_capacity(size as usize);
+
+    let mut lba = extent;
+    while ret.len() < size as usize {
+        let sec = read_sector(lba)?;
+        let remaining = size as usize - ret.len();
+        let n = if remaining < bs as usize { remaining } else { bs as usize };
+        ret.extend_from_slice(&sec[..n]);
+        lba += 1;
+    }
+
+    Ok(ret)
+}
+
+fn read_dir(dir: &Directory) -> Result<Vec<Record>, vfs::Error> {
+    let (bs, joliet) = {
+        let info = ISO_INFO.lock();
+        (info.block_size, info.joliet)
+    };
+
+    let mut res: Vec<Record> = Vec::new();
+    let sectors = (dir.size as u64 + bs - 1) / bs;
+
+    for i in 0..sectors {
+        let lba = dir.extent + i as u32;
+        let sec = read_sector(lba)?;
+
+        // Records never cross a sector boundary; a zero length byte pads out
+        // the rest of the sector.
+        let mut off = 0;
+        while off < bs as usize && off < sec.len() {
+            let len = sec[off] as usize;
+            if len == 0 || off + len > sec.len() || len < 34 {
+                break;
+            }
+
+            let r = parse_record(&sec, off, lba as u64 * bs + off as u64, joliet);
+            if r.name != "." && r.name != ".." {
+                res.push(r);
+            }
+            off += len;
+        }
+    }
+
+    Ok(res)
+}
+
+fn record_at(location: u64) -> Result<Record, vfs::Error> {
+    let (bs, joliet) = {
+        let info = ISO_INFO.lock();
+        (info.block_size, info.joliet)
+    };
+
+    let sec = read_sector((location / bs) as u32)?;
+    let off = (location % bs) as usize;
+    if off + 34 > sec.len() || sec[off] == 0 {
+        return Err(vfs::Error::FileNotFound);
+    }
+
+    Ok(parse_record(&sec, off, location, joliet))
+}
+
+fn parse_record(buf: &[u8], off: usize, location: u64, joliet: bool) -> Record {
+    let extent = u32::from_le_bytes(buf[off + 2..off + 6].try_into().expect(""));
+    let size = u32::from_le_bytes(buf[off + 10..off + 14].try_into().expect(""));
+    let flags = buf[off + 25];
+    let time = record_time(&buf[off + 18..off + 25]);
+    let name_len = buf[off + 32] as usize;
+
+    let end = if off + 33 + name_len > buf.len() { buf.len() } else { off + 33 + name_len };
+    let raw = &buf[off + 33..end];
+
+    let name = if raw.len() == 1 && raw[0] == 0 {
+        String::from(".")
+    } else if raw.len() == 1 && raw[0] == 1 {
+        String::from("..")
+    } else if joliet {
+        let mut units: Vec<u16> = Vec::with_capacity(raw.len() / 2);
+        for c in raw.chunks(2) {
+            if c.len() == 2 {
+                units.push(u16::from_be_bytes([c[0], c[1]]));
+            }
+        }
+        clean_name(String::from_utf16_lossy(&units))
+    } else {
+        clean_name(String::from_utf8_lossy(raw).into())
+    };
+
+    Record {
+        location: location,
+        extent: extent,
+        size: size,
+        flags: flags,
+        name: name,
+        time: time,
+    }
+}
+
+// Recording date: years since 1900, month, day, hour, minute, second and the
+// offset from GMT in 15 minute steps.
+fn record_time(raw: &[u8]) -> u64 {
+    let ts = time::to_unix(time::DateTime {
+        year: 1900 + raw[0] as u64,
+        month: raw[1] as u64,
+        day: raw[2] as u64,
+        hour: raw[3] as u64,
+        minute: raw[4] as u64,
+        second: raw[5] as u64,
+    });
+    let offset = (raw[6] as i8) as i64 * 15 * 60;
+
+    if ts == 0 {
+        return 0;
+    }
+    (ts as i64 - offset) as u64
+}
+
+// Strips the ";1" version suffix and the trailing dot of extensionless names.
+fn clean_name(name: String) -> String {
+    let mut n = match name.find(';') {
+        Some(i) => String::from(&name[..i]),
+        None => name,
+    };
+    if n.ends_with('.') {
+        n.pop();
+    }
+    n
+}
+
+fn scan_dirs() -> Result<(), vfs::Error> {
+    let bs = ISO_INFO.lock().block_size;
+
+    let mut i = 0;
+    loop {
+        let dir = match ISO_INFO.lock().dirs.get(i) {
+            Some(d) => d.clone(),
+            None => break,
+        };
+
+        for r in read_dir(&dir)? {
+            if !r.flags.get_bit(FLAG_DIR) {
+                continue;
+            }
+
+            let id = r.extent as u64 * bs;
+            let mut info = ISO_INFO.lock();
+            if info.dirs.len() >= MAX_DIRS {
+                return Ok(());
+            }
+            if info.dirs.iter().any(|d| d.id == id) {
+                continue;
+            }
+            info.dirs.push(Directory {
+                id: id,
+                parent: dir.id,
+                extent: r.extent,
+                size: r.size,
+                name: r.name,
+            });
+        }
+        i += 1;
+    }
+
+    Ok(())
+}
+
+fn find_dir(id: u64) -> Option<Directory> {
+    ISO_INFO.lock().dirs.iter().find(|d| d.id == id).cloned()
+}
+
+fn dir_containing(location: u64) -> Option<Directory> {
+    let bs = ISO_INFO.lock().block_size;
+    ISO_INFO.lock().dirs.iter().find(|d| {
+        let start = d.extent as u64 * bs;
+        location >= start && location < start + d.size as u64
+    }).cloned()
+}
+
+fn node_from_dir(d: &Directory, dev_id: usize) -> vfs::FsNode {
+    vfs::FsNode {
+        name: d.name.clone(),
+        device: dev_id,
+        parent_id: d.parent,
+        id: d.id,
+        attributes: *0u8.set_bit(vfs::ATTR_RO, true).set_bit(vfs::ATTR_DIR, true),
+        t_creation: 0,
+        t_edit: 0,
+        owner: 0,
+        size: d.size as u64,
+        open: false,
+    }
+}
+
+fn node_from_record(r: &Record, parent_id: u64, dev_id: usize) -> vfs::FsNode {
+    let bs = ISO_INFO.lock().block_size;
+    let is_dir = r.flags.get_bit(FLAG_DIR);
+
+    vfs::FsNode {
+        name: r.name.clone(),
+        device: dev_id,
+        parent_id: parent_id,
+        id: if is_dir { r.extent as u64 * bs } else { r.location },
+        attributes: *0u8.set_bit(vfs::ATTR_RO, true)
+            .set_bit(vfs::ATTR_DIR, is_dir)
+            .set_bit(vfs::ATTR_HDN, r.flags.get_bit(FLAG_HIDDEN)),
+        t_creation: r.time,
+        t_edit: r.time,
+        owner: 0,
+        size: r.size as u64,
+        open: false,
+    }
+}
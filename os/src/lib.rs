@@ -33,9 +33,18 @@ pub mod console;
 pub mod commands;
 pub mod vfs;
 pub mod timer;
+pub mod time;
+pub mod cache;
 pub mod pic;
 pub mod drivers;
 pub mod wfs;
+pub mod iso9660;
+pub mod ramfs;
+pub mod initrd;
+pub mod fat;
+pub mod ext2;
+pub mod devfs;
+pub mod sysfs;
 pub mod struct_tools;
 
 #[global_allocator]
@@ -46,7 +55,7 @@ pub fn init() {
     interrupts::init_idt();
     unsafe { interrupts::PICS.lock().initialize() };
     x86_64::instructions::interrupts::enable();
-    timer::init(50);
+    timer::init(timer::FREQUENCY);
 }
 
 pub fn hlt_loop() -> ! {
@@ -15,7 +15,15 @@ use os::vga_buffer;
 use os::commands;
 use os::drivers::cmos;
 use os::drivers::ata;
+use os::drivers::block;
 use os::wfs;
+use os::iso9660;
+use os::ramfs;
+use os::initrd;
+use os::fat;
+use os::ext2;
+use os::devfs;
+use os::sysfs;
 use os::vfs;
 use alloc::vec::Vec;
 use alloc::string::String;
@@ -47,17 +55,35 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 
     commands::init();
     ata::init();
+    block::init();
     wfs::init();
+    ramfs::init();
+    initrd::init();
+    iso9660::init();
+    fat::init();
+    ext2::init();
+    devfs::init();
+    sysfs::init();
 
     println!();
     console::init();
 
-    let mut hello = vfs::create_node(1, String::from("hello.txt"), 0, 0, 0).unwrap();
-    hello.open();
-    hello.write(b"Welcome to the wOS filesystem, wFS!\n".to_vec());
-    hello.close();
-
-    vfs::create_node(1, String::from("Home"), *0u8.set_bit(vfs::ATTR_DIR, true), 0, 0).unwrap();
+    // The defaults are already there after the first boot, and a read-only
+    // volume can't take them.
+    match vfs::create_node(1, String::from("hello.txt"), 0, 0, 0) {
+        Ok(mut hello) => {
+            hello.open();
+            hello.write(b"Welcome to the wOS filesystem, wFS!\n".to_vec());
+            hello.close();
+        },
+        Err(vfs::Error::AlreadyExists) | Err(vfs::Error::PermissionDenied) => {},
+        Err(e) => println!("{}", e.at("A:/hello.txt")),
+    }
+
+    match vfs::create_node(1, String::from("Home"), *0u8.set_bit(vfs::ATTR_DIR, true), 0, 0) {
+        Ok(_) | Err(vfs::Error::AlreadyExists) | Err(vfs::Error::PermissionDenied) => {},
+        Err(e) => println!("{}", e.at("A:/Home")),
+    }
 
     os::hlt_loop();
 }
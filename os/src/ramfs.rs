@@ -0,0 +1,287 @@
+//RamFS: a filesystem kept in memory, gone at reboot.
+//It stands in for A: when there is no wFS volume and holds scratch files at T:.
+
+use crate::vfs;
+use crate::time;
+use crate::println;
+use crate::allocator;
+use spin::Mutex;
+use lazy_static::lazy_static;
+use bit_field::BitField;
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+/// Device name of the volume for temporary files.
+pub const TMP_DEVICE: &str = "T:";
+
+// File data a volume may hold. Everything lives on the kernel heap, so one
+// volume must not be able to fill it.
+const MAX_BYTES: usize = allocator::HEAP_SIZE / 4;
+
+// Ids follow wFS: the root is 1 and its parent 0, so code written against
+// A: works the same when A: is in memory.
+const ROOT_ID: u64 = 1;
+
+#[derive(Clone)]
+struct Node {
+    name: String,
+    parent: u64,
+    attributes: u8,
+    t_creation: u64,
+    t_edit: u64,
+    owner: u8,
+    data: Vec<u8>,
+}
+
+struct Volume {
+    next_id: u64,
+    bytes: usize,
+    nodes: BTreeMap<u64, Node>,
+}
+
+lazy_static! {
+    // Volumes by device index.
+    static ref VOLUMES: Mutex<BTreeMap<usize, Volume>> = Mutex::new(BTreeMap::new());
+}
+
+pub fn init() {
+    if vfs::find_device("A:").is_none() {
+        println!("[RAMFS] No wFS volume, A: is kept in memory and lost at reboot.");
+        if let Err(e) = mount("A:") {
+            println!("[RAMFS] Could not mount A: ({}).", e);
+        }
+    }
+
+    if let Err(e) = mount(TMP_DEVICE) {
+        println!("[RAMFS] Could not mount {} ({}).", TMP_DEVICE, e);
+    }
+}
+
+/// Installs an empty volume as the device `name` and returns its index.
+pub fn mount(name: &str) -> Result<usize, vfs::Error> {
+    let dev = vfs::install_device(name.to_string(), vfs::System::RamFS)?;
+
+    let now = time::now();
+    let root = Node {
+        name: name.to_string(),
+        parent: 0,
+        attributes: *0u8.set_bit(vfs::ATTR_DIR, true).set_bit(vfs::ATTR_SYS, true),
+        t_creation: now,
+        t_edit: now,
+        owner: vfs::SYSTEM_USER,
+        data: Vec::new(),
+    };
+    let mut nodes = BTreeMap::new();
+    nodes.insert(ROOT_ID, root);
+    VOLUMES.lock().insert(dev, Volume { next_id: ROOT_ID + 1, bytes: 0, nodes: nodes });
+
+    Ok(dev)
+}
+
+pub fn find_node(parent_id: u64, name: String, dev_id: usize) -> Result<vfs::FsNode, vfs::Error> {
+    with_volume(dev_id, |v| {
+        let parent = v.nodes.get(&parent_id).ok_or(vfs::Error::FileNotFound)?;
+        if !parent.attributes.get_bit(vfs::ATTR_DIR) {
+            return Err(vfs::Error::ParentNotDirectory);
+        }
+        let id = child_named(v, parent_id, &name).ok_or(vfs::Error::FileNotFound)?;
+        Ok(to_node(id, &v.nodes[&id], dev_id))
+    })
+}
+
+pub fn find_node_by_id(id: u64, dev_id: usize) -> Result<vfs::FsNode, vfs::Error> {
+    with_volume(dev_id, |v| {
+        let n = v.nodes.get(&id).ok_or(vfs::Error::FileNotFound)?;
+        Ok(to_node(id, n, dev_id))
+    })
+}
+
+pub fn create_node(parent_id: u64, name: String, attributes: u8, owner: u8, dev_id: usize) -> Result<vfs::FsNode, vfs::Error> {
+    vfs::check_name(&name)?;
+
+    with_volume(dev_id, |v| {
+        let parent = v.nodes.get(&parent_id).ok_or(vfs::Error::FileNotFound)?;
+        if !parent.attributes.get_bit(vfs::ATTR_DIR) {
+            return Err(vfs::Error::ParentNotDirectory);
+        }
+        if child_named(v, parent_id, &name).is_some() {
+            return Err(vfs::Error::AlreadyExists);
+        }
+
+        let now = time::now();
+        let node = Node {
+            name: name,
+            parent: parent_id,
+            attributes: attributes,
+            t_creation: now,
+            t_edit: now,
+            owner: owner,
+            data: Vec::new(),
+        };
+        let id = v.next_id;
+        v.next_id += 1;
+        let ret = to_node(id, &node, dev_id);
+        v.nodes.insert(id, node);
+        Ok(ret)
+    })
+}
+
+/// Creates a symbolic link holding `target`, like wfs::create_symlink.
+pub fn create_symlink(parent_id: u64, name: String, target: String, owner: u8, dev_id: usize) -> Result<vfs::FsNode, vfs::Error> {
+    let attributes = *0u8.set_bit(vfs::ATTR_LNK, true);
+    let mut node = create_node(parent_id, name, attributes, owner, dev_id)?;
+    if let Err(e) = write_node(node.id, target.into_bytes(), dev_id) {
+        let _ = delete_node(node.id, dev_id);
+        return Err(e);
+    }
+    node.size = find_node_by_id(node.id, dev_id)?.size;
+    Ok(node)
+}
+
+pub fn read_node(id: u64, dev_id: usize) -> Result<Vec<u8>, vfs::Error> {
+    with_volume(dev_id, |v| {
+        let n = v.nodes.get(&id).ok_or(vfs::Error::FileNotFound)?;
+        if n.attributes.get_bit(vfs::ATTR_DIR) {
+            return Err(vfs::Error::IllegalOperation);
+        }
+        Ok(n.data.clone())
+    })
+}
+
+pub fn read_node_at(id: u64, offset: u64, len: usize, dev_id: usize) -> Result<Vec<u8>, vfs::Error> {
+    with_volume(dev_id, |v| {
+        let n = v.nodes.get(&id).ok_or(vfs::Error::FileNotFound)?;
+        if n.attributes.get_bit(vfs::ATTR_DIR) {
+            return Err(vfs::Error::IllegalOperation);
+        }
+        let start = (offset as usize).min(n.data.len());
+        let end = start.saturating_add(len).min(n.data.len());
+        Ok(n.data[start..end].to_vec())
+    })
+}
+
+pub fn write_node(id: u64, buf: Vec<u8>, dev_id: usize) -> Result<(), vfs::Error> {
+    with_volume(dev_id, |v| {
+        let old = file_len(v, id)?;
+        if v.bytes - old + buf.len() > MAX_BYTES {
+            return Err(vfs::Error::NoSpace);
+        }
+        v.bytes = v.bytes - old + buf.len();
+
+        let n = v.nodes.get_mut(&id).unwrap();
+        n.data = buf;
+        n.t_edit = time::now();
+        Ok(())
+    })
+}
+
+pub fn append_node(id: u64, buf: Vec<u8>, dev_id: usize) -> Result<(), vfs::Error> {
+    with_volume(dev_id, |v| {
+        file_len(v, id)?;
+        if v.bytes + buf.len() > MAX_BYTES {
+            return Err(vfs::Error::NoSpace);
+        }
+        v.bytes += buf.len();
+
+        let n = v.nodes.get_mut(&id).unwrap();
+        n.data.extend_from_slice(&buf);
+        n.t_edit = time::now();
+        Ok(())
+    })
+}
+
+/// Deletes a file or an empty directory.
+pub fn delete_node(id: u64, dev_id: usize) -> Result<(), vfs::Error> {
+    with_volume(dev_id, |v| {
+        if id == ROOT_ID {
+            return Err(vfs::Error::IllegalOperation);
+        }
+        if v.nodes.values().any(|n| n.parent == id) {
+            return Err(vfs::Error::NotEmpty);
+        }
+        let n = v.nodes.remove(&id).ok_or(vfs::Error::FileNotFound)?;
+        v.bytes -= n.data.len();
+        Ok(())
+    })
+}
+
+pub fn rename_node(id: u64, new_name: String, dev_id: usize) -> Result<(), vfs::Error> {
+    vfs::check_name(&new_name)?;
+
+    with_volume(dev_id, |v| {
+        let parent = v.nodes.get(&id).ok_or(vfs::Error::FileNotFound)?.parent;
+        if child_named(v, parent, &new_name).is_some() {
+            return Err(vfs::Error::AlreadyExists);
+        }
+        v.nodes.get_mut(&id).unwrap().name = new_name;
+        Ok(())
+    })
+}
+
+pub fn set_attributes(id: u64, attributes: u8, dev_id: usize) -> Result<(), vfs::Error> {
+    with_volume(dev_id, |v| {
+        let n = v.nodes.get_mut(&id).ok_or(vfs::Error::FileNotFound)?;
+        n.attributes = attributes;
+        Ok(())
+    })
+}
+
+pub fn get_root(dev_id: usize) -> Result<vfs::FsNode, vfs::Error> {
+    find_node_by_id(ROOT_ID, dev_id)
+}
+
+pub fn get_parent(id: u64, dev_id: usize) -> Result<vfs::FsNode, vfs::Error> {
+    if id == ROOT_ID {
+        return get_root(dev_id);
+    }
+    let parent = with_volume(dev_id, |v| Ok(v.nodes.get(&id).ok_or(vfs::Error::FileNotFound)?.parent))?;
+    find_node_by_id(parent, dev_id)
+}
+
+pub fn get_children(id: u64, dev_id: usize) -> Result<Vec<vfs::FsNode>, vfs::Error> {
+    with_volume(dev_id, |v| {
+        let dir = v.nodes.get(&id).ok_or(vfs::Error::FileNotFound)?;
+        if !dir.attributes.get_bit(vfs::ATTR_DIR) {
+            return Err(vfs::Error::IllegalOperation);
+        }
+        Ok(v.nodes.iter().filter(|(_, n)| n.parent == id).map(|(&c, n)| to_node(c, n, dev_id)).collect())
+    })
+}
+
+//RamFS specific functions
+
+fn with_volume<T>(dev_id: usize, f: impl FnOnce(&mut Volume) -> Result<T, vfs::Error>) -> Result<T, vfs::Error> {
+    match VOLUMES.lock().get_mut(&dev_id) {
+        Some(v) => f(v),
+        None => Err(vfs::Error::DeviceNotFound),
+    }
+}
+
+fn child_named(v: &Volume, parent_id: u64, name: &str) -> Option<u64> {
+    v.nodes.iter().find(|(_, n)| n.parent == parent_id && n.name == name).map(|(&id, _)| id)
+}
+
+// Size of a file's data; directories have none to write.
+fn file_len(v: &Volume, id: u64) -> Result<usize, vfs::Error> {
+    let n = v.nodes.get(&id).ok_or(vfs::Error::FileNotFound)?;
+    if n.attributes.get_bit(vfs::ATTR_DIR) {
+        return Err(vfs::Error::IllegalOperation);
+    }
+    Ok(n.data.len())
+}
+
+fn to_node(id: u64, n: &Node, dev_id: usize) -> vfs::FsNode {
+    vfs::FsNode {
+        name: n.name.clone(),
+        device: dev_id,
+        parent_id: n.parent,
+        id: id,
+        attributes: n.attributes,
+        t_creation: n.t_creation,
+        t_edit: n.t_edit,
+        owner: n.owner,
+        size: n.data.len() as u64,
+        open: false,
+    }
+}
@@ -1,10 +1,16 @@
 use uart_16550::SerialPort;
 use spin::Mutex;
 use lazy_static::lazy_static;
+use alloc::vec::Vec;
+use crate::io;
+
+const COM1: u16 = 0x3F8;
+const LINE_STATUS: u16 = COM1 + 5;
+const DATA_READY: u8 = 0x01;
 
 lazy_static! {
     pub static ref SERIAL1: Mutex<SerialPort> = {
-        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
+        let mut serial_port = unsafe { SerialPort::new(COM1) };
         serial_port.init();
         Mutex::new(serial_port)
     };
@@ -23,6 +29,29 @@ pub fn _print(args: ::core::fmt::Arguments) {
     });
 }
 
+/// Sends raw bytes, unlike serial_print! which takes text.
+pub fn send(buf: &[u8]) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut port = SERIAL1.lock();
+        for &b in buf {
+            port.send(b);
+        }
+    });
+}
+
+/// The bytes received so far, without waiting for more.
+pub fn receive() -> Vec<u8> {
+    let mut ret = Vec::new();
+    unsafe {
+        while io::inb(LINE_STATUS) & DATA_READY != 0 {
+            ret.push(io::inb(COM1));
+        }
+    }
+    ret
+}
+
 #[macro_export]
 macro_rules! serial_print {
     ($($arg:tt)*) => {
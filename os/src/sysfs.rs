@@ -0,0 +1,195 @@
+//sysfs: kernel state as read-only text files, mounted at SYS:.
+//Files are rendered when read, so like /proc on Unix they list with size 0.
+//Each line is `key: value` or a row of space separated fields.
+
+use crate::vfs;
+use crate::drivers::block;
+use crate::wfs;
+use crate::wfs::disk;
+use crate::allocator;
+use crate::interrupts;
+use crate::commands;
+use crate::cache;
+use crate::timer;
+use crate::time;
+use crate::println;
+use bit_field::BitField;
+use alloc::string::String;
+use alloc::vec::Vec;
+use alloc::format;
+
+/// Device name sysfs is mounted as.
+pub const DEVICE: &str = "SYS:";
+
+const ROOT_ID: u64 = 1;
+
+// File n has id n + 2.
+const FILES: [(&str, fn() -> String); 7] = [
+    ("commands", list_commands),
+    ("devices", list_devices),
+    ("interrupts", list_interrupts),
+    ("meminfo", meminfo),
+    ("mounts", list_mounts),
+    ("uptime", uptime),
+    ("wfs", wfs_info),
+];
+
+pub fn init() {
+    match vfs::install_device(String::from(DEVICE), vfs::System::SysFS) {
+        Ok(_) => println!("[SYSFS] Mounted {} files as {}.", FILES.len(), DEVICE),
+        Err(e) => println!("[SYSFS] Could not mount {} ({}).", DEVICE, e),
+    }
+}
+
+// VFS functions
+
+pub fn get_root(dev_id: usize) -> Result<vfs::FsNode, vfs::Error> {
+    Ok(vfs::FsNode {
+        name: String::from(DEVICE),
+        device: dev_id,
+        parent_id: 0,
+        id: ROOT_ID,
+        attributes: *0u8.set_bit(vfs::ATTR_RO, true).set_bit(vfs::ATTR_DIR, true),
+        t_creation: 0,
+        t_edit: 0,
+        owner: vfs::SYSTEM_USER,
+        size: 0,
+        open: false,
+    })
+}
+
+pub fn find_node(parent_id: u64, name: String, dev_id: usize) -> Result<vfs::FsNode, vfs::Error> {
+    if parent_id != ROOT_ID {
+        return Err(vfs::Error::ParentNotDirectory);
+    }
+    match FILES.iter().position(|(n, _)| *n == name) {
+        Some(i) => find_node_by_id(i as u64 + 2, dev_id),
+        None => Err(vfs::Error::FileNotFound),
+    }
+}
+
+pub fn find_node_by_id(id: u64, dev_id: usize) -> Result<vfs::FsNode, vfs::Error> {
+    if id == ROOT_ID {
+        return get_root(dev_id);
+    }
+    let (name, _) = file(id)?;
+    Ok(vfs::FsNode {
+        name: String::from(name),
+        device: dev_id,
+        parent_id: ROOT_ID,
+        id: id,
+        attributes: *0u8.set_bit(vfs::ATTR_RO, true),
+        t_creation: 0,
+        t_edit: 0,
+        owner: vfs::SYSTEM_USER,
+        size: 0,
+        open: false,
+    })
+}
+
+pub fn get_parent(id: u64, dev_id: usize) -> Result<vfs::FsNode, vfs::Error> {
+    if id != ROOT_ID {
+        file(id)?;
+    }
+    get_root(dev_id)
+}
+
+pub fn get_children(id: u64, dev_id: usize) -> Result<Vec<vfs::FsNode>, vfs::Error> {
+    if id != ROOT_ID {
+        return Err(vfs::Error::IllegalOperation);
+    }
+    (0..FILES.len()).map(|i| find_node_by_id(i as u64 + 2, dev_id)).collect()
+}
+
+pub fn read_node(id: u64) -> Result<Vec<u8>, vfs::Error> {
+    let (_, render) = file(id)?;
+    Ok(render().into_bytes())
+}
+
+//sysfs specific functions
+
+fn file(id: u64) -> Result<(&'static str, fn() -> String), vfs::Error> {
+    if id < 2 {
+        return Err(vfs::Error::FileNotFound);
+    }
+    FILES.get(id as usize - 2).cloned().ok_or(vfs::Error::FileNotFound)
+}
+
+fn list_commands() -> String {
+    let mut s = String::new();
+    for (name, desc) in commands::list() {
+        s.push_str(&format!("{} - {}\n", name, desc));
+    }
+    s
+}
+
+// Block devices: name, kind, sectors, sector size and rw or ro.
+fn list_devices() -> String {
+    let devices = block::BLOCK_DEVICES.lock().clone();
+    let mut s = String::new();
+    for d in devices.iter() {
+        let kind = match d.kind {
+            block::Kind::Ata { master: true } => String::from("ata-master"),
+            block::Kind::Ata { master: false } => String::from("ata-slave"),
+            block::Kind::Atapi => String::from("atapi"),
+            block::Kind::Partition { disk, .. } => match block::get(disk) {
+                Some(p) => format!("partition-of-{}", p.name),
+                None => String::from("partition"),
+            },
+        };
+        s.push_str(&format!("{} {} {} {} {}\n", d.name, kind, d.sectors, d.sector_size, if d.read_only { "ro" } else { "rw" }));
+    }
+    s
+}
+
+// IRQ line, interrupts taken and handler.
+fn list_interrupts() -> String {
+    let mut s = String::new();
+    for (irq, name, count) in interrupts::irq_counts().iter() {
+        s.push_str(&format!("{} {} {}\n", irq, count, name));
+    }
+    s
+}
+
+fn meminfo() -> String {
+    let (used, allocations) = allocator::usage();
+    let (cached, dirty) = cache::usage();
+    format!("heap_total: {}\nheap_used: {}\nheap_free: {}\nallocations: {}\ncache_sectors: {}\ncache_capacity: {}\ncache_dirty: {}\n",
+        allocator::HEAP_SIZE, used, allocator::HEAP_SIZE - used, allocations, cached, cache::CACHE_SECTORS, dirty)
+}
+
+// Mounted devices: name, filesystem and open files.
+fn list_mounts() -> String {
+    let mut s = String::new();
+    for d in vfs::DEVICES.lock().iter() {
+        s.push_str(&format!("{} {:?} {}\n", d.name, d.system, d.opened.len()));
+    }
+    s
+}
+
+fn uptime() -> String {
+    let ticks = timer::TIMER.lock().ticks;
+    let seconds = (ticks / timer::FREQUENCY) as u64;
+    format!("seconds: {}.{:02}\nticks: {}\nfrequency: {}\nbooted: {}\n",
+        seconds, ticks % timer::FREQUENCY * 100 / timer::FREQUENCY, ticks, timer::FREQUENCY,
+        time::format(time::now().saturating_sub(seconds)))
+}
+
+fn wfs_info() -> String {
+    let mounted = vfs::DEVICES.lock().iter().any(|d| d.system == vfs::System::WFS);
+    if !mounted {
+        return String::from("mounted: no\n");
+    }
+
+    let info = *wfs::WFS_INFO.lock();
+    let dev = block::get(*wfs::WFS_DEV.lock()).map(|d| d.name).unwrap_or_default();
+    let state = match info.state {
+        disk::STATE_CLEAN => "clean",
+        disk::STATE_MOUNTED => "mounted",
+        disk::STATE_UPGRADING => "upgrading",
+        _ => "unknown",
+    };
+    format!("mounted: {}\ndevice: {}\nstate: {}\nversion: {}\nblock_size: {}\nblocks: {}\nblocks_in_use: {}\nfiles: {}\njournal_blocks: {}\n",
+        if wfs::is_read_only() { "read-only" } else { "read-write" }, dev, state, info.version,
+        info.bytes_per_block, info.blocks, info.blocks_in_use, info.files, info.journal_len)
+}
@@ -0,0 +1,103 @@
+//Wall clock time as seconds since the Unix epoch (1970-01-01 00:00:00 UTC).
+
+use crate::drivers::cmos;
+use alloc::string::String;
+use alloc::format;
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct DateTime {
+    pub year: u64,
+    pub month: u64,
+    pub day: u64,
+    pub hour: u64,
+    pub minute: u64,
+    pub second: u64,
+}
+
+/// Current time read from the RTC.
+pub fn now() -> u64 {
+    cmos::RTC.lock().get_unix_time()
+}
+
+/// Converts a UTC calendar date to a Unix timestamp. Dates before 1970, and
+/// fields out of range (as read from a damaged disk), give 0.
+pub fn to_unix(dt: DateTime) -> u64 {
+    if dt.year < 1970 || dt.month < 1 || dt.month > 12 || dt.day < 1 || dt.day > 31 {
+        return 0;
+    }
+    if dt.hour > 23 || dt.minute > 59 || dt.second > 59 {
+        return 0;
+    }
+
+    // Days since 1970-01-01 via the proleptic Gregorian calendar, counting
+    // years from March so the leap day is the last day of the year.
+    let y = if dt.month <= 2 { dt.year - 1 } else { dt.year };
+    let m = if dt.month <= 2 { dt.month + 9 } else { dt.month - 3 };
+    let era = y / 400;
+    let yoe = y - era * 400;
+    let doy = (153 * m + 2) / 5 + dt.day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+
+    days * 86400 + dt.hour * 3600 + dt.minute * 60 + dt.second
+}
+
+pub fn from_unix(ts: u64) -> DateTime {
+    let days = ts / 86400 + 719468;
+    let secs = ts % 86400;
+
+    let era = days / 146097;
+    let doe = days - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    DateTime {
+        year: year,
+        month: month,
+        day: day,
+        hour: secs / 3600,
+        minute: secs % 3600 / 60,
+        second: secs % 60,
+    }
+}
+
+/// Formats a timestamp as `YYYY-MM-DD HH:MM:SS`. A zero timestamp means the
+/// time was never recorded and is shown as `-`.
+pub fn format(ts: u64) -> String {
+    if ts == 0 {
+        return String::from("-");
+    }
+
+    let dt = from_unix(ts);
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}", dt.year, dt.month, dt.day, dt.hour, dt.minute, dt.second)
+}
+
+// -----TESTS-----
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_unix_round_trip() {
+    serial_print!("test_unix_round_trip... ");
+
+    let dt = DateTime { year: 2020, month: 2, day: 29, hour: 13, minute: 37, second: 5 };
+    assert_eq!(to_unix(dt), 1582983425);
+    assert_eq!(from_unix(1582983425), dt);
+
+    assert_eq!(to_unix(DateTime { year: 1970, month: 1, day: 1, hour: 0, minute: 0, second: 0 }), 0);
+    assert_eq!(from_unix(951782400), DateTime { year: 2000, month: 2, day: 29, hour: 0, minute: 0, second: 0 });
+
+    // Out of range fields, as a damaged FAT entry can hold.
+    assert_eq!(to_unix(DateTime { year: 2020, month: 3, day: 0, hour: 0, minute: 0, second: 0 }), 0);
+    assert_eq!(to_unix(DateTime { year: 2020, month: 3, day: 32, hour: 0, minute: 0, second: 0 }), 0);
+    assert_eq!(to_unix(DateTime { year: 2020, month: 3, day: 1, hour: 24, minute: 0, second: 0 }), 0);
+    assert_eq!(to_unix(DateTime { year: 2020, month: 3, day: 1, hour: 0, minute: 60, second: 0 }), 0);
+    assert_eq!(to_unix(DateTime { year: 2020, month: 3, day: 1, hour: 0, minute: 0, second: 62 }), 0);
+
+    serial_println!("[ok]");
+}
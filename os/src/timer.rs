@@ -2,6 +2,9 @@ use crate::io;
 use lazy_static::lazy_static;
 use spin::Mutex;
 
+/// Timer interrupts per second.
+pub const FREQUENCY: usize = 50;
+
 #[derive(Default)]
 pub struct Timer {
     pub ticks: usize,
@@ -2,15 +2,44 @@ use lazy_static::lazy_static;
 use spin::Mutex;
 use alloc::string::{ToString, String};
 use alloc::vec::Vec;
+use alloc::format;
 use core::ptr;
 use crate::wfs;
+use crate::iso9660;
+use crate::ramfs;
+use crate::initrd;
+use crate::fat;
+use crate::ext2;
+use crate::devfs;
+use crate::sysfs;
+use crate::console;
+use crate::allocator;
+use crate::drivers::block;
 use crate::println;
+use bit_field::BitField;
 
 
 pub const ATTR_RO: usize = 0x00;
 pub const ATTR_SYS: usize = 0x01;
 pub const ATTR_DIR: usize = 0x02;
 pub const ATTR_HDN: usize = 0x03;
+pub const ATTR_LNK: usize = 0x04;
+
+/// Longest file name any backend accepts, in bytes of UTF-8.
+pub const NAME_MAX: usize = 255;
+
+/// Symbolic links followed while resolving one path, so a loop ends.
+pub const SYMLINK_MAX: usize = 8;
+
+/// Most bytes one read returns. Files are read into the kernel heap, so a
+/// larger one gives NoSpace and has to be read in parts with read_at.
+pub const READ_MAX: usize = allocator::HEAP_SIZE / 4;
+
+/// Owner id of the system. It may change files of any owner.
+pub const SYSTEM_USER: u8 = 0;
+
+// Owner id of whoever runs commands; new files are theirs.
+static USER: Mutex<u8> = Mutex::new(SYSTEM_USER);
 
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
 pub enum Error {
@@ -24,26 +53,98 @@ pub enum Error {
     DeviceNotFound,
     DuplicateDevice,
     ReadError,
+    AlreadyExists,
+    NoSpace,
+    NameTooLong,
+    InvalidName,
+    TooManyLinks,
+    NotEmpty,
+    Busy,
+    /// A block device (by index) failed; the cause comes from the driver.
+    IoError(usize, block::Error),
+}
+
+impl Error {
+    /// A block device error as seen by a filesystem on device `dev`.
+    /// Writing to a read-only device is denied like writing to a read-only
+    /// file.
+    pub fn io(dev: usize, e: block::Error) -> Error {
+        match e {
+            block::Error::ReadOnly => Error::PermissionDenied,
+            _ => Error::IoError(dev, e),
+        }
+    }
+
+    /// This error for the path a command was given.
+    pub fn at(self, path: &str) -> PathError {
+        PathError { path: String::from(path), error: self }
+    }
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Error::FileNotFound => write!(f, "no such file or directory"),
+            Error::IllegalOperation => write!(f, "operation not allowed on this file"),
+            Error::PermissionDenied => write!(f, "permission denied"),
+            Error::Closed => write!(f, "file is not open"),
+            Error::AlreadyOpened => write!(f, "file is already open"),
+            Error::OperationNotSupported => write!(f, "not supported by this filesystem"),
+            Error::ParentNotDirectory => write!(f, "not a directory"),
+            Error::DeviceNotFound => write!(f, "no such device"),
+            Error::DuplicateDevice => write!(f, "a device with that name is already mounted"),
+            Error::ReadError => write!(f, "damaged or unreadable filesystem data"),
+            Error::AlreadyExists => write!(f, "file already exists"),
+            Error::NoSpace => write!(f, "not enough space"),
+            Error::NameTooLong => write!(f, "name is too long (at most {} bytes)", NAME_MAX),
+            Error::InvalidName => write!(f, "invalid name"),
+            Error::TooManyLinks => write!(f, "too many levels of symbolic links"),
+            Error::NotEmpty => write!(f, "directory is not empty"),
+            Error::Busy => write!(f, "in use"),
+            Error::IoError(dev, e) => match block::get(*dev) {
+                Some(d) => write!(f, "I/O error on {}: {}", d.name, e),
+                None => write!(f, "I/O error: {}", e),
+            },
+        }
+    }
+}
+
+/// An error with the path it happened at, printed as `path: reason`.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct PathError {
+    pub path: String,
+    pub error: Error,
+}
+
+impl core::fmt::Display for PathError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "{}: {}", self.path, self.error)
+    }
 }
 
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
 pub enum System {
     Initrd,
     WFS,
+    ISO9660,
+    RamFS,
+    FAT,
+    Ext2,
+    DevFS,
+    SysFS,
 }
 
 pub struct Device {
-    pub name: [char; 64],
+    pub name: String,
     pub system: System,
     pub index: usize,
     pub opened: Vec<u64>,
 }
 
-#[derive(Clone, Copy)]
-#[repr(C)]
+#[derive(Clone)]
 pub struct FsNode {
     pub open: bool,
-    pub name:   [char; 64],
+    pub name: String,
     pub device: usize,
     pub parent_id: u64,
     pub id: u64,
@@ -55,7 +156,49 @@ pub struct FsNode {
 }
 unsafe impl Send for FsNode {}
 
+/// A child as its directory lists it, without opening it.
+#[derive(Clone)]
+pub struct DirEntry {
+    pub name: String,
+    pub id: u64,
+    pub attributes: u8,
+}
+
 impl FsNode {
+    fn check_owner(&self) -> Result<(), Error> {
+        let user = current_user();
+        if user != SYSTEM_USER && user != self.owner {
+            return Err(Error::PermissionDenied);
+        }
+        Ok(())
+    }
+
+    /// Whether the node itself may be written, renamed or deleted: not
+    /// read-only, not a system file and owned by the current user.
+    pub fn check_change(&self) -> Result<(), Error> {
+        if self.attributes.get_bit(ATTR_RO) || self.attributes.get_bit(ATTR_SYS) {
+            return Err(Error::PermissionDenied);
+        }
+        self.check_owner()
+    }
+
+    /// Whether children may be created in or removed from this directory:
+    /// by any user unless it is read-only, so `A:` and `Home` (owned by the
+    /// system) take everyone's files. Removing a child also needs
+    /// check_change on the child, so users only remove their own files.
+    pub fn check_children(&self) -> Result<(), Error> {
+        if self.attributes.get_bit(ATTR_RO) {
+            return Err(Error::PermissionDenied);
+        }
+        Ok(())
+    }
+
+    // Deleting or renaming a node changes its directory as well.
+    fn check_unlink(&self) -> Result<(), Error> {
+        self.check_change()?;
+        find_node_by_id(self.parent_id, self.device)?.check_children()
+    }
+
     pub fn open(&mut self) -> Result<(), Error> {
         if self.open { return Err(Error::AlreadyOpened); }
 
@@ -94,26 +237,57 @@ impl FsNode {
     pub fn read(&mut self) -> Result<Vec<u8>, Error> {
         if !self.open { return Err(Error::Closed); }
 
-        match DEVICES.lock().get_mut(self.device) {
-            Some(d) => {
-                match d.system {
-                    System::WFS => return wfs::read_node(self.parent_id, sfn(self.name)),
-                    _ => return Err(Error::OperationNotSupported),
-                }
-            }
+        let system = match DEVICES.lock().get(self.device) {
+            Some(d) => d.system,
             None => return Err(Error::DeviceNotFound),
+        };
+        match system {
+            System::WFS => wfs::read_node(self.parent_id, self.name.to_string()),
+            System::ISO9660 => iso9660::read_node(self.id),
+            System::RamFS => ramfs::read_node(self.id, self.device),
+            System::Initrd => initrd::read_node(self.id),
+            System::FAT => fat::read_node(self.parent_id, self.name.to_string(), self.device),
+            System::Ext2 => ext2::read_node(self.id, self.device),
+            System::DevFS => devfs::read_node(self.id),
+            System::SysFS => sysfs::read_node(self.id),
+        }
+    }
+
+    /// Reads up to `len` bytes from `offset`. Filesystems that can't seek
+    /// read the whole file and return the slice.
+    pub fn read_at(&mut self, offset: u64, len: usize) -> Result<Vec<u8>, Error> {
+        if !self.open { return Err(Error::Closed); }
+        let len = len.min(READ_MAX);
+
+        let system = match DEVICES.lock().get(self.device) {
+            Some(d) => d.system,
+            None => return Err(Error::DeviceNotFound),
+        };
+        match system {
+            System::WFS => wfs::read_node_at(self.parent_id, self.name.to_string(), offset, len),
+            System::RamFS => ramfs::read_node_at(self.id, offset, len, self.device),
+            System::DevFS => devfs::read_node_at(self.id, offset, len),
+            System::FAT => fat::read_node_at(self.parent_id, self.name.to_string(), offset, len, self.device),
+            System::ISO9660 => iso9660::read_node_at(self.id, offset, len),
+            _ => {
+                let all = self.read()?;
+                let start = (offset as usize).min(all.len());
+                let end = start.saturating_add(len).min(all.len());
+                Ok(all[start..end].to_vec())
+            },
         }
     }
 
     pub fn write(&mut self, buf: Vec<u8>) -> Result<(), Error> {
         if !self.open { return Err(Error::Closed); }
+        self.check_change()?;
 
         match DEVICES.lock().get_mut(self.device) {
             Some(d) => {
                 match d.system {
                     System::WFS => {
                         let len = buf.len() as u64;
-                        match wfs::write_node(self.parent_id, sfn(self.name), buf) {
+                        match wfs::write_node(self.parent_id, self.name.to_string(), buf) {
                             Ok(_) => {
                                 self.size = len;
                                 Ok(())
@@ -121,6 +295,19 @@ impl FsNode {
                             Err(s) => Err(s),
                         }
                     },
+                    System::RamFS => {
+                        let len = buf.len() as u64;
+                        ramfs::write_node(self.id, buf, self.device)?;
+                        self.size = len;
+                        Ok(())
+                    },
+                    System::FAT => {
+                        let len = buf.len() as u64;
+                        fat::write_node(self.parent_id, self.name.to_string(), buf, self.device)?;
+                        self.size = len;
+                        Ok(())
+                    },
+                    System::DevFS => devfs::write_node(self.id, buf),
                     _ => return Err(Error::OperationNotSupported),
                 }
             }
@@ -130,13 +317,14 @@ impl FsNode {
 
     pub fn append(&mut self, buf: Vec<u8>) -> Result<(), Error> {
         if !self.open { return Err(Error::Closed); }
+        self.check_change()?;
 
         match DEVICES.lock().get_mut(self.device) {
             Some(d) => {
                 match d.system {
                     System::WFS => {
                         let len = self.size + (buf.len() as u64);
-                        match wfs::append_node(self.parent_id, sfn(self.name), buf) {
+                        match wfs::append_node(self.parent_id, self.name.to_string(), buf) {
                             Ok(_) => {
                                 self.size = len;
                                 Ok(())
@@ -144,6 +332,19 @@ impl FsNode {
                             Err(s) => Err(s),
                         }
                     },
+                    System::RamFS => {
+                        let len = self.size + (buf.len() as u64);
+                        ramfs::append_node(self.id, buf, self.device)?;
+                        self.size = len;
+                        Ok(())
+                    },
+                    System::FAT => {
+                        let len = self.size + (buf.len() as u64);
+                        fat::append_node(self.parent_id, self.name.to_string(), buf, self.device)?;
+                        self.size = len;
+                        Ok(())
+                    },
+                    System::DevFS => devfs::append_node(self.id, buf),
                     _ => return Err(Error::OperationNotSupported),
                 }
             },
@@ -153,11 +354,47 @@ impl FsNode {
 
     pub fn delete(&mut self) -> Result<(), Error> {
         if !self.open { return Err(Error::Closed) };
+        self.check_unlink()?;
 
         match DEVICES.lock().get_mut(self.device) {
             Some(d) => {
                 match d.system {
-                    System::WFS => return wfs::delete_node(self.parent_id, sfn(self.name)),
+                    System::WFS => return wfs::delete_node(self.parent_id, self.name.to_string()),
+                    System::RamFS => return ramfs::delete_node(self.id, self.device),
+                    System::FAT => return fat::delete_node(self.parent_id, self.name.to_string(), self.device),
+                    _ => return Err(Error::OperationNotSupported),
+                }
+            },
+            None => return Err(Error::DeviceNotFound),
+        }
+    }
+
+    pub fn rename(&mut self, new_name: String) -> Result<(), Error> {
+        if !self.open { return Err(Error::Closed) };
+        self.check_unlink()?;
+
+        match DEVICES.lock().get_mut(self.device) {
+            Some(d) => {
+                match d.system {
+                    System::WFS => {
+                        match wfs::rename_node(self.parent_id, self.name.to_string(), new_name.to_string()) {
+                            Ok(_) => {
+                                self.name = new_name;
+                                Ok(())
+                            },
+                            Err(s) => Err(s),
+                        }
+                    },
+                    System::RamFS => {
+                        ramfs::rename_node(self.id, new_name.to_string(), self.device)?;
+                        self.name = new_name;
+                        Ok(())
+                    },
+                    System::FAT => {
+                        fat::rename_node(self.parent_id, self.name.to_string(), new_name.to_string(), self.device)?;
+                        self.name = new_name;
+                        Ok(())
+                    },
                     _ => return Err(Error::OperationNotSupported),
                 }
             },
@@ -169,13 +406,76 @@ impl FsNode {
         match DEVICES.lock().get_mut(self.device) {
             Some(d) => {
                 match d.system {
-                    System::WFS => return wfs::get_children(self.parent_id, sfn(self.name), self.device),
+                    System::WFS => return wfs::get_children(self.parent_id, self.name.to_string(), self.device),
+                    System::ISO9660 => return iso9660::get_children(self.id, self.device),
+                    System::RamFS => return ramfs::get_children(self.id, self.device),
+                    System::Initrd => return initrd::get_children(self.id, self.device),
+                    System::FAT => return fat::get_children(self.id, self.device),
+                    System::Ext2 => return ext2::get_children(self.id, self.device),
+                    System::DevFS => return devfs::get_children(self.id, self.device),
+                    System::SysFS => return sysfs::get_children(self.id, self.device),
                     _ => return Err(Error::OperationNotSupported), 
                 }
             },
             None => return Err(Error::DeviceNotFound),
         }
     }
+
+    /// Sets the read-only, system and hidden bits; whether the node is a
+    /// directory can't change. Only the system may set or clear ATTR_SYS.
+    pub fn set_attributes(&mut self, attributes: u8) -> Result<(), Error> {
+        self.check_owner()?;
+        if attributes.get_bit(ATTR_SYS) != self.attributes.get_bit(ATTR_SYS) && current_user() != SYSTEM_USER {
+            return Err(Error::PermissionDenied);
+        }
+        let mut attributes = attributes;
+        attributes.set_bit(ATTR_DIR, self.attributes.get_bit(ATTR_DIR));
+
+        let system = match DEVICES.lock().get(self.device) {
+            Some(d) => d.system,
+            None => return Err(Error::DeviceNotFound),
+        };
+        match system {
+            System::WFS => wfs::set_attributes(self.parent_id, self.name.to_string(), attributes)?,
+            System::RamFS => ramfs::set_attributes(self.id, attributes, self.device)?,
+            System::FAT => fat::set_attributes(self.parent_id, self.name.to_string(), attributes, self.device)?,
+            _ => return Err(Error::OperationNotSupported),
+        }
+        self.attributes = attributes;
+        Ok(())
+    }
+
+    /// The path a symbolic link points to.
+    pub fn read_link(&self) -> Result<String, Error> {
+        if !self.attributes.get_bit(ATTR_LNK) {
+            return Err(Error::IllegalOperation);
+        }
+        let system = match DEVICES.lock().get(self.device) {
+            Some(d) => d.system,
+            None => return Err(Error::DeviceNotFound),
+        };
+        let buf = match system {
+            System::WFS => wfs::read_node(self.parent_id, self.name.to_string())?,
+            System::RamFS => ramfs::read_node(self.id, self.device)?,
+            System::Initrd => initrd::read_node(self.id)?,
+            System::Ext2 => ext2::read_node(self.id, self.device)?,
+            _ => return Err(Error::OperationNotSupported),
+        };
+        String::from_utf8(buf).map_err(|_| Error::InvalidName)
+    }
+
+    /// Names, ids and attributes of the children. Cheaper than get_children
+    /// where the directory itself holds them.
+    pub fn list(&mut self) -> Result<Vec<DirEntry>, Error> {
+        let system = match DEVICES.lock().get(self.device) {
+            Some(d) => d.system,
+            None => return Err(Error::DeviceNotFound),
+        };
+        match system {
+            System::WFS => wfs::list_node(self.parent_id, self.name.to_string()),
+            _ => Ok(self.get_children()?.into_iter().map(|c| DirEntry { name: c.name, id: c.id, attributes: c.attributes }).collect()),
+        }
+    }
 }
 
 lazy_static! {
@@ -184,14 +484,14 @@ lazy_static! {
 
 pub fn install_device(name: String, system: System) -> Result<usize, Error> {
     for d in DEVICES.lock().iter() {
-        if name == sfn(d.name) {
+        if name == d.name {
             return Err(Error::DuplicateDevice);
         }
     }
 
     let s = DEVICES.lock().len();
     DEVICES.lock().push(Device {
-        name: nfs(name),
+        name: name,
         system: system,
         index: s,
         opened: Vec::new(),
@@ -205,6 +505,13 @@ pub fn find_node_by_id(id: u64, dev_id: usize) -> Result<FsNode, Error> {
         Some(d) => {
             match d.system {
                 System::WFS => return wfs::find_node_by_id(id, dev_id),
+                System::ISO9660 => return iso9660::find_node_by_id(id, dev_id),
+                System::RamFS => return ramfs::find_node_by_id(id, dev_id),
+                System::Initrd => return initrd::find_node_by_id(id, dev_id),
+                System::FAT => return fat::find_node_by_id(id, dev_id),
+                System::Ext2 => return ext2::find_node_by_id(id, dev_id),
+                System::DevFS => return devfs::find_node_by_id(id, dev_id),
+                System::SysFS => return sysfs::find_node_by_id(id, dev_id),
                 _ => return Err(Error::OperationNotSupported),
             }
         },
@@ -217,6 +524,13 @@ pub fn find_node(parent_id: u64, name: String, dev_id: usize) -> Result<FsNode,
         Some(d) => {
             match d.system {
                 System::WFS => return wfs::find_node(parent_id, name, dev_id),
+                System::ISO9660 => return iso9660::find_node(parent_id, name, dev_id),
+                System::RamFS => return ramfs::find_node(parent_id, name, dev_id),
+                System::Initrd => return initrd::find_node(parent_id, name, dev_id),
+                System::FAT => return fat::find_node(parent_id, name, dev_id),
+                System::Ext2 => return ext2::find_node(parent_id, name, dev_id),
+                System::DevFS => return devfs::find_node(parent_id, name, dev_id),
+                System::SysFS => return sysfs::find_node(parent_id, name, dev_id),
                 _ => return Err(Error::OperationNotSupported),
             }
         },
@@ -225,10 +539,14 @@ pub fn find_node(parent_id: u64, name: String, dev_id: usize) -> Result<FsNode,
 }
 
 pub fn create_node(parent_id: u64, filename: String, attributes: u8, owner: u8, dev_id: usize) -> Result<FsNode, Error> {
+    find_node_by_id(parent_id, dev_id)?.check_children()?;
+
     match DEVICES.lock().get_mut(dev_id) {
         Some(d) => {
             match d.system {
                 System::WFS => return wfs::create_node(parent_id, filename, attributes, owner, dev_id),
+                System::RamFS => return ramfs::create_node(parent_id, filename, attributes, owner, dev_id),
+                System::FAT => return fat::create_node(parent_id, filename, attributes, owner, dev_id),
                 _ => return Err(Error::OperationNotSupported),
             }
         },
@@ -241,6 +559,13 @@ pub fn get_root(dev_id: usize) -> Result<FsNode, Error> {
         Some(d) => {
             match d.system {
                 System::WFS => return wfs::get_root(dev_id),
+                System::ISO9660 => return iso9660::get_root(dev_id),
+                System::RamFS => return ramfs::get_root(dev_id),
+                System::Initrd => return initrd::get_root(dev_id),
+                System::FAT => return fat::get_root(dev_id),
+                System::Ext2 => return ext2::get_root(dev_id),
+                System::DevFS => return devfs::get_root(dev_id),
+                System::SysFS => return sysfs::get_root(dev_id),
                 _ => return Err(Error::OperationNotSupported),
             }
         },
@@ -253,6 +578,13 @@ pub fn get_parent(id: u64, dev_id: usize) -> Result<FsNode, Error> {
         Some(d) => {
             match d.system {
                 System::WFS => return wfs::get_parent(id, dev_id),
+                System::ISO9660 => return iso9660::get_parent(id, dev_id),
+                System::RamFS => return ramfs::get_parent(id, dev_id),
+                System::Initrd => return initrd::get_parent(id, dev_id),
+                System::FAT => return fat::get_parent(id, dev_id),
+                System::Ext2 => return ext2::get_parent(id, dev_id),
+                System::DevFS => return devfs::get_parent(id, dev_id),
+                System::SysFS => return sysfs::get_parent(id, dev_id),
                 _ => return Err(Error::OperationNotSupported),
             }
         },
@@ -260,103 +592,168 @@ pub fn get_parent(id: u64, dev_id: usize) -> Result<FsNode, Error> {
     }
 }
 
-pub fn node_from_local_path(p: &FsNode, pa: String) -> Result<FsNode, Error> {
-    let mut path = pa;
-   
-    let pchars: Vec<char> = path.chars().collect();
-    if pchars[pchars.len() - 1] == '/' {
-        path.pop();
+/// Creates `name` in the directory `dir` as another name of the file
+/// `target`. Both must be on the same device.
+pub fn create_link(dir: &FsNode, name: String, target: &FsNode) -> Result<(), Error> {
+    if dir.device != target.device {
+        return Err(Error::IllegalOperation);
     }
+    dir.check_children()?;
 
-    let mut names: Vec<&str> = path.split("/").collect();
-    let dev_id = (*p).device;
-
-    let goal = names[names.len() - 1];
+    let system = match DEVICES.lock().get(dir.device) {
+        Some(d) => d.system,
+        None => return Err(Error::DeviceNotFound),
+    };
+    match system {
+        System::WFS => wfs::link_node(target.parent_id, target.name.to_string(), dir.id, name),
+        _ => Err(Error::OperationNotSupported),
+    }
+}
 
-    let mut parent = *p;
+/// Creates a symbolic link to `target`, a path resolved when the link is
+/// followed: from the device root if it starts with a device name,
+/// otherwise from the directory holding the link.
+pub fn create_symlink(dir: &FsNode, name: String, target: String, owner: u8) -> Result<FsNode, Error> {
+    dir.check_children()?;
 
-    let mut i = 0;
-    loop {
-        if i >= names.len() {
-            break;
-        }
+    let system = match DEVICES.lock().get(dir.device) {
+        Some(d) => d.system,
+        None => return Err(Error::DeviceNotFound),
+    };
+    match system {
+        System::WFS => wfs::create_symlink(dir.id, name, target, owner, dir.device),
+        System::RamFS => ramfs::create_symlink(dir.id, name, target, owner, dir.device),
+        _ => Err(Error::OperationNotSupported),
+    }
+}
 
-        if names[i] == ".." {
-            parent = get_parent(parent.id, dev_id)?;
-            if i == names.len() - 1 {
-                return Ok(parent);
-            } 
+/// Deletes `node`. A directory must be empty unless `recursive`, which
+/// deletes everything below it first, deepest first; symbolic links are
+/// deleted, not followed. The root of a device, the current directory and
+/// anything above it and open files are refused with Busy.
+pub fn remove(node: &FsNode, recursive: bool) -> Result<(), Error> {
+    if node.id == get_root(node.device)?.id || holds_cdir(node)? {
+        return Err(Error::Busy);
+    }
 
-            i += 1;
-            continue;
+    if node.attributes.get_bit(ATTR_DIR) && recursive {
+        let mut dir = node.clone();
+        for c in dir.get_children()? {
+            remove(&c, true)?;
         }
+    }
 
-        let node = find_node(parent.id, names[i].to_string(), dev_id)?;
+    let mut n = node.clone();
+    if n.open().is_err() {
+        return Err(Error::Busy);
+    }
+    let res = n.delete();
+    n.close()?;
+    res
+}
 
-        if &sfn(node.name) == goal {
-            return Ok(node);
+// Whether the current directory is `node` or somewhere below it.
+fn holds_cdir(node: &FsNode) -> Result<bool, Error> {
+    let mut d = console::get_cdir();
+    if d.device != node.device {
+        return Ok(false);
+    }
+    loop {
+        if d.id == node.id {
+            return Ok(true);
         }
-        
-        parent = node;
-        i += 1;
+        let p = get_parent(d.id, d.device)?;
+        if p.id == d.id {
+            return Ok(false);
+        }
+        d = p;
     }
-    return Err(Error::FileNotFound);
 }
 
-pub fn node_from_path(path: String) -> Result<FsNode, Error> {
-    let mut names: Vec<&str> = path.split("/").collect();
-    let mut dev_id = 0;
-   
+pub fn current_user() -> u8 {
+    *USER.lock()
+}
+
+pub fn set_user(user: u8) {
+    *USER.lock() = user;
+}
+
+pub fn find_device(name: &str) -> Option<usize> {
     for d in DEVICES.lock().iter() {
-        if sfn(d.name) == names[0] {
-            dev_id = d.index;
+        if d.name == name {
+            return Some(d.index);
         }
     }
-    names.remove(0);
+    None
+}
 
-    let goal = names[names.len() - 1];
-    let mut parent = get_root(dev_id)?;
-    
-    let mut i = 0;
-    loop {
-        if i >= names.len() {
-            break;
-        }
+/// The first drive letter from C: that no device is mounted as.
+pub fn free_letter() -> Option<String> {
+    (b'C'..=b'Z').map(|c| format!("{}:", c as char)).find(|n| find_device(n).is_none())
+}
 
-        let node = find_node(parent.id, names[i].to_string(), dev_id)?;
-        
-        if &sfn(node.name) == goal {
-            return Ok(node);
-        }
-        
-        parent = node;
-        i += 1;
-    }
+/// Resolves `pa` from the directory `p`, following symbolic links.
+pub fn node_from_local_path(p: &FsNode, pa: String) -> Result<FsNode, Error> {
+    resolve(p, &pa, true, &mut 0)
+}
 
-    return Err(Error::FileNotFound);
+/// Like node_from_local_path, but a symbolic link at the end of the path is
+/// returned itself, for deleting or renaming it.
+pub fn link_from_local_path(p: &FsNode, pa: String) -> Result<FsNode, Error> {
+    resolve(p, &pa, false, &mut 0)
 }
 
-pub fn nfs(name: String) -> [char; 64] {
-    filename_from_slice(name.as_bytes())
+pub fn node_from_path(path: String) -> Result<FsNode, Error> {
+    let names: Vec<&str> = path.split("/").collect();
+    let dev_id = find_device(names[0]).ok_or(Error::DeviceNotFound)?;
+
+    walk_path(get_root(dev_id)?, &names[1..], true, &mut 0)
 }
 
-pub fn filename_from_slice(slice: &[u8]) -> [char; 64] {
-    let mut res: [char; 64] = [' '; 64];
-    let mut i = 0;
-    for b in slice {
-        if i >= 64 || *b as char == ' ' { break; }
-        res[i] = *b as char;
-        i += 1;
+fn resolve(p: &FsNode, pa: &str, follow: bool, hops: &mut usize) -> Result<FsNode, Error> {
+    let names: Vec<&str> = pa.split("/").collect();
+
+    // Paths starting with a device name (`D:/docs`) are absolute.
+    match find_device(names[0]) {
+        Some(dev_id) => walk_path(get_root(dev_id)?, &names[1..], follow, hops),
+        None => walk_path(p.clone(), &names, follow, hops),
     }
-    return res;
 }
 
-pub fn sfn(filename: [char; 64]) -> String {
-    let mut res = String::from("");
-    for c in filename.iter() {
-        if *c == ' ' { break; }
-        res.push(*c);
+// Links are followed on the way; the last name only with `follow`. `hops`
+// counts the links followed for the whole path.
+fn walk_path(start: FsNode, names: &[&str], follow: bool, hops: &mut usize) -> Result<FsNode, Error> {
+    let mut node = start;
+
+    for (i, name) in names.iter().enumerate() {
+        let dir = node.clone();
+        match *name {
+            "" | "." => continue,
+            ".." => node = get_parent(node.id, node.device)?,
+            n => node = find_node(node.id, n.to_string(), node.device)?,
+        }
+
+        let last = i + 1 == names.len();
+        if node.attributes.get_bit(ATTR_LNK) && (follow || !last) {
+            *hops += 1;
+            if *hops > SYMLINK_MAX {
+                return Err(Error::TooManyLinks);
+            }
+            node = resolve(&dir, &node.read_link()?, true, hops)?;
+        }
     }
-    return res;
+
+    Ok(node)
 }
 
+/// Rejects names no backend can store: empty, `.`/`..`, containing `/` or
+/// NUL, or longer than NAME_MAX bytes.
+pub fn check_name(name: &str) -> Result<(), Error> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') || name.contains('\0') {
+        return Err(Error::InvalidName);
+    }
+    if name.len() > NAME_MAX {
+        return Err(Error::NameTooLong);
+    }
+    Ok(())
+}
@@ -3,6 +3,7 @@ use core::fmt;
 use lazy_static::lazy_static;
 use spin::Mutex;
 use crate::io;
+use alloc::string::String;
 
 #[macro_export]
 macro_rules! print {
@@ -207,6 +208,22 @@ pub fn set_color(fg: Color, bg: Color) {
     WRITER.lock().color_code = ColorCode::new(fg, bg);
 }
 
+/// The text on the screen, a line per row without the trailing blanks.
+pub fn screen_text() -> String {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let writer = WRITER.lock();
+        let mut s = String::new();
+        for row in writer.buffer.chars.iter() {
+            let line: String = row.iter().map(|c| c.read().ascii_character as char).collect();
+            s.push_str(line.trim_end());
+            s.push('\n');
+        }
+        s
+    })
+}
+
 // -----TESTS-----
 
 #[cfg(test)]
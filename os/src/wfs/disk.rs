@@ -0,0 +1,1560 @@
+//On-disk structures of wFS, see ../../wfs_spec.txt.
+//This file is also compiled into the host tool (wfs-tool), so it may only use core and alloc.
+
+use core::convert::TryInto;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+pub const SECTOR_SIZE: usize = 512;
+
+pub const FREE: u64 = 0x00000000_00000000;
+pub const RESERVED: u64 = 0xFFFFFFFF_FFFFFFF0;
+pub const END_OF_CHAIN: u64 = 0xFFFFFFFF_FFFFFFFF;
+
+pub const DATA_SIG: [u8; 4] = [b'D', b'A', b'T', b'A'];
+pub const WFS_SIG: [u8; 8] = [b'_', b'W', b'F', b'S', b'_', b'S', b'I', b'G'];
+pub const JOURNAL_DESC_SIG: [u8; 4] = [b'W', b'J', b'D', b'S'];
+pub const JOURNAL_COMMIT_SIG: [u8; 4] = [b'W', b'J', b'C', b'M'];
+pub const EXTENT_SIG: [u8; 4] = [b'W', b'E', b'X', b'T'];
+pub const DIR_SIG: [u8; 4] = [b'W', b'D', b'I', b'R'];
+
+// Blocks are whole sectors, from one sector up to 64 KiB. Entries and the
+// InfoBlock use the first sector of their block; data blocks carry a 12 byte
+// header and the rest is payload.
+pub const MIN_BLOCK_SIZE: u64 = 512;
+pub const MAX_BLOCK_SIZE: u64 = 65536;
+pub const DATA_HEADER: usize = 12;
+pub const DEFAULT_BLOCK_SIZE: u64 = 4096;
+
+// Since version 3 file data lives in extents: runs of whole blocks without a
+// header. An entry holds the first INLINE_EXTENTS itself, the rest go to a
+// chain of extent blocks.
+pub const EXTENT_SIZE: usize = 12;
+pub const INLINE_EXTENTS: usize = 14;
+pub const EXTENT_HEADER: usize = 16;
+
+// Since version 4 a directory's data is a header and one record per child.
+// Once the records no longer fit one block they are grouped into hash
+// buckets, with a table of where each bucket starts after the header.
+pub const DIR_HEADER: usize = 12;
+pub const DIR_RECORD: usize = 18;
+
+// InfoBlock byte 0. A volume found in the mounted state at boot was not
+// unmounted cleanly and is checked before use.
+// While an upgrade rewrites the entries the volume holds both layouts; the
+// upgrade is finished at the next mount.
+pub const STATE_CLEAN: u8 = 0;
+pub const STATE_MOUNTED: u8 = 1;
+pub const STATE_UPGRADING: u8 = 2;
+
+// The journal sits in the last JOURNAL_LEN sectors of the volume. A transaction
+// is written there as descriptor sectors (each naming up to LBAS_PER_DESC home
+// sectors) followed by the sector images, then a commit sector.
+pub const JOURNAL_LEN: u64 = 128;
+pub const LBAS_PER_DESC: usize = 60;
+
+// Attribute bits, same as vfs::ATTR_*.
+pub const ATTR_RO: usize = 0;
+pub const ATTR_SYS: usize = 1;
+pub const ATTR_DIR: usize = 2;
+pub const ATTR_HDN: usize = 3;
+pub const ATTR_LNK: usize = 4;
+
+/// Longest name, in bytes of UTF-8.
+pub const NAME_MAX: usize = 255;
+
+/// Version 1 entries hold up to 64 chars, padded with spaces.
+pub const NAME_LEN_V1: usize = 64;
+
+/// On-disk format version written by this code. Volumes made before the
+/// version field existed read as 0 there and are treated as version 1.
+///
+/// 1: names are [char; 64] padded with spaces
+/// 2: names are length-prefixed UTF-8 of up to NAME_MAX bytes
+/// 3: file data in extents, free blocks in a bitmap
+/// 4: directories hold name, id, location and attributes of their children
+/// 5: entries count their hard links
+pub const FORMAT_VERSION: u32 = 5;
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum DecodeError {
+    BadSignature,
+    UnsupportedVersion(u32),
+    BadName,
+    OutOfRange(&'static str),
+}
+
+// Little-endian field access at fixed offsets.
+fn get_u32(sec: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes(sec[off..off + 4].try_into().expect(""))
+}
+
+fn get_u64(sec: &[u8], off: usize) -> u64 {
+    u64::from_le_bytes(sec[off..off + 8].try_into().expect(""))
+}
+
+fn put_u32(sec: &mut [u8], off: usize, v: u32) {
+    sec[off..off + 4].copy_from_slice(&v.to_le_bytes());
+}
+
+fn put_u64(sec: &mut [u8], off: usize, v: u64) {
+    sec[off..off + 8].copy_from_slice(&v.to_le_bytes());
+}
+
+/// Sector 0 of a volume.
+///
+/// ```text
+///  0      u8        state
+///  1..9   [u8; 8]   _WFS_SIG
+///  9..17  u64       blocks
+/// 17..25  u64       blocks in use
+/// 25..33  u64       files
+/// 33..41  u64       bytes per block
+/// 41..49  u64       final entry
+/// 49..57  u64       journal start
+/// 57..65  u64       journal length
+/// 65..69  u32       format version
+/// 69..77  u64       bitmap start (version 3)
+/// 77..85  u64       bitmap length in blocks (version 3)
+/// ```
+#[derive(Debug, Default, Eq, PartialEq, Copy, Clone)]
+pub struct InfoBlock {
+    pub state: u8,
+    pub signature: [u8; 8],
+    pub blocks: u64,
+    pub blocks_in_use: u64,
+    pub files: u64,
+    pub bytes_per_block: u64,
+    pub final_entry: u64,
+    pub journal_start: u64,
+    pub journal_len: u64,
+    pub version: u32,
+    pub bitmap_start: u64,
+    pub bitmap_len: u64,
+}
+
+impl InfoBlock {
+    pub fn decode(sec: &[u8; SECTOR_SIZE]) -> Result<InfoBlock, DecodeError> {
+        let mut info = InfoBlock {
+            state: sec[0],
+            signature: sec[1..9].try_into().expect(""),
+            blocks: get_u64(sec, 9),
+            blocks_in_use: get_u64(sec, 17),
+            files: get_u64(sec, 25),
+            bytes_per_block: get_u64(sec, 33),
+            final_entry: get_u64(sec, 41),
+            journal_start: get_u64(sec, 49),
+            journal_len: get_u64(sec, 57),
+            version: get_u32(sec, 65),
+            bitmap_start: get_u64(sec, 69),
+            bitmap_len: get_u64(sec, 77),
+        };
+        if info.version == 0 {
+            info.version = 1;
+        }
+
+        info.validate()?;
+        Ok(info)
+    }
+
+    pub fn encode(&self) -> [u8; SECTOR_SIZE] {
+        let mut sec = [0u8; SECTOR_SIZE];
+        sec[0] = self.state;
+        sec[1..9].copy_from_slice(&self.signature);
+        put_u64(&mut sec, 9, self.blocks);
+        put_u64(&mut sec, 17, self.blocks_in_use);
+        put_u64(&mut sec, 25, self.files);
+        put_u64(&mut sec, 33, self.bytes_per_block);
+        put_u64(&mut sec, 41, self.final_entry);
+        put_u64(&mut sec, 49, self.journal_start);
+        put_u64(&mut sec, 57, self.journal_len);
+        put_u32(&mut sec, 65, self.version);
+        put_u64(&mut sec, 69, self.bitmap_start);
+        put_u64(&mut sec, 77, self.bitmap_len);
+        sec
+    }
+
+    pub fn sectors_per_block(&self) -> u64 {
+        self.bytes_per_block / SECTOR_SIZE as u64
+    }
+
+    pub fn validate(&self) -> Result<(), DecodeError> {
+        if self.signature != WFS_SIG {
+            return Err(DecodeError::BadSignature);
+        }
+        if self.version > FORMAT_VERSION {
+            return Err(DecodeError::UnsupportedVersion(self.version));
+        }
+        if self.state > STATE_UPGRADING {
+            return Err(DecodeError::OutOfRange("state"));
+        }
+        if self.bytes_per_block < MIN_BLOCK_SIZE || self.bytes_per_block > MAX_BLOCK_SIZE || !self.bytes_per_block.is_power_of_two() {
+            return Err(DecodeError::OutOfRange("bytes per block"));
+        }
+        if self.blocks < 2 {
+            return Err(DecodeError::OutOfRange("blocks"));
+        }
+        if self.blocks_in_use > self.blocks {
+            return Err(DecodeError::OutOfRange("blocks in use"));
+        }
+        if self.final_entry == 0 || self.final_entry >= self.blocks {
+            return Err(DecodeError::OutOfRange("final entry"));
+        }
+        if self.journal_len > 0 && (self.journal_start < 2 || self.journal_start + self.journal_len > self.blocks) {
+            return Err(DecodeError::OutOfRange("journal"));
+        }
+        if self.version >= 3 && (self.bitmap_start < 2 || self.bitmap_len < bitmap_blocks(self.blocks, self.bytes_per_block)
+            || self.bitmap_start + self.bitmap_len > self.blocks) {
+            return Err(DecodeError::OutOfRange("bitmap"));
+        }
+        Ok(())
+    }
+}
+
+/// A file name: up to NAME_MAX bytes of UTF-8, kept inline so entries stay Copy.
+#[derive(Copy, Clone)]
+pub struct Name {
+    len: u8,
+    bytes: [u8; NAME_MAX],
+}
+
+impl Name {
+    pub fn new(s: &str) -> Result<Name, DecodeError> {
+        if s.len() > NAME_MAX {
+            return Err(DecodeError::BadName);
+        }
+
+        let mut n = Name { len: s.len() as u8, bytes: [0; NAME_MAX] };
+        n.bytes[..s.len()].copy_from_slice(s.as_bytes());
+        Ok(n)
+    }
+
+    fn from_bytes(b: &[u8]) -> Result<Name, DecodeError> {
+        match core::str::from_utf8(b) {
+            Ok(s) => Name::new(s),
+            Err(_) => Err(DecodeError::BadName),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.bytes[..self.len as usize]).unwrap_or("")
+    }
+
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Default for Name {
+    fn default() -> Name {
+        Name { len: 0, bytes: [0; NAME_MAX] }
+    }
+}
+
+impl PartialEq for Name {
+    fn eq(&self, other: &Name) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+
+impl Eq for Name {}
+
+impl core::fmt::Debug for Name {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+/// Whether `name` can be stored in an entry of the given format version.
+/// Version 1 names end at the first space.
+pub fn name_fits(name: &str, version: u32) -> bool {
+    if version == 1 {
+        name.chars().count() <= NAME_LEN_V1 && !name.contains(' ')
+    } else {
+        name.len() <= NAME_MAX
+    }
+}
+
+/// A file or directory entry, one per sector. Version 2 layout:
+///
+/// ```text
+///  0..4    DATA
+///  4..12   u64   parent id
+/// 12..20   u64   id
+/// 20       u8    attributes
+/// 21..29   u64   time of creation
+/// 29..37   u64   time of last edit
+/// 37       u8    owner
+/// 38..46   u64   size
+/// 46..54   u64   first data sector
+/// 54..62   u64   next entry
+/// 62..70   u64   previous entry
+/// 70..78   u64   location
+/// 78..80   u16   name length in bytes
+/// 80..     name, UTF-8
+/// ```
+///
+/// Version 3 adds the extents after room for the longest name:
+///
+/// ```text
+/// 336..340 u32   extent count
+/// 340..508 [Extent; 14]
+/// ```
+///
+/// Version 5 adds the number of directory records naming the entry. Older
+/// entries have exactly one, and a stored 0 reads as 1 too:
+///
+/// ```text
+/// 508..512 u32   links
+/// ```
+///
+/// With extents, "first data sector" is the first extent block holding the
+/// extents past the inline ones. Without, it is the first block of a version
+/// 2 data chain, which version 3 still reads.
+///
+/// Version 1 has the name first, as 64 u32 chars at 4..260, and the
+/// remaining fields in the same order from 260.
+#[derive(Debug, Default, Eq, PartialEq, Clone, Copy)]
+pub struct FileEntry {
+    pub signature: [u8; 4],
+    pub name: Name,
+    pub parent_id: u64,
+    pub id: u64,
+    pub attributes: u8,
+    pub t_creation: u64,
+    pub t_edit: u64,
+    pub owner: u8,
+    pub size: u64,
+    pub start_sec: u64,
+    pub next_entry: u64,
+    pub prev_entry: u64,
+    pub location: u64,
+    pub extent_count: u32,
+    pub extents: [Extent; INLINE_EXTENTS],
+    pub links: u32,
+}
+
+const V1_NAME: usize = 4;
+const V1_FIELDS: usize = V1_NAME + NAME_LEN_V1 * 4;
+const V2_FIELDS: usize = 4;
+const V2_NAME_LEN: usize = 78;
+const V2_NAME: usize = 80;
+const V3_EXTENT_COUNT: usize = 336;
+const V3_EXTENTS: usize = 340;
+const V5_LINKS: usize = 508;
+
+impl FileEntry {
+    pub fn decode(sec: &[u8; SECTOR_SIZE], version: u32) -> Result<FileEntry, DecodeError> {
+        if sec[0..4] != DATA_SIG {
+            return Err(DecodeError::BadSignature);
+        }
+
+        let (name, f) = if version == 1 {
+            let mut buf = [0u8; NAME_LEN_V1 * 4];
+            let mut len = 0;
+            for i in 0..NAME_LEN_V1 {
+                let c = core::char::from_u32(get_u32(sec, V1_NAME + i * 4)).ok_or(DecodeError::BadName)?;
+                if c == ' ' {
+                    break;
+                }
+                len += c.encode_utf8(&mut buf[len..]).len();
+            }
+            (Name::from_bytes(&buf[..len])?, V1_FIELDS)
+        } else {
+            let len = u16::from_le_bytes(sec[V2_NAME_LEN..V2_NAME_LEN + 2].try_into().expect("")) as usize;
+            if len > NAME_MAX {
+                return Err(DecodeError::BadName);
+            }
+            (Name::from_bytes(&sec[V2_NAME..V2_NAME + len])?, V2_FIELDS)
+        };
+
+        let mut extents = [Extent::default(); INLINE_EXTENTS];
+        let extent_count = if version >= 3 { get_u32(sec, V3_EXTENT_COUNT) } else { 0 };
+        for (i, x) in extents.iter_mut().enumerate().take(extent_count as usize) {
+            *x = Extent::decode(sec, V3_EXTENTS + i * EXTENT_SIZE);
+        }
+
+        Ok(FileEntry {
+            signature: DATA_SIG,
+            name,
+            parent_id: get_u64(sec, f),
+            id: get_u64(sec, f + 8),
+            attributes: sec[f + 16],
+            t_creation: get_u64(sec, f + 17),
+            t_edit: get_u64(sec, f + 25),
+            owner: sec[f + 33],
+            size: get_u64(sec, f + 34),
+            start_sec: get_u64(sec, f + 42),
+            next_entry: get_u64(sec, f + 50),
+            prev_entry: get_u64(sec, f + 58),
+            location: get_u64(sec, f + 66),
+            extent_count,
+            extents,
+            links: if version >= 5 { get_u32(sec, V5_LINKS).max(1) } else { 1 },
+        })
+    }
+
+    /// Names that don't fit a version 1 entry are cut short; check them
+    /// with `name_fits` first.
+    pub fn encode(&self, version: u32) -> [u8; SECTOR_SIZE] {
+        let mut sec = [0u8; SECTOR_SIZE];
+        sec[0..4].copy_from_slice(&self.signature);
+
+        let f = if version == 1 {
+            let mut chars = self.name.as_str().chars();
+            for i in 0..NAME_LEN_V1 {
+                put_u32(&mut sec, V1_NAME + i * 4, chars.next().unwrap_or(' ') as u32);
+            }
+            V1_FIELDS
+        } else {
+            let name = self.name.as_str().as_bytes();
+            sec[V2_NAME_LEN..V2_NAME_LEN + 2].copy_from_slice(&(name.len() as u16).to_le_bytes());
+            sec[V2_NAME..V2_NAME + name.len()].copy_from_slice(name);
+            V2_FIELDS
+        };
+
+        put_u64(&mut sec, f, self.parent_id);
+        put_u64(&mut sec, f + 8, self.id);
+        sec[f + 16] = self.attributes;
+        put_u64(&mut sec, f + 17, self.t_creation);
+        put_u64(&mut sec, f + 25, self.t_edit);
+        sec[f + 33] = self.owner;
+        put_u64(&mut sec, f + 34, self.size);
+        put_u64(&mut sec, f + 42, self.start_sec);
+        put_u64(&mut sec, f + 50, self.next_entry);
+        put_u64(&mut sec, f + 58, self.prev_entry);
+        put_u64(&mut sec, f + 66, self.location);
+
+        if version >= 3 {
+            put_u32(&mut sec, V3_EXTENT_COUNT, self.extent_count);
+            for (i, x) in self.extents.iter().enumerate().take(self.inline_extents()) {
+                x.encode_into(&mut sec, V3_EXTENTS + i * EXTENT_SIZE);
+            }
+        }
+        if version >= 5 {
+            put_u32(&mut sec, V5_LINKS, self.links);
+        }
+        sec
+    }
+
+    /// Tells which layout the entry at `lba` was written in, by finding its
+    /// own location in one of them. Used while a volume holds both. Versions
+    /// 2 and later share the layout and all read as 2; the fields added since
+    /// are zero in older entries, so those decode with the newest version.
+    pub fn layout_version(sec: &[u8; SECTOR_SIZE], lba: u64) -> Option<u32> {
+        if get_u64(sec, V2_FIELDS + 66) == lba && FileEntry::decode(sec, 2).is_ok() {
+            Some(2)
+        } else if get_u64(sec, V1_FIELDS + 66) == lba && FileEntry::decode(sec, 1).is_ok() {
+            Some(1)
+        } else {
+            None
+        }
+    }
+
+    /// Checks the sector pointers against the size of the volume.
+    pub fn validate(&self, blocks: u64) -> Result<(), DecodeError> {
+        let link = |l: u64| l == END_OF_CHAIN || l < blocks;
+        if self.location == 0 || self.location >= blocks {
+            return Err(DecodeError::OutOfRange("location"));
+        }
+        if !link(self.next_entry) || !link(self.prev_entry) {
+            return Err(DecodeError::OutOfRange("entry link"));
+        }
+        if !link(self.start_sec) && self.start_sec != RESERVED {
+            return Err(DecodeError::OutOfRange("first data sector"));
+        }
+        for x in self.extents.iter().take(self.inline_extents()) {
+            if !x.within(blocks) {
+                return Err(DecodeError::OutOfRange("extent"));
+            }
+        }
+        Ok(())
+    }
+
+    /// How many of the extents are held in the entry itself.
+    pub fn inline_extents(&self) -> usize {
+        (self.extent_count as usize).min(INLINE_EXTENTS)
+    }
+
+    /// Whether the data is a version 2 chain of data blocks.
+    pub fn uses_chain(&self) -> bool {
+        self.extent_count == 0 && self.start_sec != END_OF_CHAIN && self.start_sec != FREE
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.attributes & (1 << ATTR_DIR) != 0
+    }
+}
+
+/// Header of a data block, followed by the payload up to the end of the block.
+///
+/// ```text
+///  0..4    DATA
+///  4..12   u64        next block
+/// 12..     data
+/// ```
+pub struct DataHeader {
+    pub next_sec: u64,
+}
+
+impl DataHeader {
+    pub fn decode(block: &[u8]) -> Result<DataHeader, DecodeError> {
+        if block.len() < DATA_HEADER || block[0..4] != DATA_SIG {
+            return Err(DecodeError::BadSignature);
+        }
+        Ok(DataHeader { next_sec: get_u64(block, 4) })
+    }
+
+    pub fn encode_into(&self, block: &mut [u8]) {
+        block[0..4].copy_from_slice(&DATA_SIG);
+        put_u64(block, 4, self.next_sec);
+    }
+}
+
+/// A run of `len` blocks from `start`.
+#[derive(Debug, Default, Eq, PartialEq, Clone, Copy)]
+pub struct Extent {
+    pub start: u64,
+    pub len: u32,
+}
+
+impl Extent {
+    fn decode(buf: &[u8], off: usize) -> Extent {
+        Extent { start: get_u64(buf, off), len: get_u32(buf, off + 8) }
+    }
+
+    fn encode_into(&self, buf: &mut [u8], off: usize) {
+        put_u64(buf, off, self.start);
+        put_u32(buf, off + 8, self.len);
+    }
+
+    pub fn end(&self) -> u64 {
+        self.start + self.len as u64
+    }
+
+    pub fn within(&self, blocks: u64) -> bool {
+        self.start > 0 && self.len > 0 && self.end() <= blocks
+    }
+}
+
+/// Merges a list of blocks into runs, keeping their order.
+pub fn extents_from_blocks(blocks: &[u64]) -> Vec<Extent> {
+    let mut res: Vec<Extent> = Vec::new();
+    for &b in blocks {
+        match res.last_mut() {
+            Some(x) if x.end() == b && x.len < u32::MAX => x.len += 1,
+            _ => res.push(Extent { start: b, len: 1 }),
+        }
+    }
+    res
+}
+
+/// A block of extents that didn't fit in the entry.
+///
+/// ```text
+///  0..4    WEXT
+///  4..12   u64        next extent block
+/// 12..16   u32        extents in this block
+/// 16..     [Extent]
+/// ```
+pub struct ExtentBlock {
+    pub next: u64,
+    pub extents: Vec<Extent>,
+}
+
+impl ExtentBlock {
+    pub fn decode(block: &[u8]) -> Result<ExtentBlock, DecodeError> {
+        if block.len() < EXTENT_HEADER || block[0..4] != EXTENT_SIG {
+            return Err(DecodeError::BadSignature);
+        }
+        let count = get_u32(block, 12) as usize;
+        if count > extents_per_block(block.len() as u64) {
+            return Err(DecodeError::OutOfRange("extent count"));
+        }
+
+        let mut extents: Vec<Extent> = Vec::with_capacity(count);
+        for i in 0..count {
+            extents.push(Extent::decode(block, EXTENT_HEADER + i * EXTENT_SIZE));
+        }
+        Ok(ExtentBlock { next: get_u64(block, 4), extents })
+    }
+
+    pub fn encode_into(&self, block: &mut [u8]) {
+        block[0..4].copy_from_slice(&EXTENT_SIG);
+        put_u64(block, 4, self.next);
+        put_u32(block, 12, self.extents.len() as u32);
+        for (i, x) in self.extents.iter().enumerate() {
+            x.encode_into(block, EXTENT_HEADER + i * EXTENT_SIZE);
+        }
+    }
+}
+
+/// Extents one extent block holds.
+pub fn extents_per_block(bytes_per_block: u64) -> usize {
+    (bytes_per_block as usize - EXTENT_HEADER) / EXTENT_SIZE
+}
+
+/// Blocks the free block bitmap of a volume takes, one bit per block.
+// The kernel's toolchain predates u64::div_ceil.
+#[allow(clippy::manual_div_ceil)]
+pub fn bitmap_blocks(blocks: u64, bytes_per_block: u64) -> u64 {
+    let bits = bytes_per_block * 8;
+    (blocks + bits - 1) / bits
+}
+
+/// A child as its directory lists it.
+///
+/// ```text
+///  0..8    u64   id
+///  8..16   u64   location
+/// 16       u8    attributes
+/// 17       u8    name length in bytes
+/// 18..     name, UTF-8
+/// ```
+#[derive(Debug, Default, Eq, PartialEq, Clone, Copy)]
+pub struct DirRecord {
+    pub id: u64,
+    pub location: u64,
+    pub attributes: u8,
+    pub name: Name,
+}
+
+impl DirRecord {
+    pub fn from_entry(e: &FileEntry) -> DirRecord {
+        DirRecord { id: e.id, location: e.location, attributes: e.attributes, name: e.name }
+    }
+
+    fn encode_into(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.id.to_le_bytes());
+        buf.extend_from_slice(&self.location.to_le_bytes());
+        buf.push(self.attributes);
+        buf.push(self.name.len() as u8);
+        buf.extend_from_slice(self.name.as_str().as_bytes());
+    }
+
+    fn len(&self) -> usize {
+        DIR_RECORD + self.name.len()
+    }
+}
+
+/// The start of a directory's data.
+///
+/// ```text
+///  0..4    WDIR
+///  4..8    u32        buckets, 0 if not hashed
+///  8..12   u32        records
+/// 12..     [u32; buckets + 1] offset of each bucket in the data, and the end
+/// ```
+pub struct DirHeader {
+    pub buckets: u32,
+    pub count: u32,
+}
+
+impl DirHeader {
+    pub fn decode(data: &[u8]) -> Result<DirHeader, DecodeError> {
+        if data.len() < DIR_HEADER || data[0..4] != DIR_SIG {
+            return Err(DecodeError::BadSignature);
+        }
+        Ok(DirHeader { buckets: get_u32(data, 4), count: get_u32(data, 8) })
+    }
+
+    /// Where the offset of `bucket` is kept; the next one ends it.
+    pub fn table_entry(bucket: u32) -> usize {
+        DIR_HEADER + bucket as usize * 4
+    }
+
+    pub fn records_start(&self) -> usize {
+        if self.buckets == 0 { DIR_HEADER } else { DirHeader::table_entry(self.buckets + 1) }
+    }
+}
+
+/// FNV-1a of a name, picking its bucket in a hashed directory.
+pub fn name_hash(name: &str) -> u32 {
+    let mut h: u32 = 0x811c9dc5;
+    for b in name.as_bytes() {
+        h ^= *b as u32;
+        h = h.wrapping_mul(0x01000193);
+    }
+    h
+}
+
+pub fn bucket_of(name: &str, buckets: u32) -> u32 {
+    name_hash(name) % buckets
+}
+
+/// Lays out the data of a directory. Records that fit one block with the
+/// header are stored as they are; more are hashed into about one bucket per
+/// block, so a lookup reads the table and a single bucket.
+#[allow(clippy::manual_div_ceil)]
+pub fn encode_dir(records: &[DirRecord], bytes_per_block: u64) -> Vec<u8> {
+    let bpb = bytes_per_block as usize;
+    let total: usize = records.iter().map(|r| r.len()).sum();
+    let buckets = if DIR_HEADER + total <= bpb { 0 } else { ((total + bpb - 1) / bpb) as u32 };
+
+    let mut buf: Vec<u8> = Vec::with_capacity(DIR_HEADER + buckets as usize * 4 + 4 + total);
+    buf.extend_from_slice(&DIR_SIG);
+    buf.extend_from_slice(&buckets.to_le_bytes());
+    buf.extend_from_slice(&(records.len() as u32).to_le_bytes());
+
+    if buckets == 0 {
+        for r in records {
+            r.encode_into(&mut buf);
+        }
+        return buf;
+    }
+
+    buf.resize(DirHeader::table_entry(buckets + 1), 0);
+    for b in 0..buckets {
+        let off = buf.len() as u32;
+        put_u32(&mut buf, DirHeader::table_entry(b), off);
+        for r in records.iter().filter(|r| bucket_of(r.name.as_str(), buckets) == b) {
+            r.encode_into(&mut buf);
+        }
+    }
+    let end = buf.len() as u32;
+    put_u32(&mut buf, DirHeader::table_entry(buckets), end);
+    buf
+}
+
+/// Every record of a directory. Empty data is an empty directory.
+pub fn decode_dir(data: &[u8]) -> Result<Vec<DirRecord>, DecodeError> {
+    if data.is_empty() {
+        return Ok(Vec::new());
+    }
+    let h = DirHeader::decode(data)?;
+    if h.records_start() > data.len() {
+        return Err(DecodeError::OutOfRange("bucket table"));
+    }
+    let records = decode_records(&data[h.records_start()..])?;
+    if records.len() != h.count as usize {
+        return Err(DecodeError::OutOfRange("record count"));
+    }
+    Ok(records)
+}
+
+/// The records packed in `buf`, e.g. one bucket.
+pub fn decode_records(buf: &[u8]) -> Result<Vec<DirRecord>, DecodeError> {
+    let mut res: Vec<DirRecord> = Vec::new();
+    let mut off = 0;
+    while off < buf.len() {
+        if off + DIR_RECORD > buf.len() {
+            return Err(DecodeError::OutOfRange("record"));
+        }
+        let len = buf[off + 17] as usize;
+        if off + DIR_RECORD + len > buf.len() {
+            return Err(DecodeError::OutOfRange("record"));
+        }
+        res.push(DirRecord {
+            id: get_u64(buf, off),
+            location: get_u64(buf, off + 8),
+            attributes: buf[off + 16],
+            name: Name::from_bytes(&buf[off + DIR_RECORD..off + DIR_RECORD + len])?,
+        });
+        off += DIR_RECORD + len;
+    }
+    Ok(res)
+}
+
+/// Bytes of file data one block of a version 2 chain holds.
+pub fn data_payload(bytes_per_block: u64) -> usize {
+    bytes_per_block as usize - DATA_HEADER
+}
+
+/// FNV-1a over the journalled block images.
+pub fn checksum<T: AsRef<[u8]>>(images: &[T]) -> u64 {
+    let mut h: u64 = 0xcbf29ce4_84222325;
+    for img in images {
+        for b in img.as_ref().iter() {
+            h ^= *b as u64;
+            h = h.wrapping_mul(0x100000001b3);
+        }
+    }
+    h
+}
+
+/// What the consistency check needs from a volume: the kernel reads through
+/// its block cache, wfs-tool from an image file.
+pub trait BlockReader {
+    type Error;
+
+    fn read_block(&mut self, lba: u64) -> Result<Vec<u8>, Self::Error>;
+
+    /// The first sector of block `lba`.
+    fn read_head(&mut self, lba: u64) -> Result<[u8; SECTOR_SIZE], Self::Error>;
+
+    /// The whole data of `entry`, however it is stored.
+    fn read_data(&mut self, entry: &FileEntry) -> Result<Vec<u8>, Self::Error>;
+
+    /// Block `index` of the free block bitmap, including changes not yet
+    /// written back.
+    fn read_bitmap(&mut self, index: u64) -> Result<Vec<u8>, Self::Error>;
+
+    /// Prints one line of the check's findings.
+    fn report(&mut self, msg: &str);
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+pub struct CheckReport {
+    pub entries: u64,
+    pub problems: u64,
+    pub fixed: u64,
+}
+
+impl CheckReport {
+    pub fn problem<R: BlockReader>(&mut self, r: &mut R, repair: bool, fixable: bool, msg: String) {
+        self.problems += 1;
+        if repair && fixable {
+            self.fixed += 1;
+        }
+        r.report(&format!("{}{}", msg, if repair && fixable { " (fixed)" } else { "" }));
+    }
+}
+
+/// What `scan_volume` found and, for a repair, what the caller has to write
+/// back. Nothing here has been written yet.
+#[derive(Default)]
+pub struct Scan {
+    pub report: CheckReport,
+    /// The entry chain in order, with the fixes applied.
+    pub entries: Vec<FileEntry>,
+    /// Entries that differ from what is on disk.
+    pub dirty: Vec<bool>,
+    pub final_entry: u64,
+    pub files: u64,
+    pub blocks_in_use: u64,
+    /// Damaged extent lists: (entry, extents to keep, blocks to free). They
+    /// are rewritten once the bitmap has been fixed.
+    pub extents: Vec<(usize, Vec<Extent>, Vec<u64>)>,
+    /// Last blocks of version 2 data chains whose next link must be cut.
+    pub chain_ends: Vec<u64>,
+    /// Directories whose listing is rebuilt: (entry, records).
+    pub listings: Vec<(usize, Vec<DirRecord>)>,
+    /// Bitmap bits that are wrong, with the value they should have.
+    pub bitmap: Vec<(u64, bool)>,
+    /// Unreferenced blocks with a data signature, before version 3.
+    pub leaked: Vec<u64>,
+}
+
+fn mark(map: &mut [u8], lba: u64) {
+    map[lba as usize / 8] |= 1 << (lba % 8);
+}
+
+fn marked(map: &[u8], lba: u64) -> bool {
+    map[lba as usize / 8] & (1 << (lba % 8)) != 0
+}
+
+/// Walks the entry chain, the data of every entry, every directory listing
+/// and the bitmap of the volume described by `info`, and reports what is
+/// inconsistent. `repair` only decides how the problems are reported; the
+/// fixes come back in the `Scan` for the caller to write.
+// The kernel's toolchain predates div_ceil, is_multiple_of and is_some_and.
+#[allow(clippy::manual_div_ceil, clippy::manual_is_multiple_of, clippy::unnecessary_map_or)]
+pub fn scan_volume<R: BlockReader>(r: &mut R, info: &InfoBlock, repair: bool) -> Result<Scan, R::Error> {
+    let mut scan: Scan = Default::default();
+    let blocks = info.blocks;
+    let bs = info.bytes_per_block;
+    let payload = data_payload(bs) as u64;
+    let version = info.version;
+    let in_journal = |lba: u64| info.journal_len > 0 && lba >= info.journal_start && lba < info.journal_start + info.journal_len;
+
+    let mut used: Vec<u8> = alloc::vec![0; (blocks as usize + 7) / 8];
+    mark(&mut used, 0);
+    for lba in info.journal_start..info.journal_start + info.journal_len {
+        mark(&mut used, lba);
+    }
+    for lba in info.bitmap_start..info.bitmap_start + info.bitmap_len {
+        mark(&mut used, lba);
+    }
+
+    // The entry chain.
+    let mut entries: Vec<FileEntry> = Vec::new();
+    let mut dirty: Vec<bool> = Vec::new();
+    let mut lba: u64 = 1;
+    let mut prev: u64 = END_OF_CHAIN;
+    while lba != END_OF_CHAIN {
+        let e = if lba == 0 || lba >= blocks || marked(&used, lba) {
+            None
+        } else {
+            FileEntry::decode(&r.read_head(lba)?, version).ok()
+        };
+
+        let mut e = match e {
+            Some(e) => e,
+            _ => {
+                scan.report.problem(r, repair, !entries.is_empty(), format!("entry chain broken at block {}", lba));
+                if let Some(last) = entries.last_mut() {
+                    last.next_entry = END_OF_CHAIN;
+                    dirty[entries.len() - 1] = true;
+                }
+                break;
+            },
+        };
+
+        let mut d = false;
+        if e.location != lba {
+            scan.report.problem(r, repair, true, format!("entry {} records location {} but is at {}", e.name.as_str(), { e.location }, lba));
+            e.location = lba;
+            d = true;
+        }
+        if e.prev_entry != prev {
+            scan.report.problem(r, repair, true, format!("entry {} has a wrong previous entry link", e.name.as_str()));
+            e.prev_entry = prev;
+            d = true;
+        }
+
+        mark(&mut used, lba);
+        prev = lba;
+        lba = e.next_entry;
+        entries.push(e);
+        dirty.push(d);
+    }
+
+    if entries.is_empty() {
+        r.report("root entry is missing, nothing to check");
+        return Ok(scan);
+    }
+    scan.report.entries = entries.len() as u64;
+
+    if info.final_entry != prev {
+        scan.report.problem(r, repair, true, format!("final entry is {} but the chain ends at {}", { info.final_entry }, prev));
+    }
+
+    // Ids must be unique.
+    let mut ids: BTreeMap<u64, usize> = BTreeMap::new();
+    let mut max_id = entries.iter().map(|e| e.id).max().unwrap_or(0);
+    for i in 0..entries.len() {
+        let id = entries[i].id;
+        if ids.contains_key(&id) {
+            max_id += 1;
+            scan.report.problem(r, repair, true, format!("entry {} reuses id {}, renumbered to {}", entries[i].name.as_str(), id, max_id));
+            entries[i].id = max_id;
+            dirty[i] = true;
+        }
+        ids.insert(entries[i].id, i);
+    }
+
+    let by_loc: BTreeMap<u64, usize> = entries.iter().enumerate().map(|(i, e)| (e.location, i)).collect();
+
+    // Extents must lie inside the volume and cover the entry's size, and no
+    // block may belong to two files.
+    for i in 0..entries.len() {
+        let e = entries[i];
+        if e.extent_count == 0 {
+            continue;
+        }
+        let want = (e.size + bs - 1) / bs;
+        let mut damaged = false;
+        let mut all: Vec<Extent> = e.extents[..e.inline_extents()].to_vec();
+        let mut ext_blocks: Vec<u64> = Vec::new();
+
+        let mut lba = e.start_sec;
+        while lba != END_OF_CHAIN && all.len() < e.extent_count as usize {
+            if lba >= blocks || marked(&used, lba) {
+                damaged = true;
+                break;
+            }
+            match ExtentBlock::decode(&r.read_block(lba)?) {
+                Ok(b) => {
+                    mark(&mut used, lba);
+                    ext_blocks.push(lba);
+                    all.extend_from_slice(&b.extents);
+                    lba = b.next;
+                },
+                Err(_) => {
+                    damaged = true;
+                    break;
+                },
+            }
+        }
+        damaged |= all.len() != e.extent_count as usize;
+        all.truncate(e.extent_count as usize);
+
+        let mut keep: Vec<Extent> = Vec::new();
+        let mut got: u64 = 0;
+        for x in all {
+            if !x.within(blocks) || (x.start..x.end()).any(|b| marked(&used, b)) {
+                damaged = true;
+                break;
+            }
+            for b in x.start..x.end() {
+                mark(&mut used, b);
+            }
+            got += x.len as u64;
+            keep.push(x);
+        }
+
+        if got < want {
+            let size = (got * bs).min(e.size);
+            scan.report.problem(r, repair, true, format!("data of {} is damaged, {} of {} bytes readable", e.name.as_str(), size, { e.size }));
+            entries[i].size = size;
+        } else if damaged {
+            scan.report.problem(r, repair, true, format!("extents of {} are damaged", e.name.as_str()));
+        } else if got > want {
+            scan.report.problem(r, repair, true, format!("extents of {} are longer than its size", e.name.as_str()));
+        } else {
+            continue;
+        }
+
+        // Blocks past the size are dropped along with the old extent blocks.
+        let mut free = ext_blocks;
+        let mut need = (entries[i].size + bs - 1) / bs;
+        let mut trimmed: Vec<Extent> = Vec::new();
+        for x in keep {
+            let n = (x.len as u64).min(need);
+            if n > 0 {
+                trimmed.push(Extent { start: x.start, len: n as u32 });
+            }
+            free.extend(x.start + n..x.end());
+            need -= n;
+        }
+        scan.extents.push((i, trimmed, free));
+        dirty[i] = true;
+    }
+
+    // Version 2 data chains: each must be long enough for the entry's size,
+    // and no block may belong to two chains.
+    for i in 0..entries.len() {
+        let e = entries[i];
+        if !e.uses_chain() {
+            continue;
+        }
+        let want = (e.size + payload - 1) / payload;
+        let mut got: u64 = 0;
+        let mut last: u64 = END_OF_CHAIN;
+        let mut lba = e.start_sec;
+
+        while lba != END_OF_CHAIN && lba != FREE && got < want {
+            if lba >= blocks || marked(&used, lba) {
+                break;
+            }
+            let d = match DataHeader::decode(&r.read_head(lba)?) {
+                Ok(d) => d,
+                Err(_) => break,
+            };
+            mark(&mut used, lba);
+            got += 1;
+            last = lba;
+            lba = d.next_sec;
+        }
+
+        let truncated = got < want;
+        let too_long = !truncated && lba != END_OF_CHAIN && lba != FREE;
+        if !truncated && !too_long {
+            continue;
+        }
+
+        if truncated {
+            let size = (got * payload).min(e.size);
+            scan.report.problem(r, repair, true, format!("data of {} is damaged, {} of {} bytes readable", e.name.as_str(), size, { e.size }));
+            entries[i].size = size;
+        } else {
+            scan.report.problem(r, repair, true, format!("data chain of {} is longer than its size", e.name.as_str()));
+        }
+
+        if last == END_OF_CHAIN {
+            entries[i].start_sec = END_OF_CHAIN;
+        } else {
+            scan.chain_ends.push(last);
+        }
+        dirty[i] = true;
+    }
+
+    // Every entry but the root needs a parent directory; orphans go to A:.
+    let home = ids.get(&1).cloned();
+    for i in 1..entries.len() {
+        let ok = match ids.get(&{ entries[i].parent_id }) {
+            Some(&p) => p != i && entries[p].is_dir(),
+            None => false,
+        };
+        if ok {
+            continue;
+        }
+
+        match home {
+            Some(h) if h != i && entries[h].is_dir() => {
+                scan.report.problem(r, repair, true, format!("{} has no parent directory, moved to A:", entries[i].name.as_str()));
+                entries[i].parent_id = 1;
+                dirty[i] = true;
+            },
+            _ => scan.report.problem(r, repair, false, format!("{} has no parent directory", entries[i].name.as_str())),
+        }
+    }
+
+    // Directory listings must name exactly the entries whose parent_id
+    // points at the directory, under the entry's name, plus further hard
+    // links to files. Since version 4 records agree with their entry on id
+    // and attributes, since version 5 entries count their names.
+    let mut listings: Vec<(usize, Vec<(u64, Name)>)> = Vec::new();
+    let mut names: Vec<u32> = alloc::vec![0; entries.len()];
+    for i in 0..entries.len() {
+        if !entries[i].is_dir() {
+            continue;
+        }
+
+        let dir_id = entries[i].id;
+        let raw = r.read_data(&entries[i]).unwrap_or_default();
+        let mut listed: Vec<(u64, Name)> = Vec::new();
+
+        // (location, record) of each child listed; records since version 4.
+        let (found, mut changed): (Vec<(u64, Option<DirRecord>)>, bool) = if version < 4 {
+            (raw.chunks_exact(8).map(|c| (u64::from_le_bytes(c.try_into().expect("")), None)).collect(), raw.len() % 8 != 0)
+        } else {
+            match decode_dir(&raw) {
+                Ok(d) => (d.into_iter().map(|d| (d.location, Some(d))).collect(), false),
+                Err(_) => {
+                    scan.report.problem(r, repair, true, format!("directory {} is damaged", entries[i].name.as_str()));
+                    (Vec::new(), true)
+                },
+            }
+        };
+
+        for (loc, record) in found {
+            let ci = match by_loc.get(&loc) {
+                Some(&ci) if ci != i && ci != 0 => ci,
+                _ => {
+                    scan.report.problem(r, repair, true, format!("directory {} lists a bad entry at block {}", entries[i].name.as_str(), loc));
+                    changed = true;
+                    continue;
+                },
+            };
+            let e = entries[ci];
+            let name = record.map_or(e.name, |d| d.name);
+            let own = e.parent_id == dir_id && name == e.name;
+            let link = version >= 5 && !e.is_dir();
+            if !(own || link) || listed.contains(&(loc, name)) {
+                scan.report.problem(r, repair, true, format!("directory {} lists a bad entry at block {}", entries[i].name.as_str(), loc));
+                changed = true;
+                continue;
+            }
+
+            listed.push((loc, name));
+            names[ci] += 1;
+            if record.map_or(false, |d| d.id != e.id || d.attributes != e.attributes) {
+                scan.report.problem(r, repair, true, format!("directory {} has a stale record for {}", entries[i].name.as_str(), name.as_str()));
+                changed = true;
+            }
+        }
+        for j in 0..entries.len() {
+            let own = (entries[j].location, entries[j].name);
+            if j != i && entries[j].parent_id == dir_id && !listed.contains(&own) {
+                scan.report.problem(r, repair, true, format!("{} is missing from directory {}", entries[j].name.as_str(), entries[i].name.as_str()));
+                listed.push(own);
+                names[j] += 1;
+                changed = true;
+            }
+        }
+
+        if changed {
+            listings.push((i, listed));
+        }
+    }
+
+    if version >= 5 {
+        for i in 1..entries.len() {
+            if entries[i].links != names[i] {
+                scan.report.problem(r, repair, true, format!("{} has {} names but counts {}", entries[i].name.as_str(), names[i], { entries[i].links }));
+                entries[i].links = names[i];
+                dirty[i] = true;
+            }
+        }
+    }
+
+    // The bitmap must mark exactly the blocks found above. Before version 3
+    // anything else carrying a data signature is leaked.
+    let mut in_use: u64 = 1;
+    for lba in 1..blocks {
+        if in_journal(lba) {
+            continue;
+        }
+        if marked(&used, lba) {
+            in_use += 1;
+        } else if version < 3 && r.read_head(lba)?[0..4] == DATA_SIG {
+            scan.report.problem(r, repair, true, format!("block {} is not referenced", lba));
+            scan.leaked.push(lba);
+        }
+    }
+    if version >= 3 {
+        let bits = bs * 8;
+        for b in 0..info.bitmap_len {
+            let map = r.read_bitmap(b)?;
+            for lba in b * bits..((b + 1) * bits).min(blocks) {
+                let bit = lba % bits;
+                let want = marked(&used, lba);
+                if marked(&map, bit) == want {
+                    continue;
+                }
+                if want {
+                    scan.report.problem(r, repair, true, format!("block {} is in use but marked free", lba));
+                } else {
+                    scan.report.problem(r, repair, true, format!("block {} is not referenced", lba));
+                }
+                scan.bitmap.push((lba, want));
+            }
+        }
+    }
+
+    let files = entries.len() as u64 - 1;
+    if info.files != files {
+        scan.report.problem(r, repair, true, format!("file count is {}, should be {}", { info.files }, files));
+    }
+    if info.blocks_in_use != in_use {
+        scan.report.problem(r, repair, true, format!("blocks in use is {}, should be {}", { info.blocks_in_use }, in_use));
+    }
+
+    scan.listings = listings.into_iter().map(|(i, listed)| {
+        let records = listed.iter().map(|(l, name)| DirRecord { name: *name, ..DirRecord::from_entry(&entries[by_loc[l]]) }).collect();
+        (i, records)
+    }).collect();
+    scan.entries = entries;
+    scan.dirty = dirty;
+    scan.final_entry = prev;
+    scan.files = files;
+    scan.blocks_in_use = in_use;
+    Ok(scan)
+}
+
+// Only built for the host tool: the kernel's own test runner can't run #[test].
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str) -> FileEntry {
+        FileEntry {
+            signature: DATA_SIG,
+            name: Name::new(name).unwrap(),
+            parent_id: 0x0102030405060708,
+            id: 0x1112131415161718,
+            attributes: 0xA5,
+            t_creation: 1_600_000_000,
+            t_edit: 1_600_000_123,
+            owner: 7,
+            size: 0x2122232425262728,
+            start_sec: 0x3132333435363738,
+            next_entry: END_OF_CHAIN,
+            prev_entry: 0x4142434445464748,
+            location: 0x5152535455565758,
+            extent_count: 0,
+            extents: [Extent::default(); INLINE_EXTENTS],
+            links: 1,
+        }
+    }
+
+    #[test]
+    fn info_block_round_trip() {
+        let info = InfoBlock {
+            state: STATE_MOUNTED,
+            signature: WFS_SIG,
+            blocks: 0x0000_0001_0000_0000,
+            blocks_in_use: 0x1234_5678,
+            files: 0x0102_0304_0506_0708,
+            bytes_per_block: SECTOR_SIZE as u64,
+            final_entry: 0x8765_4321,
+            journal_start: 0x0000_0000_FFFF_FF80,
+            journal_len: JOURNAL_LEN,
+            version: FORMAT_VERSION,
+            bitmap_start: 2,
+            bitmap_len: 0x0000_0000_0010_0000,
+        };
+        let sec = info.encode();
+        assert_eq!(InfoBlock::decode(&sec), Ok(info));
+
+        // Fixed offsets, little endian.
+        assert_eq!(sec[0], STATE_MOUNTED);
+        assert_eq!(&sec[1..9], &WFS_SIG);
+        assert_eq!(&sec[9..17], &[0, 0, 0, 0, 1, 0, 0, 0]);
+        assert_eq!(&sec[25..33], &[8, 7, 6, 5, 4, 3, 2, 1]);
+        assert_eq!(&sec[65..69], &FORMAT_VERSION.to_le_bytes());
+        assert_eq!(&sec[69..77], &2u64.to_le_bytes());
+        assert_eq!(&sec[77..85], &[0, 0, 0x10, 0, 0, 0, 0, 0]);
+        assert!(sec[85..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn info_block_validation() {
+        let good = InfoBlock {
+            state: STATE_CLEAN,
+            signature: WFS_SIG,
+            blocks: 4096,
+            blocks_in_use: 3,
+            files: 1,
+            bytes_per_block: 512,
+            final_entry: 2,
+            journal_start: 4096 - JOURNAL_LEN,
+            journal_len: JOURNAL_LEN,
+            version: FORMAT_VERSION,
+            bitmap_start: 2,
+            bitmap_len: 1,
+        };
+        assert!(good.validate().is_ok());
+
+        // Volumes from before the version field.
+        let mut sec = good.encode();
+        sec[65..69].copy_from_slice(&[0; 4]);
+        assert_eq!(InfoBlock::decode(&sec).map(|i| i.version), Ok(1));
+
+        let bad = |f: &dyn Fn(&mut InfoBlock)| {
+            let mut i = good;
+            f(&mut i);
+            InfoBlock::decode(&i.encode())
+        };
+        assert_eq!(bad(&|i| i.signature[0] = b'X'), Err(DecodeError::BadSignature));
+        assert_eq!(bad(&|i| i.version = FORMAT_VERSION + 1), Err(DecodeError::UnsupportedVersion(FORMAT_VERSION + 1)));
+        assert_eq!(bad(&|i| i.state = 9), Err(DecodeError::OutOfRange("state")));
+        assert_eq!(bad(&|i| i.bytes_per_block = 1000), Err(DecodeError::OutOfRange("bytes per block")));
+        assert_eq!(bad(&|i| i.bytes_per_block = 256), Err(DecodeError::OutOfRange("bytes per block")));
+        assert_eq!(bad(&|i| i.bytes_per_block = 2 * MAX_BLOCK_SIZE), Err(DecodeError::OutOfRange("bytes per block")));
+        assert!(bad(&|i| i.bytes_per_block = 4096).is_ok());
+        assert_eq!(bad(&|i| i.blocks_in_use = 5000), Err(DecodeError::OutOfRange("blocks in use")));
+        assert_eq!(bad(&|i| i.final_entry = 4096), Err(DecodeError::OutOfRange("final entry")));
+        assert_eq!(bad(&|i| i.journal_start = 4000), Err(DecodeError::OutOfRange("journal")));
+        assert_eq!(bad(&|i| i.bitmap_start = 0), Err(DecodeError::OutOfRange("bitmap")));
+        assert_eq!(bad(&|i| i.bitmap_start = 4096), Err(DecodeError::OutOfRange("bitmap")));
+        assert_eq!(bad(&|i| i.blocks = 8192), Err(DecodeError::OutOfRange("bitmap")));
+        assert!(bad(&|i| { i.version = 2; i.bitmap_len = 0 }).is_ok());
+    }
+
+    #[test]
+    fn file_entry_round_trip_v1() {
+        let e = entry("some-caf\u{e9}.txt");
+        let sec = e.encode(1);
+        assert_eq!(FileEntry::decode(&sec, 1), Ok(e));
+
+        // Same layout the packed struct used to have.
+        assert_eq!(&sec[0..4], &DATA_SIG);
+        assert_eq!(&sec[4..8], &(b's' as u32).to_le_bytes());
+        assert_eq!(&sec[36..40], &0xe9u32.to_le_bytes());
+        assert_eq!(&sec[56..60], &(b' ' as u32).to_le_bytes());
+        assert_eq!(&sec[260..268], &0x0102030405060708u64.to_le_bytes());
+        assert_eq!(&sec[268..276], &0x1112131415161718u64.to_le_bytes());
+        assert_eq!(sec[276], 0xA5);
+        assert_eq!(&sec[277..285], &1_600_000_000u64.to_le_bytes());
+        assert_eq!(&sec[285..293], &1_600_000_123u64.to_le_bytes());
+        assert_eq!(sec[293], 7);
+        assert_eq!(&sec[294..302], &0x2122232425262728u64.to_le_bytes());
+        assert_eq!(&sec[302..310], &0x3132333435363738u64.to_le_bytes());
+        assert_eq!(&sec[310..318], &END_OF_CHAIN.to_le_bytes());
+        assert_eq!(&sec[318..326], &0x4142434445464748u64.to_le_bytes());
+        assert_eq!(&sec[326..334], &0x5152535455565758u64.to_le_bytes());
+        assert!(sec[334..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn file_entry_round_trip_v2() {
+        let e = entry("a name with spaces, \u{e9}t\u{e9} ");
+        let sec = e.encode(2);
+        assert_eq!(FileEntry::decode(&sec, 2), Ok(e));
+
+        assert_eq!(&sec[0..4], &DATA_SIG);
+        assert_eq!(&sec[4..12], &0x0102030405060708u64.to_le_bytes());
+        assert_eq!(&sec[12..20], &0x1112131415161718u64.to_le_bytes());
+        assert_eq!(sec[20], 0xA5);
+        assert_eq!(&sec[21..29], &1_600_000_000u64.to_le_bytes());
+        assert_eq!(&sec[29..37], &1_600_000_123u64.to_le_bytes());
+        assert_eq!(sec[37], 7);
+        assert_eq!(&sec[38..46], &0x2122232425262728u64.to_le_bytes());
+        assert_eq!(&sec[46..54], &0x3132333435363738u64.to_le_bytes());
+        assert_eq!(&sec[54..62], &END_OF_CHAIN.to_le_bytes());
+        assert_eq!(&sec[62..70], &0x4142434445464748u64.to_le_bytes());
+        assert_eq!(&sec[70..78], &0x5152535455565758u64.to_le_bytes());
+        assert_eq!(&sec[78..80], &(e.name.len() as u16).to_le_bytes());
+        assert_eq!(&sec[80..80 + e.name.len()], e.name.as_str().as_bytes());
+
+        let long: String = "\u{e9}".repeat(NAME_MAX / 2);
+        let e = entry(&long);
+        assert_eq!(FileEntry::decode(&e.encode(2), 2), Ok(e));
+        assert!(Name::new(&format!("{}ab", long)).is_err());
+    }
+
+    #[test]
+    fn file_entry_round_trip_v3() {
+        let mut e = entry(&"x".repeat(NAME_MAX));
+        e.extent_count = 20;
+        for i in 0..INLINE_EXTENTS {
+            e.extents[i] = Extent { start: 0x0100 + i as u64, len: 0x0200 + i as u32 };
+        }
+        let sec = e.encode(3);
+        assert_eq!(FileEntry::decode(&sec, 3), Ok(e));
+
+        // The longest name ends before the extents.
+        assert_eq!(&sec[80..335], "x".repeat(NAME_MAX).as_bytes());
+        assert_eq!(&sec[336..340], &20u32.to_le_bytes());
+        assert_eq!(&sec[340..348], &0x0100u64.to_le_bytes());
+        assert_eq!(&sec[348..352], &0x0200u32.to_le_bytes());
+        assert_eq!(&sec[496..504], &0x010Du64.to_le_bytes());
+        assert!(sec[508..].iter().all(|b| *b == 0));
+
+        // Version 2 entries have no extents, but keep their data chain.
+        let e = entry("old");
+        let d = FileEntry::decode(&e.encode(2), 3).unwrap();
+        assert_eq!(d.extent_count, 0);
+        assert!(d.uses_chain());
+        assert_eq!(FileEntry::layout_version(&e.encode(3), e.location), Some(2));
+    }
+
+    #[test]
+    fn file_entry_round_trip_v5() {
+        let mut e = entry("linked");
+        e.links = 3;
+        let sec = e.encode(5);
+        assert_eq!(FileEntry::decode(&sec, 5), Ok(e));
+        assert_eq!(&sec[508..512], &3u32.to_le_bytes());
+
+        // Older entries are named once.
+        assert_eq!(FileEntry::decode(&e.encode(4), 5).unwrap().links, 1);
+        assert_eq!(FileEntry::decode(&sec, 4).unwrap().links, 1);
+    }
+
+    #[test]
+    fn extent_block_round_trip() {
+        let b = ExtentBlock {
+            next: END_OF_CHAIN,
+            extents: (0..41).map(|i| Extent { start: 1000 + i, len: 3 }).collect(),
+        };
+        let mut block = vec![0u8; 512];
+        b.encode_into(&mut block);
+        assert_eq!(&block[0..4], &EXTENT_SIG);
+        assert_eq!(&block[12..16], &41u32.to_le_bytes());
+
+        let d = ExtentBlock::decode(&block).unwrap();
+        assert_eq!(d.next, END_OF_CHAIN);
+        assert_eq!(d.extents, b.extents);
+
+        assert_eq!(extents_per_block(512), 41);
+        block[12..16].copy_from_slice(&42u32.to_le_bytes());
+        assert!(ExtentBlock::decode(&block).is_err());
+        assert!(ExtentBlock::decode(&[0; 512]).is_err());
+
+        assert_eq!(extents_from_blocks(&[5, 6, 7, 9, 10, 3]), vec![
+            Extent { start: 5, len: 3 },
+            Extent { start: 9, len: 2 },
+            Extent { start: 3, len: 1 },
+        ]);
+        assert!(extents_from_blocks(&[]).is_empty());
+
+        assert_eq!(bitmap_blocks(4096, 512), 1);
+        assert_eq!(bitmap_blocks(4097, 512), 2);
+        assert!(Extent { start: 10, len: 5 }.within(15));
+        assert!(!Extent { start: 10, len: 5 }.within(14));
+        assert!(!Extent { start: 0, len: 5 }.within(14));
+    }
+
+    #[test]
+    fn file_entry_layout_detection() {
+        let mut e = entry("old name");
+        e.location = 42;
+        assert_eq!(FileEntry::layout_version(&e.encode(1), 42), Some(1));
+        assert_eq!(FileEntry::layout_version(&e.encode(2), 42), Some(2));
+        assert_eq!(FileEntry::layout_version(&e.encode(2), 43), None);
+        assert_eq!(FileEntry::layout_version(&[0; SECTOR_SIZE], 42), None);
+    }
+
+    #[test]
+    fn file_entry_validation() {
+        let mut sec = entry("x").encode(2);
+        sec[0] = b'X';
+        assert_eq!(FileEntry::decode(&sec, 2), Err(DecodeError::BadSignature));
+
+        let mut sec = entry("x").encode(1);
+        sec[4..8].copy_from_slice(&0xD800u32.to_le_bytes());
+        assert_eq!(FileEntry::decode(&sec, 1), Err(DecodeError::BadName));
+
+        let mut sec = entry("x").encode(2);
+        sec[80] = 0xFF;
+        assert_eq!(FileEntry::decode(&sec, 2), Err(DecodeError::BadName));
+        sec[78..80].copy_from_slice(&300u16.to_le_bytes());
+        assert_eq!(FileEntry::decode(&sec, 2), Err(DecodeError::BadName));
+
+        assert!(name_fits("short", 1));
+        assert!(!name_fits("has space", 1));
+        assert!(!name_fits(&"x".repeat(65), 1));
+        assert!(name_fits(&"x".repeat(255), 2));
+
+        let mut e = entry("x");
+        e.location = 10;
+        e.prev_entry = 1;
+        e.start_sec = 12;
+        assert_eq!(e.validate(100), Ok(()));
+        e.next_entry = 100;
+        assert_eq!(e.validate(100), Err(DecodeError::OutOfRange("entry link")));
+        e.next_entry = END_OF_CHAIN;
+        e.extent_count = 1;
+        e.extents[0] = Extent { start: 90, len: 11 };
+        assert_eq!(e.validate(100), Err(DecodeError::OutOfRange("extent")));
+        e.extents[0].len = 10;
+        assert_eq!(e.validate(100), Ok(()));
+        e.location = 0;
+        assert_eq!(e.validate(100), Err(DecodeError::OutOfRange("location")));
+    }
+
+    #[test]
+    fn data_header_round_trip() {
+        let mut block = vec![0xEEu8; 4096];
+        DataHeader { next_sec: 0x0A0B0C0D }.encode_into(&mut block);
+        assert_eq!(&block[0..4], &DATA_SIG);
+        assert_eq!(&block[4..12], &0x0A0B0C0Du64.to_le_bytes());
+        assert_eq!(block[12], 0xEE);
+        assert_eq!(DataHeader::decode(&block).map(|h| h.next_sec), Ok(0x0A0B0C0D));
+
+        assert!(DataHeader::decode(&[0; SECTOR_SIZE]).is_err());
+        assert_eq!(data_payload(512), 500);
+        assert_eq!(data_payload(MAX_BLOCK_SIZE), 65524);
+    }
+
+    fn record(i: u64) -> DirRecord {
+        let name = format!("file {}", i);
+        DirRecord { id: i + 2, location: 100 + i, attributes: (i % 2) as u8, name: Name::new(&name).unwrap() }
+    }
+
+    #[test]
+    fn small_directory_round_trip() {
+        let records: Vec<DirRecord> = (0..5).map(record).collect();
+        let data = encode_dir(&records, 512);
+        assert_eq!(&data[0..4], &DIR_SIG);
+        assert_eq!(&data[4..8], &0u32.to_le_bytes());
+        assert_eq!(&data[8..12], &5u32.to_le_bytes());
+        assert_eq!(&data[12..20], &2u64.to_le_bytes());
+        assert_eq!(&data[20..28], &100u64.to_le_bytes());
+        assert_eq!(data[29], 6);
+        assert_eq!(&data[30..36], b"file 0");
+        assert_eq!(decode_dir(&data), Ok(records));
+        assert_eq!(decode_dir(&[]), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn large_directory_is_hashed() {
+        let records: Vec<DirRecord> = (0..200).map(record).collect();
+        let data = encode_dir(&records, 512);
+        let h = DirHeader::decode(&data).unwrap();
+        assert!(h.buckets > 1);
+        assert_eq!(h.count, 200);
+
+        let mut all = decode_dir(&data).unwrap();
+        all.sort_by_key(|r| r.id);
+        assert_eq!(all, records);
+
+        // Every record is found in its own bucket alone.
+        for r in &records {
+            let b = bucket_of(r.name.as_str(), h.buckets);
+            let from = get_u32(&data, DirHeader::table_entry(b)) as usize;
+            let to = get_u32(&data, DirHeader::table_entry(b + 1)) as usize;
+            assert!(decode_records(&data[from..to]).unwrap().contains(r));
+        }
+    }
+
+    #[test]
+    fn damaged_directories_are_rejected() {
+        let records: Vec<DirRecord> = (0..5).map(record).collect();
+        let data = encode_dir(&records, 512);
+        assert_eq!(decode_dir(&data[..data.len() - 1]), Err(DecodeError::OutOfRange("record")));
+        let mut bad = data.clone();
+        bad[8] = 6;
+        assert_eq!(decode_dir(&bad), Err(DecodeError::OutOfRange("record count")));
+        assert_eq!(decode_dir(&[0; 16]), Err(DecodeError::BadSignature));
+    }
+}
@@ -0,0 +1,1947 @@
+//WFS: A shit filesystem
+//Spec can be found at ../../wfs_spec.txt
+
+use crate::timer;
+use crate::time;
+use crate::vga_buffer;
+use crate::vfs;
+use crate::drivers::block;
+use crate::cache;
+use crate::drivers::partition;
+use crate::fat;
+use crate::ext2;
+use spin::Mutex;
+use lazy_static::lazy_static;
+use bit_field::BitField;
+use crate::println;
+use crate::print;
+use alloc::string::String;
+use alloc::vec::Vec;
+use alloc::vec;
+use alloc::collections::BTreeMap;
+use core::convert::TryInto;
+use core::result::Result;
+use alloc::string::ToString;
+
+pub mod disk;
+
+use self::disk::*;
+
+const TXN_CAPACITY: usize = 120;
+
+/// Volumes in an older format are mounted read-only until `upgrade` is run.
+/// With this set they are upgraded at mount instead.
+pub const UPGRADE_ON_MOUNT: bool = false;
+
+static READ_ONLY: Mutex<bool> = Mutex::new(false);
+
+// Where the allocator starts looking for free blocks: just past the last
+// blocks it handed out, so files written one after another stay contiguous.
+static ALLOC_HINT: Mutex<u64> = Mutex::new(2);
+
+lazy_static! {
+    pub static ref WFS_DEV: Mutex<usize> = Mutex::new(0);
+
+    // id -> block of the file entry, rebuilt at mount.
+    static ref ENTRY_INDEX: Mutex<BTreeMap<u64, u64>> = Mutex::new(BTreeMap::new());
+
+    pub static ref WFS_INFO: Mutex<InfoBlock> = Mutex::new(Default::default());
+
+    // Format version of the mounted volume. Kept apart from WFS_INFO so
+    // entries can be decoded while WFS_INFO is locked.
+    static ref WFS_VERSION: Mutex<u32> = Mutex::new(FORMAT_VERSION);
+
+    // Bytes per block of the mounted volume, kept apart for the same reason.
+    static ref WFS_BLOCK_SIZE: Mutex<usize> = Mutex::new(MIN_BLOCK_SIZE as usize);
+}
+
+// Metadata writes made during an operation are staged here, a whole block
+// each, and only reach their home blocks after the whole set has been
+// committed to the journal. Blocks allocated by the operation itself are not
+// referenced by anything on disk yet, so they are written straight through;
+// blocks it frees are not handed out again before the commit.
+struct Transaction {
+    active: bool,
+    overflow: bool,
+    lbas: Vec<u64>,
+    images: Vec<Vec<u8>>,
+    allocated: Vec<u64>,
+    freed: Vec<u64>,
+    seq: u64,
+}
+
+static TXN: Mutex<Transaction> = Mutex::new(Transaction {
+    active: false,
+    overflow: false,
+    lbas: Vec::new(),
+    images: Vec::new(),
+    allocated: Vec::new(),
+    freed: Vec::new(),
+    seq: 0,
+});
+
+pub fn init() {
+    if block::BLOCK_DEVICES.lock().is_empty() {
+        println!("[WFS] No block devices. Aborting.");
+        return;
+    }
+
+    match find_volume() {
+        Some(dev) => {
+            println!("[WFS] Valid InfoBlock found on {}.", block::get(dev).unwrap().name);
+            *WFS_DEV.lock() = dev;
+            init_fs();
+        },
+        None => {
+            println!("[WFS] No valid InfoBlock found.");
+            match find_install_target() {
+                Some(dev) => install(dev, DEFAULT_BLOCK_SIZE),
+                None => println!("[WFS] No blank disk or wFS partition to install on. Not installing."),
+            }
+        },
+    }
+}
+
+// wFS partitions are preferred over a volume written to a whole disk.
+fn find_volume() -> Option<usize> {
+    let devices = block::BLOCK_DEVICES.lock().clone();
+
+    for d in devices.iter().filter(|d| d.part_type.is_wfs()) {
+        if has_signature(d.index) {
+            return Some(d.index);
+        }
+    }
+
+    for d in devices.iter() {
+        if let block::Kind::Ata { .. } = d.kind {
+            if has_signature(d.index) {
+                return Some(d.index);
+            }
+        }
+    }
+
+    None
+}
+
+// Only the boot disk (the ATA master) is ever formatted, so attaching a
+// second disk can't destroy it. An empty wFS partition is formatted. A disk
+// without any partition table is formatted whole, like before partitions
+// were supported; a partitioned disk without a wFS partition is left alone.
+fn find_install_target() -> Option<usize> {
+    let devices = block::BLOCK_DEVICES.lock().clone();
+    let boot = devices.iter().find(|d| d.kind == block::Kind::Ata { master: true })?.index;
+
+    let partition = devices.iter().find(|d| match d.kind {
+        block::Kind::Partition { disk, .. } => disk == boot && d.part_type.is_wfs(),
+        _ => false,
+    });
+    if let Some(d) = partition {
+        return Some(d.index);
+    }
+
+    if partition::read_table(boot).is_none() && !fat::is_volume(boot) && !ext2::is_volume(boot) {
+        return Some(boot);
+    }
+
+    None
+}
+
+fn has_signature(dev: usize) -> bool {
+    match block::read(dev, 0) {
+        Ok(block0) => block0[1..9] == WFS_SIG,
+        Err(_) => false,
+    }
+}
+
+/// Formats `dev` with blocks of `bytes_per_block` bytes, a power of two from
+/// MIN_BLOCK_SIZE to MAX_BLOCK_SIZE.
+pub fn install(dev: usize, bytes_per_block: u64) {
+    let d = match block::get(dev) {
+        Some(d) => d,
+        None => return,
+    };
+    if bytes_per_block < MIN_BLOCK_SIZE || bytes_per_block > MAX_BLOCK_SIZE || !bytes_per_block.is_power_of_two() {
+        println!("[WFS] Invalid block size {}.", bytes_per_block);
+        return;
+    }
+    *WFS_DEV.lock() = dev;
+
+    println!("[WFS] Installing wFS on {} with {}B blocks.", d.name, bytes_per_block);
+    let blocks = d.sectors / (bytes_per_block / 512);
+    let bitmap_len = bitmap_blocks(blocks, bytes_per_block);
+    
+    //Create WFS InfoBlock and write to first sector of the device.
+    WFS_INFO.lock().signature = WFS_SIG;
+    WFS_INFO.lock().blocks = blocks;
+    WFS_INFO.lock().state = STATE_CLEAN;
+    WFS_INFO.lock().blocks_in_use = 2 + bitmap_len;
+    WFS_INFO.lock().files = 0;
+    WFS_INFO.lock().bytes_per_block = bytes_per_block;
+    WFS_INFO.lock().final_entry = 1;
+    WFS_INFO.lock().journal_start = 0;
+    WFS_INFO.lock().journal_len = 0;
+    WFS_INFO.lock().version = FORMAT_VERSION;
+    WFS_INFO.lock().bitmap_start = 2;
+    WFS_INFO.lock().bitmap_len = bitmap_len;
+    *WFS_VERSION.lock() = FORMAT_VERSION;
+    *WFS_BLOCK_SIZE.lock() = bytes_per_block as usize;
+    *ALLOC_HINT.lock() = 2 + bitmap_len;
+
+    // The bitmap follows the root entry and covers itself.
+    let mut reserved: Vec<u64> = vec![0, 1];
+    for lba in 2..2 + bitmap_len {
+        write_block(lba as usize, &[0; 512]);
+        reserved.push(lba);
+    }
+
+    if blocks >= JOURNAL_LEN * 4 {
+        WFS_INFO.lock().journal_start = blocks - JOURNAL_LEN;
+        WFS_INFO.lock().journal_len = JOURNAL_LEN;
+        write_block(WFS_INFO.lock().journal_start as usize, &[0; 512]);
+        reserved.extend(blocks - JOURNAL_LEN..blocks);
+    }
+    set_used(&reserved, true);
+
+    println!("[WFS] Writing InfoBlock to {}.", d.name);
+    
+    update_info();
+
+    let root_attributes: u8 = *0.set_bit(0, true).set_bit(1, true).set_bit(2, true);
+    let root = FileEntry {
+        name: Default::default(),
+        signature: DATA_SIG,
+        parent_id: 0,
+        id: 0,
+        attributes: root_attributes,
+        t_creation: time::now(),
+        t_edit: time::now(),
+        owner: 0,
+        size: 0,
+        start_sec: END_OF_CHAIN,
+        next_entry: END_OF_CHAIN,
+        prev_entry: END_OF_CHAIN,
+        location: 1,
+        extent_count: 0,
+        extents: Default::default(),
+        links: 1,
+    };
+    let root_arr = sector_from_entry(root);
+    println!("[WFS] Writing Root file entry to {}.", d.name);
+    write_block(1, &root_arr);
+    init_fs();
+
+    // The read-only root only ever gets A: as a child, so this goes past the
+    // VFS permission checks.
+    if let Err(e) = create_node(0, String::from("A:"), *0.set_bit(vfs::ATTR_DIR, true).set_bit(vfs::ATTR_SYS, true), 0, 0) {
+        println!("[WFS] Could not create A: on {} ({}).", d.name, e);
+    }
+}
+
+pub fn init_fs() {
+    if let Err(e) = read_info() {
+        println!("[WFS] Invalid InfoBlock ({:?}). Not mounting.", e);
+        return;
+    }
+    *READ_ONLY.lock() = false;
+
+    if WFS_INFO.lock().journal_len > 0 {
+        replay_journal();
+        let _ = read_info();
+    }
+    *ALLOC_HINT.lock() = 2;
+
+    let (version, state) = {
+        let info = WFS_INFO.lock();
+        (info.version, info.state)
+    };
+
+    if state == STATE_UPGRADING || (version < FORMAT_VERSION && UPGRADE_ON_MOUNT) {
+        println!("[WFS] Upgrading volume from format version {} to {}.", version, FORMAT_VERSION);
+        if let Err(e) = upgrade() {
+            println!("[WFS] Upgrade failed ({}).", e);
+        }
+    }
+
+    if WFS_INFO.lock().version < FORMAT_VERSION {
+        println!("[WFS] Volume has format version {}, mounting read-only. Run 'wfs upgrade' to upgrade it to version {}.", version, FORMAT_VERSION);
+        if state == STATE_MOUNTED {
+            println!("[WFS] Volume was not cleanly unmounted, it will be checked when upgraded.");
+        }
+        *READ_ONLY.lock() = true;
+        build_index();
+        vfs::install_device(String::from("A:"), vfs::System::WFS);
+        return;
+    }
+
+    if WFS_INFO.lock().journal_len == 0 {
+        create_journal();
+    }
+
+    build_index();
+
+    // An upgrade checks the volume itself.
+    if state == STATE_MOUNTED && version == FORMAT_VERSION {
+        println!("[WFS] Volume was not cleanly unmounted, checking.");
+        let r = check(true);
+        println!("[WFS] {} problems found, {} fixed.", r.problems, r.fixed);
+    }
+
+    WFS_INFO.lock().state = STATE_MOUNTED;
+    update_info();
+    let _ = sync();
+
+    vfs::install_device(String::from("A:"), vfs::System::WFS);
+}
+
+/// Rewrites a volume in an older format to FORMAT_VERSION in place and makes
+/// it writable. Every entry is rewritten in the new layout, the free block
+/// bitmap is built and directories are rewritten as named records; file data
+/// stays where it is, version 2 data chains are turned into extents when the
+/// file is next written. The volume is marked as upgrading meanwhile, and an
+/// interrupted upgrade is finished at the next mount.
+pub fn upgrade() -> Result<(), vfs::Error> {
+    let (version, state, blocks) = {
+        let info = WFS_INFO.lock();
+        (info.version, info.state, info.blocks)
+    };
+    if version >= FORMAT_VERSION && state != STATE_UPGRADING {
+        return Err(vfs::Error::IllegalOperation);
+    }
+
+    let read_only = *READ_ONLY.lock();
+    *READ_ONLY.lock() = false;
+
+    // Decode everything before writing anything, so a name that doesn't fit
+    // leaves the volume as it was.
+    let mut entries: Vec<FileEntry> = Vec::new();
+    let mut lba: u64 = 1;
+    while lba != END_OF_CHAIN {
+        let sec = read_head(lba as usize);
+        // Fields added since version 2 are zero in older entries, and must
+        // not be dropped from entries this upgrade already rewrote.
+        let e = match FileEntry::layout_version(&sec, lba) {
+            Some(v) if entries.len() < blocks as usize => FileEntry::decode(&sec, if v >= 2 { FORMAT_VERSION } else { v }).unwrap_or_default(),
+            _ => {
+                *READ_ONLY.lock() = read_only;
+                return Err(vfs::Error::ReadError);
+            },
+        };
+        if !name_fits(e.name.as_str(), FORMAT_VERSION) {
+            println!("[WFS] Name of entry {} is too long for format version {}.", { e.id }, FORMAT_VERSION);
+            *READ_ONLY.lock() = read_only;
+            return Err(vfs::Error::NameTooLong);
+        }
+        lba = e.next_entry;
+        entries.push(e);
+    }
+
+    {
+        // Before version 3 these bytes meant nothing. A resumed upgrade keeps
+        // the bitmap it built, since directories may already use its blocks.
+        let mut info = WFS_INFO.lock();
+        if version < 3 && state != STATE_UPGRADING {
+            info.bitmap_start = 0;
+            info.bitmap_len = 0;
+        }
+        info.state = STATE_UPGRADING;
+    }
+    update_info();
+    sync().map_err(|e| vfs::Error::io(*WFS_DEV.lock(), e))?;
+
+    for e in entries.iter() {
+        write_block(e.location as usize, &e.encode(FORMAT_VERSION));
+    }
+    if WFS_INFO.lock().bitmap_len == 0 {
+        create_bitmap()?;
+        update_info();
+    }
+    sync().map_err(|e| vfs::Error::io(*WFS_DEV.lock(), e))?;
+
+    // Entries are written in the new layout from here on.
+    *WFS_VERSION.lock() = FORMAT_VERSION;
+    if let Err(e) = upgrade_dirs(&entries) {
+        *WFS_VERSION.lock() = version;
+        *READ_ONLY.lock() = read_only;
+        return Err(e);
+    }
+    sync().map_err(|e| vfs::Error::io(*WFS_DEV.lock(), e))?;
+
+    WFS_INFO.lock().version = FORMAT_VERSION;
+    WFS_INFO.lock().state = STATE_MOUNTED;
+    update_info();
+    if WFS_INFO.lock().journal_len == 0 {
+        create_journal();
+    }
+    sync().map_err(|e| vfs::Error::io(*WFS_DEV.lock(), e))?;
+
+    build_index();
+    println!("[WFS] Upgraded {} entries to format version {}.", entries.len(), FORMAT_VERSION);
+
+    // Repairs need the bitmap, so the check comes last. An interrupted
+    // upgrade may have been interrupted on an unclean volume.
+    if state != STATE_CLEAN {
+        println!("[WFS] Volume was not cleanly unmounted, checking.");
+        let r = check(true);
+        println!("[WFS] {} problems found, {} fixed.", r.problems, r.fixed);
+    }
+    Ok(())
+}
+
+// Gives directories that still list bare locations a record per child.
+// Those already rewritten by an interrupted upgrade are left alone.
+fn upgrade_dirs(entries: &[FileEntry]) -> Result<(), vfs::Error> {
+    let by_loc: BTreeMap<u64, usize> = entries.iter().enumerate().map(|(i, e)| (e.location, i)).collect();
+    for e in entries.iter().filter(|e| e.attributes.get_bit(vfs::ATTR_DIR)) {
+        let data = read_entry(*e)?;
+        if is_dir_data(&data) {
+            continue;
+        }
+        let records: Vec<DirRecord> = data.chunks_exact(8)
+            .filter_map(|c| by_loc.get(&u64::from_le_bytes(c.try_into().expect(""))))
+            .map(|&i| DirRecord::from_entry(&entries[i]))
+            .collect();
+        write_dir(*e, &records)?;
+    }
+    Ok(())
+}
+
+// Builds the free block bitmap of a volume from before version 3, where every
+// block in use carries a data signature, and stores it in the first free run
+// long enough for it.
+fn create_bitmap() -> Result<(), vfs::Error> {
+    let (blocks, bpb, j_start, j_len) = {
+        let info = WFS_INFO.lock();
+        (info.blocks, info.bytes_per_block, info.journal_start, info.journal_len)
+    };
+    let len = bitmap_blocks(blocks, bpb);
+
+    let mut map: Vec<u8> = vec![0; (len * bpb) as usize];
+    let mut in_use: u64 = 1;
+    mark(&mut map, 0);
+    for lba in 1..blocks {
+        if j_len > 0 && lba >= j_start && lba < j_start + j_len {
+            mark(&mut map, lba);
+        } else if read_head(lba as usize)[0..4] == DATA_SIG {
+            mark(&mut map, lba);
+            in_use += 1;
+        }
+    }
+
+    let mut start = 0;
+    let mut run = 0;
+    for lba in 2..blocks {
+        run = if marked(&map, lba) { 0 } else { run + 1 };
+        if run == len {
+            start = lba + 1 - len;
+            break;
+        }
+    }
+    if start == 0 {
+        println!("[WFS] No room for a free block bitmap of {} blocks.", len);
+        return Err(vfs::Error::NoSpace);
+    }
+
+    for lba in start..start + len {
+        mark(&mut map, lba);
+    }
+    let bs = bpb as usize;
+    for i in 0..len {
+        write_block((start + i) as usize, &map[i as usize * bs..(i as usize + 1) * bs]);
+    }
+
+    let mut info = WFS_INFO.lock();
+    info.bitmap_start = start;
+    info.bitmap_len = len;
+    info.blocks_in_use = in_use + len;
+    Ok(())
+}
+
+pub fn is_read_only() -> bool {
+    *READ_ONLY.lock()
+}
+
+/// Writes all cached sectors of the volume back to disk.
+pub fn sync() -> Result<(), block::Error> {
+    cache::flush_device(*WFS_DEV.lock())
+}
+
+pub fn unmount() -> Result<(), block::Error> {
+    if !is_read_only() {
+        WFS_INFO.lock().state = STATE_CLEAN;
+        update_info();
+    }
+    sync()
+}
+
+// The mounted volume as disk::scan_volume reads it.
+struct Volume;
+
+impl BlockReader for Volume {
+    type Error = vfs::Error;
+
+    fn read_block(&mut self, lba: u64) -> Result<Vec<u8>, vfs::Error> {
+        Ok(read_block(lba as usize))
+    }
+
+    fn read_head(&mut self, lba: u64) -> Result<[u8; 512], vfs::Error> {
+        Ok(read_head(lba as usize))
+    }
+
+    fn read_data(&mut self, entry: &FileEntry) -> Result<Vec<u8>, vfs::Error> {
+        read_entry(*entry)
+    }
+
+    fn read_bitmap(&mut self, index: u64) -> Result<Vec<u8>, vfs::Error> {
+        let start = WFS_INFO.lock().bitmap_start;
+        Ok(read_block((start + index) as usize))
+    }
+
+    fn report(&mut self, msg: &str) {
+        println!("[FSCK] {}", msg);
+    }
+}
+
+/// Walks the entry chain, the data of every entry and every directory
+/// listing and reports what is inconsistent. With `repair` the problems are
+/// fixed: links are relinked, data truncated to what is readable, orphans
+/// moved to A:, listings rebuilt, unreferenced blocks reclaimed and the
+/// InfoBlock counters recomputed.
+pub fn check(repair: bool) -> CheckReport {
+    let repair = repair && !is_read_only();
+    let info = *WFS_INFO.lock();
+    let mut scan = match scan_volume(&mut Volume, &info, repair) {
+        Ok(s) => s,
+        Err(e) => {
+            println!("[FSCK] Could not check the volume ({}).", e);
+            return Default::default();
+        },
+    };
+    if !repair || scan.entries.is_empty() {
+        return scan.report;
+    }
+
+    {
+        let mut info = WFS_INFO.lock();
+        info.files = scan.files;
+        info.blocks_in_use = scan.blocks_in_use;
+        info.final_entry = scan.final_entry;
+    }
+
+    for lba in scan.leaked {
+        write_block(lba as usize, &[0; 512]);
+    }
+    let (mark, clear): (Vec<(u64, bool)>, Vec<(u64, bool)>) = scan.bitmap.into_iter().partition(|b| b.1);
+    set_used(&mark.iter().map(|b| b.0).collect::<Vec<u64>>(), true);
+    set_used(&clear.iter().map(|b| b.0).collect::<Vec<u64>>(), false);
+    for lba in scan.chain_ends {
+        let mut raw = read_block(lba as usize);
+        raw[4..12].copy_from_slice(&END_OF_CHAIN.to_le_bytes());
+        write_block(lba as usize, &raw);
+    }
+
+    // The bitmap is right now, so extents can be rewritten the usual way.
+    let entries = &mut scan.entries;
+    for (i, extents, free) in scan.extents {
+        if let Err(e) = store_extents(&mut entries[i], &extents, &free) {
+            println!("[FSCK] could not rewrite the extents of {}: {}", entries[i].name.as_str(), e);
+        }
+    }
+
+    for i in 0..entries.len() {
+        if scan.dirty[i] {
+            write_block(entries[i].location as usize, &sector_from_entry(entries[i]));
+        }
+    }
+
+    // Rebuilt listings get new extents; write_entry keeps blocks_in_use in step.
+    for (i, records) in scan.listings {
+        if let Err(e) = write_dir(entries[i], &records) {
+            println!("[FSCK] could not rewrite directory {}: {}", entries[i].name.as_str(), e);
+        }
+    }
+
+    update_info();
+    build_index();
+    let _ = sync();
+
+    scan.report
+}
+
+fn mark(map: &mut Vec<u8>, lba: u64) {
+    map[lba as usize / 8].set_bit(lba as usize % 8, true);
+}
+
+fn marked(map: &Vec<u8>, lba: u64) -> bool {
+    map[lba as usize / 8].get_bit(lba as usize % 8)
+}
+
+// VFS functions
+
+pub fn find_node(parent_id: u64, name: String, dev_id: usize) -> Result<vfs::FsNode, vfs::Error> {
+    match find_entry_by_name(parent_id, name.to_string()) {
+        Ok(e) => {
+            let entry = e;
+            let node = vfs::FsNode {
+                name: name.to_string(),
+                device: dev_id,
+                parent_id: parent_id,
+                id: entry.id,
+                attributes: entry.attributes,
+                t_creation: entry.t_creation,
+                t_edit: entry.t_edit,
+                owner: entry.owner,
+                size: entry.size,
+                open: false,
+            };
+            return Ok(node);
+        },
+        Err(e) => return Err(e),
+    }
+}
+
+pub fn create_node(parent_id: u64, name: String, attributes: u8, owner: u8, dev_id: usize) -> Result<vfs::FsNode, vfs::Error> {
+    writable()?;
+    check_name(&name)?;
+
+    atomic(|| {
+        let parent = find_entry(parent_id)?;
+        if !parent.attributes.get_bit(vfs::ATTR_DIR) {
+            return Err(vfs::Error::ParentNotDirectory);
+        }
+        if find_entry_by_name(parent_id, name.to_string()).is_ok() {
+            return Err(vfs::Error::AlreadyExists);
+        }
+
+        let entry = create_entry(name.to_string(), parent_id, attributes, owner)?;
+        let node = vfs::FsNode {
+            name: name.to_string(),
+            device: dev_id,
+            parent_id: parent_id,
+            id: entry.id,
+            attributes: entry.attributes,
+            t_creation: entry.t_creation,
+            t_edit: entry.t_edit,
+            owner: entry.owner,
+            size: entry.size,
+            open: false,
+        };
+
+        // create_entry may have rewritten the parent (when it was the final
+        // entry of the chain), so don't reuse the copy read above.
+        let parent = find_entry(parent_id)?;
+        let mut records = read_dir(parent)?;
+        records.push(DirRecord::from_entry(&entry));
+        write_dir(parent, &records)?;
+
+        Ok(node)
+    })
+}
+
+pub fn read_node(parent_id: u64, name: String) -> Result<Vec<u8>, vfs::Error> {
+    match find_entry_by_name(parent_id, name) {
+        Ok(e) => {
+            return read_entry(e);
+        },
+        Err(e)=> return Err(e),
+    }
+}
+
+pub fn read_node_at(parent_id: u64, name: String, offset: u64, len: usize) -> Result<Vec<u8>, vfs::Error> {
+    let e = find_entry_by_name(parent_id, name)?;
+    read_entry_at(e, offset, len)
+}
+
+pub fn write_node(parent_id: u64, name: String, buf: Vec<u8>) -> Result<(), vfs::Error> {
+    writable()?;
+    atomic(|| {
+        let e = find_entry_by_name(parent_id, name)?;
+        write_entry(e, buf)
+    })
+}
+
+pub fn append_node(parent_id: u64, name: String, buf:Vec<u8>) -> Result<(), vfs::Error> {
+    writable()?;
+    atomic(|| {
+        let e = find_entry_by_name(parent_id, name)?;
+        append_entry(e, buf)
+    })
+}
+
+/// Removes one name: a file's, or an empty directory. Trees are taken
+/// apart by vfs::remove.
+pub fn delete_node(parent_id: u64, name: String) -> Result<(), vfs::Error> {
+    writable()?;
+    let e = find_entry_by_name(parent_id, name.to_string())?;
+    if !e.attributes.get_bit(vfs::ATTR_DIR) {
+        return unlink(parent_id, name);
+    }
+
+    atomic(|| {
+        let e = find_entry(e.id)?;
+        if !read_dir(e)?.is_empty() {
+            return Err(vfs::Error::NotEmpty);
+        }
+        delete_entry(e)
+    })
+}
+
+/// Gives the file `name` in `parent_id` another name, `new_name` in the
+/// directory `dir_id`. Directories can't be linked.
+pub fn link_node(parent_id: u64, name: String, dir_id: u64, new_name: String) -> Result<(), vfs::Error> {
+    writable()?;
+    check_name(&new_name)?;
+
+    atomic(|| {
+        let mut e = find_entry_by_name(parent_id, name)?;
+        if e.attributes.get_bit(vfs::ATTR_DIR) {
+            return Err(vfs::Error::IllegalOperation);
+        }
+        let dir = find_entry(dir_id)?;
+        if !dir.attributes.get_bit(vfs::ATTR_DIR) {
+            return Err(vfs::Error::ParentNotDirectory);
+        }
+        if find_entry_by_name(dir_id, new_name.to_string()).is_ok() {
+            return Err(vfs::Error::AlreadyExists);
+        }
+
+        e.links += 1;
+        write_block(e.location as usize, &sector_from_entry(e));
+
+        let mut records = read_dir(dir)?;
+        records.push(DirRecord {
+            name: Name::new(&new_name).map_err(|_| vfs::Error::NameTooLong)?,
+            ..DirRecord::from_entry(&e)
+        });
+        write_dir(dir, &records)
+    })
+}
+
+/// Creates a symbolic link holding the path `target`.
+pub fn create_symlink(parent_id: u64, name: String, target: String, owner: u8, dev_id: usize) -> Result<vfs::FsNode, vfs::Error> {
+    atomic(|| {
+        let node = create_node(parent_id, name.to_string(), *0u8.set_bit(vfs::ATTR_LNK, true), owner, dev_id)?;
+        let size = target.len() as u64;
+        write_node(parent_id, name.to_string(), target.into_bytes())?;
+        Ok(vfs::FsNode { size, ..node })
+    })
+}
+
+pub fn rename_node(parent_id: u64, name: String, new_name: String) -> Result<(), vfs::Error> {
+    writable()?;
+    atomic(|| {
+        let mut e = find_entry_by_name(parent_id, name.to_string())?;
+        check_name(&new_name)?;
+        if find_entry_by_name(parent_id, new_name.to_string()).is_ok() {
+            return Err(vfs::Error::AlreadyExists);
+        }
+        let new = Name::new(&new_name).map_err(|_| vfs::Error::NameTooLong)?;
+
+        // Other hard links keep their names; the entry holds the first one.
+        if e.parent_id == parent_id && e.name.as_str() == name {
+            e.name = new;
+            write_block(e.location as usize, &sector_from_entry(e));
+        }
+
+        // The name may hash to another bucket, so the directory is rebuilt.
+        let parent = find_entry(parent_id)?;
+        let mut records = read_dir(parent)?;
+        for r in records.iter_mut().filter(|r| r.location == e.location && r.name.as_str() == name) {
+            r.name = new;
+        }
+        write_dir(parent, &records)
+    })
+}
+
+pub fn set_attributes(parent_id: u64, name: String, attributes: u8) -> Result<(), vfs::Error> {
+    writable()?;
+    atomic(|| {
+        let mut e = find_entry_by_name(parent_id, name)?;
+        e.attributes = attributes;
+        write_block(e.location as usize, &sector_from_entry(e));
+
+        // Every directory naming the entry repeats the attributes.
+        for dir in link_dirs(&e)? {
+            let mut records = read_dir(dir)?;
+            for r in records.iter_mut().filter(|r| r.location == e.location) {
+                r.attributes = attributes;
+            }
+            write_dir(dir, &records)?;
+        }
+        Ok(())
+    })
+}
+
+pub fn get_root(dev_id: usize) -> Result<vfs::FsNode, vfs::Error> {
+    match find_entry(1) {
+        Ok(e) => {
+            let node = vfs::FsNode {
+                name: e.name.as_str().to_string(),
+                device: dev_id,
+                parent_id: e.parent_id,
+                id: e.id,
+                attributes: e.attributes,
+                t_creation: e.t_creation,
+                t_edit: e.t_edit,
+                owner: e.owner,
+                size: e.size,
+                open: false,
+            };
+            return Ok(node);
+        },
+        Err(e) => return Err(e),
+    }
+}
+
+pub fn find_node_by_id(id: u64, dev_id: usize) -> Result<vfs::FsNode, vfs::Error> {
+    match find_entry(id) {
+        Ok(e) => {
+            let node = vfs::FsNode {
+                name: e.name.as_str().to_string(),
+                device: dev_id,
+                parent_id: e.parent_id,
+                id: e.id,
+                attributes: e.attributes,
+                t_creation: e.t_creation,
+                t_edit: e.t_edit,
+                owner: e.owner,
+                size: e.size,
+                open: false,
+            };
+            return Ok(node);
+        },
+        Err(e) => return Err(e),
+    }
+}
+
+pub fn get_children(parent_id: u64, name: String, dev_id: usize) -> Result<Vec<vfs::FsNode>, vfs::Error> {
+    match find_entry_by_name(parent_id, name) {
+        Ok(e) => {
+            let entries = get_entry_children(e)?;
+            let mut ret: Vec<vfs::FsNode> = Vec::with_capacity(entries.len());
+
+            for (name, entry) in entries {
+                ret.push(vfs::FsNode {
+                    name: name.as_str().to_string(),
+                    device: dev_id,
+                    parent_id: e.id,
+                    id: entry.id,
+                    attributes: entry.attributes,
+                    t_creation: entry.t_creation,
+                    t_edit: entry.t_edit,
+                    owner: entry.owner,
+                    size: entry.size,
+                    open: false,
+                });
+            }
+            
+            return Ok(ret);
+        },
+        Err(e) => return Err(e),
+    }
+}
+
+/// The children of a directory as it lists them, without reading their entries.
+pub fn list_node(parent_id: u64, name: String) -> Result<Vec<vfs::DirEntry>, vfs::Error> {
+    let e = find_entry_by_name(parent_id, name)?;
+    Ok(read_dir(e)?.into_iter().map(|r| vfs::DirEntry { name: r.name.as_str().to_string(), id: r.id, attributes: r.attributes }).collect())
+}
+
+pub fn get_parent(id: u64, dev_id: usize) -> Result<vfs::FsNode, vfs::Error> {
+    if id != 1 {
+        let mut e = find_entry(id)?;
+        e = find_entry(e.parent_id)?;
+        let node = vfs::FsNode {
+            name: e.name.as_str().to_string(),
+            device: dev_id,
+            parent_id: e.parent_id,
+            id: e.id,
+            attributes: e.attributes,
+            t_creation: e.t_creation,
+            t_edit: e.t_edit,
+            owner: e.owner,
+            size: e.size,
+            open: false,
+        };
+        return Ok(node);
+    } else {
+        let e = find_entry(id)?;
+        let node = vfs::FsNode {
+            name: e.name.as_str().to_string(),
+            device: dev_id,
+            parent_id: e.parent_id,
+            id: e.id,
+            attributes: e.attributes,
+            t_creation: e.t_creation,
+            t_edit: e.t_edit,
+            owner: e.owner,
+            size: e.size,
+            open: false,
+        };
+        return Ok(node);
+    }
+
+}
+
+//WFS specific functions
+
+// Each extent is read with as few disk commands as the cache allows.
+fn read_entry(entry: FileEntry) -> Result<Vec<u8>, vfs::Error> {
+    // The size comes from disk; don't let it take the heap.
+    if entry.size > vfs::READ_MAX as u64 {
+        return Err(vfs::Error::NoSpace);
+    }
+    if entry.uses_chain() {
+        return read_chain(entry);
+    }
+
+    let size = entry.size as usize;
+    let bs = block_size();
+    let mut ret: Vec<u8> = Vec::with_capacity(size);
+
+    for x in load_extents(&entry)?.0 {
+        if ret.len() >= size {
+            break;
+        }
+        let left = (size - ret.len() + bs - 1) / bs;
+        let run = read_run(x.start, (x.len as usize).min(left))?;
+        let n = (size - ret.len()).min(run.len());
+        ret.extend_from_slice(&run[..n]);
+    }
+
+    if ret.len() < size {
+        return Err(vfs::Error::ReadError);
+    }
+    Ok(ret)
+}
+
+// Reads `len` bytes from `offset` without touching the blocks before it.
+fn read_entry_at(entry: FileEntry, offset: u64, len: usize) -> Result<Vec<u8>, vfs::Error> {
+    let size = entry.size;
+    if offset >= size || len == 0 {
+        return Ok(Vec::new());
+    }
+    let end = offset.saturating_add(len as u64).min(size);
+
+    if entry.uses_chain() {
+        let all = read_entry(entry)?;
+        return Ok(all[offset as usize..end as usize].to_vec());
+    }
+
+    let bs = block_size() as u64;
+    let (first, last) = (offset / bs, (end - 1) / bs);
+    let mut ret: Vec<u8> = Vec::with_capacity((last - first + 1) as usize * bs as usize);
+
+    // Index of the file's block at the start of each extent.
+    let mut idx: u64 = 0;
+    for x in load_extents(&entry)?.0 {
+        let next = idx + x.len as u64;
+        if next > first {
+            let from = if first > idx { first } else { idx };
+            let to = if last + 1 < next { last + 1 } else { next };
+            ret.extend_from_slice(&read_run(x.start + (from - idx), (to - from) as usize)?);
+        }
+        idx = next;
+        if idx > last {
+            break;
+        }
+    }
+
+    let skip = (offset - first * bs) as usize;
+    let n = (end - offset) as usize;
+    if ret.len() < skip + n {
+        return Err(vfs::Error::ReadError);
+    }
+    Ok(ret[skip..skip + n].to_vec())
+}
+
+// Version 2 data: a chain of blocks, each naming the next.
+fn read_chain(entry: FileEntry) -> Result<Vec<u8>, vfs::Error> {
+    let size = entry.size as usize;
+    let payload = data_payload(block_size() as u64);
+    let mut ret: Vec<u8> = Vec::with_capacity(size);
+    let mut lba = entry.start_sec;
+
+    while ret.len() < size {
+        if lba == END_OF_CHAIN || lba == FREE || lba == RESERVED {
+            return Err(vfs::Error::ReadError);
+        }
+
+        let raw = read_block(lba as usize);
+        if raw[0..4] != DATA_SIG {
+            return Err(vfs::Error::ReadError);
+        }
+
+        let n = if size - ret.len() < payload { size - ret.len() } else { payload };
+        ret.extend_from_slice(&raw[DATA_HEADER..DATA_HEADER + n]);
+        lba = u64::from_le_bytes(raw[4..12].try_into().expect(""));
+    }
+
+    return Ok(ret);
+}
+
+// Removes one name of a file. The entry and its data go with the last one;
+// before that, if the entry's own name is removed it takes over another.
+fn unlink(parent_id: u64, name: String) -> Result<(), vfs::Error> {
+    atomic(|| {
+        let mut e = find_entry_by_name(parent_id, name.to_string())?;
+        if e.links <= 1 {
+            return delete_entry(e);
+        }
+
+        let parent = find_entry(parent_id)?;
+        let mut records = read_dir(parent)?;
+        records.retain(|r| !(r.location == e.location && r.name.as_str() == name));
+        write_dir(parent, &records)?;
+
+        if e.parent_id == parent_id && e.name.as_str() == name {
+            for dir in link_dirs(&e)? {
+                if let Some(r) = read_dir(dir)?.into_iter().find(|r| r.location == e.location) {
+                    e.parent_id = dir.id;
+                    e.name = r.name;
+                    break;
+                }
+            }
+        }
+        e.links -= 1;
+        write_block(e.location as usize, &sector_from_entry(e));
+        Ok(())
+    })
+}
+
+// The directories holding a record of `e`. Only an entry with more than one
+// name makes this search them all.
+fn link_dirs(e: &FileEntry) -> Result<Vec<FileEntry>, vfs::Error> {
+    if e.links <= 1 {
+        return Ok(vec![find_entry(e.parent_id)?]);
+    }
+
+    let ids: Vec<u64> = ENTRY_INDEX.lock().keys().cloned().collect();
+    let mut res: Vec<FileEntry> = Vec::new();
+    for id in ids {
+        let d = find_entry(id)?;
+        if d.attributes.get_bit(vfs::ATTR_DIR) && read_dir(d)?.iter().any(|r| r.location == e.location) {
+            res.push(d);
+        }
+    }
+    Ok(res)
+}
+
+fn delete_entry(entry: FileEntry) -> Result<(), vfs::Error> {
+    // Drop the entry from its parent's list of children.
+    let parent = find_entry(entry.parent_id)?;
+    let mut records = read_dir(parent)?;
+    if let Some(i) = records.iter().position(|r| r.location == entry.location) {
+        records.remove(i);
+        write_dir(parent, &records)?;
+    }
+
+    // Unlink it from the entry chain. prev/next are read again because the
+    // parent rewrite above may have been one of them.
+    if entry.prev_entry != END_OF_CHAIN {
+        let mut prev = entry_from_sector(read_head(entry.prev_entry as usize));
+        prev.next_entry = entry.next_entry;
+        write_block(prev.location as usize, &sector_from_entry(prev));
+    }
+    if entry.next_entry != END_OF_CHAIN {
+        let mut next = entry_from_sector(read_head(entry.next_entry as usize));
+        next.prev_entry = entry.prev_entry;
+        write_block(next.location as usize, &sector_from_entry(next));
+    }
+    if WFS_INFO.lock().final_entry == entry.location {
+        WFS_INFO.lock().final_entry = entry.prev_entry;
+    }
+    if WFS_INFO.lock().files > 0 {
+        WFS_INFO.lock().files -= 1;
+    }
+
+    free_data(&entry);
+    release_blocks(&[entry.location]);
+    ENTRY_INDEX.lock().remove(&{ entry.id });
+
+    update_info();
+    Ok(())
+} 
+
+fn create_entry(name: String, parent_id: u64, attributes: u8, owner: u8) -> Result<FileEntry, vfs::Error> {
+    let block = find_empty_blocks(1)?[0];
+
+    WFS_INFO.lock().files += 1;
+    WFS_INFO.lock().blocks_in_use += 1;
+
+    // `files` counts live entries, so ids come from the highest one in use.
+    let f = ENTRY_INDEX.lock().keys().next_back().map_or(1, |k| k + 1);
+    let now = time::now();
+
+    let entry = FileEntry {
+        signature: DATA_SIG,
+        name: Name::new(&name).map_err(|_| vfs::Error::NameTooLong)?,
+        parent_id: parent_id,
+        id: f,
+        attributes: attributes,
+        t_creation: now,
+        t_edit: now,
+        owner: owner,
+        size: 0,
+        start_sec: END_OF_CHAIN,
+        next_entry: END_OF_CHAIN,
+        prev_entry: WFS_INFO.lock().final_entry,
+        location: block as u64,
+        extent_count: 0,
+        extents: Default::default(),
+        links: 1,
+    };
+    write_block(entry.location as usize, &sector_from_entry(entry)); 
+    ENTRY_INDEX.lock().insert(entry.id, entry.location);
+
+    let mut prev = entry_from_sector(read_head(WFS_INFO.lock().final_entry as usize));
+    prev.next_entry = entry.location;
+    write_block(prev.location as usize, &sector_from_entry(prev));
+
+    WFS_INFO.lock().final_entry = entry.location;
+    update_info();
+
+    return Ok(entry);
+}
+
+// Data is written to fresh extents and the entry switched over to them, so
+// the old contents stay intact until the transaction commits.
+fn write_entry(e: FileEntry, buf: Vec<u8>) -> Result<(), vfs::Error> {
+    let mut entry = e;
+    let old = entry;
+
+    let extents = write_extents(&buf)?;
+    let old_ext = if old.uses_chain() { Vec::new() } else { load_extents(&old).map(|l| l.1).unwrap_or_default() };
+    store_extents(&mut entry, &extents, &old_ext)?;
+    entry.size = buf.len() as u64;
+    entry.t_edit = time::now();
+    write_block(entry.location as usize, &sector_from_entry(entry));
+
+    release_data(&old);
+    update_info();
+
+    Ok(())
+}
+
+// Fills up the final block and adds extents for the rest, merging them with
+// the last one when the allocator found the blocks right after it.
+fn append_entry(e: FileEntry, buf: Vec<u8>) -> Result<(), vfs::Error> {
+    if e.size == 0 && e.extent_count == 0 {
+        return write_entry(e, buf);
+    }
+    // Version 2 chains are moved to extents on their first write.
+    if e.uses_chain() {
+        let mut data = read_entry(e)?;
+        data.extend_from_slice(&buf);
+        return write_entry(e, data);
+    }
+
+    let mut entry = e;
+    let bs = block_size();
+    let (mut extents, ext_blocks) = load_extents(&entry)?;
+    let last = match extents.last() {
+        Some(x) => x.end() - 1,
+        None => return Err(vfs::Error::ReadError),
+    };
+
+    let used = (entry.size % bs as u64) as usize;
+    let mut fits = 0;
+    if used != 0 {
+        let mut tail = read_block(last as usize);
+        fits = buf.len().min(bs - used);
+        tail[used..used + fits].copy_from_slice(&buf[..fits]);
+        write_block(last as usize, &tail);
+    }
+
+    for x in write_extents(&buf[fits..])? {
+        match extents.last_mut() {
+            Some(l) if l.end() == x.start && (l.len as u64 + x.len as u64) <= u32::MAX as u64 => l.len += x.len,
+            _ => extents.push(x),
+        }
+    }
+
+    store_extents(&mut entry, &extents, &ext_blocks)?;
+    entry.size += buf.len() as u64;
+    entry.t_edit = time::now();
+    write_block(entry.location as usize, &sector_from_entry(entry));
+    update_info();
+
+    return Ok(());
+}
+
+// Writes `buf` to newly allocated blocks, as few runs as the free space allows.
+fn write_extents(buf: &[u8]) -> Result<Vec<Extent>, vfs::Error> {
+    if buf.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let bs = block_size();
+    let n = (buf.len() + bs - 1) / bs;
+    let blocks: Vec<u64> = find_empty_blocks(n)?.iter().map(|b| *b as u64).collect();
+    let extents = extents_from_blocks(&blocks);
+
+    for (i, lba) in blocks.iter().enumerate() {
+        let end = ((i + 1) * bs).min(buf.len());
+        write_block(*lba as usize, &buf[i * bs..end]);
+    }
+
+    WFS_INFO.lock().blocks_in_use += n as u64;
+    Ok(extents)
+}
+
+// The extents of an entry and the extent blocks holding those past the
+// inline ones.
+fn load_extents(entry: &FileEntry) -> Result<(Vec<Extent>, Vec<u64>), vfs::Error> {
+    let count = entry.extent_count as usize;
+    let blocks = WFS_INFO.lock().blocks;
+    let mut extents: Vec<Extent> = entry.extents[..entry.inline_extents()].to_vec();
+    let mut ext_blocks: Vec<u64> = Vec::new();
+
+    let mut lba = entry.start_sec;
+    while extents.len() < count {
+        if lba == END_OF_CHAIN || lba >= blocks || ext_blocks.contains(&lba) {
+            return Err(vfs::Error::ReadError);
+        }
+        let b = ExtentBlock::decode(&read_block(lba as usize)).map_err(|_| vfs::Error::ReadError)?;
+        ext_blocks.push(lba);
+        extents.extend_from_slice(&b.extents);
+        lba = b.next;
+    }
+
+    extents.truncate(count);
+    Ok((extents, ext_blocks))
+}
+
+// Points `entry` at `extents`, moving those that don't fit in it to new
+// extent blocks, and frees the extent blocks in `old`. The entry itself is
+// left for the caller to write.
+fn store_extents(entry: &mut FileEntry, extents: &[Extent], old: &[u64]) -> Result<(), vfs::Error> {
+    let inline = extents.len().min(INLINE_EXTENTS);
+    entry.extent_count = extents.len() as u32;
+    entry.extents = Default::default();
+    entry.extents[..inline].copy_from_slice(&extents[..inline]);
+    entry.start_sec = END_OF_CHAIN;
+
+    let rest = &extents[inline..];
+    if !rest.is_empty() {
+        let bs = block_size();
+        let per = extents_per_block(bs as u64);
+        let n = (rest.len() + per - 1) / per;
+        let blocks = find_empty_blocks(n)?;
+
+        let mut block: Vec<u8> = vec![0; bs];
+        for (i, chunk) in rest.chunks(per).enumerate() {
+            let next = if i == n - 1 { END_OF_CHAIN } else { blocks[i + 1] as u64 };
+            for b in block.iter_mut() {
+                *b = 0;
+            }
+            ExtentBlock { next: next, extents: chunk.to_vec() }.encode_into(&mut block);
+            write_block(blocks[i], &block);
+        }
+
+        WFS_INFO.lock().blocks_in_use += n as u64;
+        entry.start_sec = blocks[0] as u64;
+    }
+
+    release_blocks(old);
+    Ok(())
+}
+
+// Frees the data blocks of an entry, but not its extent blocks.
+fn release_data(entry: &FileEntry) {
+    if entry.uses_chain() {
+        free_chain(entry.start_sec);
+        return;
+    }
+    if let Ok((extents, _)) = load_extents(entry) {
+        for x in extents {
+            let blocks: Vec<u64> = (x.start..x.end()).collect();
+            release_blocks(&blocks);
+        }
+    }
+}
+
+// Frees everything an entry's data takes.
+fn free_data(entry: &FileEntry) {
+    if !entry.uses_chain() {
+        if let Ok((_, ext_blocks)) = load_extents(entry) {
+            release_blocks(&ext_blocks);
+        }
+    }
+    release_data(entry);
+}
+
+fn data_chain(start: u64) -> Vec<u64> {
+    let mut res: Vec<u64> = Vec::new();
+    let blocks = WFS_INFO.lock().blocks;
+
+    let mut lba = start;
+    while lba != END_OF_CHAIN && lba != FREE && lba != RESERVED && lba < blocks {
+        let raw = read_head(lba as usize);
+        if raw[0..4] != DATA_SIG || res.contains(&lba) {
+            break;
+        }
+        res.push(lba);
+        lba = u64::from_le_bytes(raw[4..12].try_into().expect(""));
+    }
+
+    res
+}
+
+fn free_chain(start: u64) {
+    let chain = data_chain(start);
+    release_blocks(&chain);
+}
+
+// Returns blocks to the free pool. Inside a transaction they are not handed
+// out again before it has committed, so a crash never loses data still
+// referenced on disk.
+fn release_blocks(lbas: &[u64]) {
+    if lbas.is_empty() {
+        return;
+    }
+    {
+        let mut info = WFS_INFO.lock();
+        let n = lbas.len() as u64;
+        info.blocks_in_use = if info.blocks_in_use > n { info.blocks_in_use - n } else { 1 };
+    }
+
+    set_used(lbas, false);
+
+    let mut txn = TXN.lock();
+    if txn.active {
+        txn.freed.extend_from_slice(lbas);
+    }
+}
+
+// Sets or clears the bits of `lbas` in the free block bitmap. The bitmap is
+// metadata like any other, so inside a transaction the change is staged.
+fn set_used(lbas: &[u64], used: bool) {
+    let start = WFS_INFO.lock().bitmap_start;
+    let bits = block_size() as u64 * 8;
+    let mut cur: Option<(u64, Vec<u8>)> = None;
+
+    for lba in lbas {
+        let b = start + lba / bits;
+        if cur.as_ref().map(|c| c.0) != Some(b) {
+            if let Some((cb, map)) = cur.take() {
+                write_block(cb as usize, &map);
+            }
+            cur = Some((b, read_block(b as usize)));
+        }
+        if let Some((_, map)) = cur.as_mut() {
+            let bit = (lba % bits) as usize;
+            map[bit / 8].set_bit(bit % 8, used);
+        }
+    }
+
+    if let Some((cb, map)) = cur {
+        write_block(cb as usize, &map);
+    }
+}
+
+fn is_used(lba: u64) -> bool {
+    let start = WFS_INFO.lock().bitmap_start;
+    let bits = block_size() as u64 * 8;
+    let bit = (lba % bits) as usize;
+    read_block((start + lba / bits) as usize)[bit / 8].get_bit(bit % 8)
+}
+
+fn find_entry(id: u64) -> Result<FileEntry, vfs::Error> {
+    let location = ENTRY_INDEX.lock().get(&id).cloned();
+    if let Some(l) = location {
+        let e = entry_from_sector(read_head(l as usize));
+        if e.signature == DATA_SIG && e.id == id {
+            return Ok(e);
+        }
+    }
+
+    // Not indexed, or the index is stale: walk the chain and remember the result.
+    let mut temp = entry_from_sector(read_head(1));
+    let mut steps: u64 = 0;
+    loop {
+        if temp.id == id {
+            ENTRY_INDEX.lock().insert(id, temp.location);
+            return Ok(temp);
+        }
+
+        steps += 1;
+        if temp.next_entry == END_OF_CHAIN || steps > WFS_INFO.lock().blocks {
+            ENTRY_INDEX.lock().remove(&id);
+            return Err(vfs::Error::FileNotFound);
+        }
+        temp = entry_from_sector(read_head(temp.next_entry as usize));
+    }
+}
+
+// Walks the entry chain once and records where every id lives, so lookups
+// by id don't have to follow `next_entry` pointers across the disk.
+fn build_index() {
+    let mut index: BTreeMap<u64, u64> = BTreeMap::new();
+    let blocks = WFS_INFO.lock().blocks;
+
+    let mut lba = 1;
+    while lba != END_OF_CHAIN && (index.len() as u64) <= blocks {
+        let e = entry_from_sector(read_head(lba as usize));
+        if e.signature != DATA_SIG || e.validate(blocks).is_err() || index.contains_key(&{ e.id }) {
+            println!("[WFS] Entry chain broken at block {}.", lba);
+            break;
+        }
+        index.insert(e.id, lba);
+        lba = e.next_entry;
+    }
+
+    *ENTRY_INDEX.lock() = index;
+}
+
+fn find_entry_by_name(parent_id: u64, name: String) -> Result<FileEntry, vfs::Error> {
+    let parent = find_entry(parent_id)?;
+    if !parent.attributes.get_bit(vfs::ATTR_DIR) {
+        return Err(vfs::Error::FileNotFound);
+    }
+
+    match lookup_dir(parent, &name)? {
+        Some(r) => {
+            let e = entry_from_sector(read_head(r.location as usize));
+            if e.signature != DATA_SIG || e.id != r.id {
+                return Err(vfs::Error::ReadError);
+            }
+            Ok(e)
+        },
+        None => Err(vfs::Error::FileNotFound),
+    }
+}
+
+// Each child under the name this directory gives it, which for a hard link
+// isn't the one in its entry.
+fn get_entry_children(e: FileEntry) -> Result<Vec<(Name, FileEntry)>, vfs::Error> {
+    let records = read_dir(e)?;
+    let mut res: Vec<(Name, FileEntry)> = Vec::with_capacity(records.len());
+    for r in records {
+        res.push((r.name, entry_from_sector(read_head(r.location as usize))));
+    }
+    Ok(res)
+}
+
+// What a directory lists. Before version 4 that is only where its children
+// are, so their entries are read for the rest.
+fn read_dir(dir: FileEntry) -> Result<Vec<DirRecord>, vfs::Error> {
+    if !dir.attributes.get_bit(vfs::ATTR_DIR) {
+        return Err(vfs::Error::IllegalOperation);
+    }
+
+    let data = read_entry(dir)?;
+    if *WFS_VERSION.lock() < 4 && !is_dir_data(&data) {
+        let mut res: Vec<DirRecord> = Vec::with_capacity(data.len() / 8);
+        for c in data.chunks_exact(8) {
+            let e = entry_from_sector(read_head(u64::from_le_bytes(c.try_into().expect("")) as usize));
+            res.push(DirRecord::from_entry(&e));
+        }
+        return Ok(res);
+    }
+    decode_dir(&data).map_err(|_| vfs::Error::ReadError)
+}
+
+// Directories converted by an upgrade that didn't finish start with this too.
+fn is_dir_data(data: &[u8]) -> bool {
+    data.len() >= 4 && data[0..4] == DIR_SIG
+}
+
+fn write_dir(dir: FileEntry, records: &[DirRecord]) -> Result<(), vfs::Error> {
+    write_entry(dir, encode_dir(records, block_size() as u64))
+}
+
+// Finds `name` in a directory. A hashed one costs a read of the block with
+// the bucket table and one of the bucket the name falls in; smaller ones
+// fit in a single block anyway.
+fn lookup_dir(dir: FileEntry, name: &str) -> Result<Option<DirRecord>, vfs::Error> {
+    let bs = block_size();
+    if *WFS_VERSION.lock() < 4 || dir.size <= bs as u64 {
+        return Ok(read_dir(dir)?.into_iter().find(|r| r.name.as_str() == name));
+    }
+
+    let head = read_entry_at(dir, 0, bs)?;
+    let h = DirHeader::decode(&head).map_err(|_| vfs::Error::ReadError)?;
+    if h.buckets == 0 {
+        return Ok(read_dir(dir)?.into_iter().find(|r| r.name.as_str() == name));
+    }
+
+    let pos = DirHeader::table_entry(bucket_of(name, h.buckets));
+    let table = if pos + 8 <= head.len() { head[pos..pos + 8].to_vec() } else { read_entry_at(dir, pos as u64, 8)? };
+    if table.len() < 8 {
+        return Err(vfs::Error::ReadError);
+    }
+    let from = u32::from_le_bytes(table[0..4].try_into().expect("")) as u64;
+    let to = u32::from_le_bytes(table[4..8].try_into().expect("")) as u64;
+    if from > to || to > dir.size {
+        return Err(vfs::Error::ReadError);
+    }
+
+    let bucket = read_entry_at(dir, from, (to - from) as usize)?;
+    let records = decode_records(&bucket).map_err(|_| vfs::Error::ReadError)?;
+    Ok(records.into_iter().find(|r| r.name.as_str() == name))
+}
+
+
+// Finds `n` blocks free in the bitmap, going on from where the last search
+// stopped so consecutive allocations come out contiguous, and marks them used.
+// Blocks freed by the running transaction are skipped until it commits.
+fn find_empty_blocks(n: usize) -> Result<Vec<usize>, vfs::Error> {
+    let mut res: Vec<usize> = Vec::with_capacity(n);
+
+    let (blocks, start) = {
+        let info = WFS_INFO.lock();
+        (info.blocks, info.bitmap_start)
+    };
+    let freed = TXN.lock().freed.clone();
+    let bits = block_size() as u64 * 8;
+    let mut cur: Option<(u64, Vec<u8>)> = None;
+
+    let mut lba = *ALLOC_HINT.lock();
+    for _ in 2..blocks {
+        if res.len() >= n {
+            break;
+        }
+        if lba >= blocks || lba < 2 {
+            lba = 2;
+        }
+
+        let b = start + lba / bits;
+        if cur.as_ref().map(|c| c.0) != Some(b) {
+            cur = Some((b, read_block(b as usize)));
+        }
+        let bit = (lba % bits) as usize;
+        let free = cur.as_ref().map_or(false, |c| !c.1[bit / 8].get_bit(bit % 8));
+        if free && !freed.contains(&lba) {
+            res.push(lba as usize);
+        }
+        lba += 1;
+    }
+
+    if res.len() < n {
+        return Err(vfs::Error::NoSpace);
+    }
+    *ALLOC_HINT.lock() = lba;
+
+    let lbas: Vec<u64> = res.iter().map(|b| *b as u64).collect();
+    set_used(&lbas, true);
+
+    let mut txn = TXN.lock();
+    if txn.active {
+        txn.allocated.extend_from_slice(&lbas);
+    }
+
+    return Ok(res);
+}
+
+// Runs `f` as one transaction: either all of its metadata updates reach the
+// disk or, after a crash, none of them do.
+fn atomic<T>(f: impl FnOnce() -> Result<T, vfs::Error>) -> Result<T, vfs::Error> {
+    {
+        let mut txn = TXN.lock();
+        if txn.active {
+            drop(txn);
+            return f();
+        }
+        txn.active = true;
+        txn.overflow = false;
+        txn.lbas.clear();
+        txn.images.clear();
+        txn.allocated.clear();
+        txn.freed.clear();
+    }
+
+    let res = f();
+    let overflow = TXN.lock().overflow;
+
+    match res {
+        Ok(_) if !overflow => commit().and(res),
+        Ok(_) => {
+            abort();
+            Err(vfs::Error::NoSpace)
+        },
+        Err(e) => {
+            abort();
+            Err(e)
+        },
+    }
+}
+
+// The journal is only cleared once every home block and flush succeeded, so a
+// failure part way leaves it to be replayed at the next mount.
+fn commit() -> Result<(), vfs::Error> {
+    let dev = *WFS_DEV.lock();
+    let (j_start, j_len) = {
+        let info = WFS_INFO.lock();
+        (info.journal_start, info.journal_len)
+    };
+
+    let (seq, lbas, images) = {
+        let mut txn = TXN.lock();
+        txn.active = false;
+        txn.seq += 1;
+        txn.allocated.clear();
+        txn.freed.clear();
+        (txn.seq, core::mem::replace(&mut txn.lbas, Vec::new()), core::mem::replace(&mut txn.images, Vec::new()))
+    };
+    let n = lbas.len();
+    let io = |e| vfs::Error::io(dev, e);
+
+    if n > 0 && j_len > 0 {
+        if let Err(e) = write_journal(dev, j_start, seq, &lbas, &images) {
+            // Without a commit sector nothing is replayed, and no home block
+            // was written, so the disk still holds the state before.
+            reload();
+            return Err(e);
+        }
+    }
+
+    for i in 0..n {
+        write_raw(dev, lbas[i], &images[i]).map_err(io)?;
+    }
+
+    if n > 0 && j_len > 0 {
+        cache::flush_device(dev).map_err(io)?;
+        write_raw(dev, j_start, &[0; 512]).map_err(io)?;
+        cache::flush_device(dev).map_err(io)?;
+    }
+    Ok(())
+}
+
+// Descriptors, block images and, once those are on disk, the commit sector.
+fn write_journal(dev: usize, j_start: u64, seq: u64, lbas: &[u64], images: &[Vec<u8>]) -> Result<(), vfs::Error> {
+    let io = |e| vfs::Error::io(dev, e);
+    let n = lbas.len();
+    let descs = (n + LBAS_PER_DESC - 1) / LBAS_PER_DESC;
+
+    for d in 0..descs {
+        let mut sec = [0u8; 512];
+        sec[0..4].copy_from_slice(&JOURNAL_DESC_SIG);
+        sec[4..12].copy_from_slice(&seq.to_le_bytes());
+        sec[12..20].copy_from_slice(&(n as u64).to_le_bytes());
+        for i in d * LBAS_PER_DESC..n.min((d + 1) * LBAS_PER_DESC) {
+            let off = 20 + (i - d * LBAS_PER_DESC) * 8;
+            sec[off..off + 8].copy_from_slice(&lbas[i].to_le_bytes());
+        }
+        write_raw(dev, j_start + d as u64, &sec).map_err(io)?;
+    }
+    for i in 0..n {
+        write_raw(dev, j_start + (descs + i) as u64, &images[i]).map_err(io)?;
+    }
+    cache::flush_device(dev).map_err(io)?;
+
+    let mut sec = [0u8; 512];
+    sec[0..4].copy_from_slice(&JOURNAL_COMMIT_SIG);
+    sec[4..12].copy_from_slice(&seq.to_le_bytes());
+    sec[12..20].copy_from_slice(&(n as u64).to_le_bytes());
+    sec[20..28].copy_from_slice(&checksum(images).to_le_bytes());
+    write_raw(dev, j_start + (descs + n) as u64, &sec).map_err(io)?;
+    cache::flush_device(dev).map_err(io)
+}
+
+// Blocks the failed operation allocated were only marked in the staged
+// bitmap, so dropping the staged images frees them again.
+fn abort() {
+    {
+        let mut txn = TXN.lock();
+        txn.active = false;
+        txn.lbas.clear();
+        txn.images.clear();
+        txn.freed.clear();
+        txn.allocated.clear();
+    }
+    reload();
+}
+
+// The in-memory InfoBlock and index may have been changed by a failed
+// operation; reload both from the (untouched) disk.
+fn reload() {
+    let _ = read_info();
+    build_index();
+}
+
+// Applies a transaction left in the journal by a crash after its commit
+// sector was written. Anything less than a complete, checksummed transaction
+// is discarded.
+fn replay_journal() {
+    let dev = *WFS_DEV.lock();
+    let j_start = WFS_INFO.lock().journal_start;
+
+    let head = read_head(j_start as usize);
+    if head[0..4] != JOURNAL_DESC_SIG {
+        return;
+    }
+
+    let seq = u64::from_le_bytes(head[4..12].try_into().expect(""));
+    let n = u64::from_le_bytes(head[12..20].try_into().expect("")) as usize;
+    let descs = (n + LBAS_PER_DESC - 1) / LBAS_PER_DESC;
+
+    let mut valid = n > 0 && n <= TXN_CAPACITY;
+    let mut lbas: Vec<u64> = Vec::with_capacity(n);
+    for d in 0..if valid { descs } else { 0 } {
+        let sec = read_head((j_start + d as u64) as usize);
+        if sec[0..4] != JOURNAL_DESC_SIG || sec[4..12] != seq.to_le_bytes() {
+            valid = false;
+            break;
+        }
+        for i in d * LBAS_PER_DESC..n.min((d + 1) * LBAS_PER_DESC) {
+            let off = 20 + (i - d * LBAS_PER_DESC) * 8;
+            lbas.push(u64::from_le_bytes(sec[off..off + 8].try_into().expect("")));
+        }
+    }
+
+    if valid {
+        let mut images: Vec<Vec<u8>> = Vec::with_capacity(n);
+        for i in 0..n {
+            images.push(read_block((j_start + (descs + i) as u64) as usize));
+        }
+
+        let c = read_head((j_start + (descs + n) as u64) as usize);
+        valid = c[0..4] == JOURNAL_COMMIT_SIG
+            && c[4..12] == seq.to_le_bytes()
+            && c[12..20] == (n as u64).to_le_bytes()
+            && c[20..28] == checksum(&images).to_le_bytes();
+
+        if valid {
+            println!("[WFS] Replaying journal ({} blocks).", n);
+            for i in 0..n {
+                if let Err(e) = write_raw(dev, lbas[i], &images[i]) {
+                    println!("[WFS] Could not replay block {}: {}. Keeping the journal.", lbas[i], e);
+                    return;
+                }
+            }
+            if let Err(e) = cache::flush_device(dev) {
+                println!("[WFS] Could not replay the journal: {}. Keeping it.", e);
+                return;
+            }
+            TXN.lock().seq = seq;
+        }
+    }
+
+    if !valid {
+        println!("[WFS] Discarding incomplete journal transaction.");
+    }
+
+    let cleared = write_raw(dev, j_start, &[0; 512]).and_then(|_| cache::flush_device(dev));
+    if let Err(e) = cleared {
+        println!("[WFS] Could not clear the journal: {}", e);
+    }
+}
+
+// Volumes made before the journal existed get one in their last sectors, as
+// long as nothing is stored there.
+fn create_journal() {
+    let blocks = WFS_INFO.lock().blocks;
+    if blocks < JOURNAL_LEN * 4 {
+        println!("[WFS] Volume too small for a journal.");
+        return;
+    }
+
+    let start = blocks - JOURNAL_LEN;
+    for lba in start..blocks {
+        if is_used(lba) {
+            println!("[WFS] No room for a journal, running without one.");
+            return;
+        }
+    }
+
+    let journal: Vec<u64> = (start..blocks).collect();
+    set_used(&journal, true);
+    write_block(start as usize, &[0; 512]);
+    WFS_INFO.lock().journal_start = start;
+    WFS_INFO.lock().journal_len = JOURNAL_LEN;
+    update_info();
+    println!("[WFS] Created journal at block {}.", start);
+}
+
+fn writable() -> Result<(), vfs::Error> {
+    if is_read_only() {
+        return Err(vfs::Error::PermissionDenied);
+    }
+    Ok(())
+}
+
+// Names must also fit the entry format of the mounted volume.
+fn check_name(name: &str) -> Result<(), vfs::Error> {
+    vfs::check_name(name)?;
+
+    let version = *WFS_VERSION.lock();
+    if version == 1 && name.contains(' ') {
+        return Err(vfs::Error::InvalidName);
+    }
+    if !name_fits(name, version) {
+        return Err(vfs::Error::NameTooLong);
+    }
+    Ok(())
+}
+
+fn sector_from_entry(f: FileEntry) -> [u8; 512] {
+    f.encode(*WFS_VERSION.lock())
+}
+
+fn entry_from_sector(sec: [u8; 512]) -> FileEntry {
+    // Anything that doesn't decode comes back without a signature.
+    FileEntry::decode(&sec, *WFS_VERSION.lock()).unwrap_or_default()
+}
+
+fn block_size() -> usize {
+    *WFS_BLOCK_SIZE.lock()
+}
+
+// Reads only the first sector of a block: enough for entries, the header of a
+// data block and the signature checks of the allocator.
+fn read_head(lba: usize) -> [u8; 512] {
+    let mut sec = [0u8; 512];
+    {
+        let txn = TXN.lock();
+        if txn.active {
+            if let Some(i) = txn.lbas.iter().position(|l| *l == lba as u64) {
+                sec.copy_from_slice(&txn.images[i][..512]);
+                return sec;
+            }
+        }
+    }
+
+    let first = lba as u64 * (block_size() / 512) as u64;
+    match cache::read(*WFS_DEV.lock(), first) {
+        Ok(buf) => sec.copy_from_slice(&buf[..512]),
+        Err(e) => println!("[WFS] Could not read block {}: {}", lba, e),
+    }
+    sec
+}
+
+fn read_block(lba: usize) -> Vec<u8> {
+    {
+        let txn = TXN.lock();
+        if txn.active {
+            if let Some(i) = txn.lbas.iter().position(|l| *l == lba as u64) {
+                return txn.images[i].clone();
+            }
+        }
+    }
+
+    let dev = *WFS_DEV.lock();
+    let spb = (block_size() / 512) as u64;
+    let mut block: Vec<u8> = Vec::with_capacity(block_size());
+    for i in 0..spb {
+        match cache::read(dev, lba as u64 * spb + i) {
+            Ok(buf) => block.extend_from_slice(&buf[..512]),
+            Err(e) => {
+                println!("[WFS] Could not read block {}: {}", lba, e);
+                block.resize(block_size(), 0);
+                break;
+            },
+        }
+    }
+    block
+}
+
+// Reads `n` consecutive blocks. Unless one of them is staged in the running
+// transaction this goes to the cache (and from there the disk) in one request.
+fn read_run(lba: u64, n: usize) -> Result<Vec<u8>, vfs::Error> {
+    let staged = {
+        let txn = TXN.lock();
+        txn.active && txn.lbas.iter().any(|l| *l >= lba && *l < lba + n as u64)
+    };
+    if staged {
+        let mut buf: Vec<u8> = Vec::with_capacity(n * block_size());
+        for i in 0..n as u64 {
+            buf.extend_from_slice(&read_block((lba + i) as usize));
+        }
+        return Ok(buf);
+    }
+
+    let spb = (block_size() / 512) as u64;
+    let dev = *WFS_DEV.lock();
+    cache::read_many(dev, lba * spb, n as u64 * spb).map_err(|e| {
+        println!("[WFS] Could not read blocks {}..{}: {}", lba, lba + n as u64, e);
+        vfs::Error::io(dev, e)
+    })
+}
+
+// Inside a transaction, writes to blocks that are already part of the
+// filesystem are staged until commit. Blocks the transaction allocated
+// itself go straight to the cache. `data` shorter than a block is padded
+// with zeros.
+fn write_block(lba: usize, data: &[u8]) {
+    {
+        let mut txn = TXN.lock();
+        let lba = lba as u64;
+        if txn.active && !txn.allocated.contains(&lba) {
+            let mut image = data.to_vec();
+            image.resize(block_size(), 0);
+            match txn.lbas.iter().position(|l| *l == lba) {
+                Some(i) => txn.images[i] = image,
+                None if txn.lbas.len() < TXN_CAPACITY => {
+                    txn.lbas.push(lba);
+                    txn.images.push(image);
+                },
+                None => txn.overflow = true,
+            }
+            return;
+        }
+    }
+
+    if let Err(e) = write_raw(*WFS_DEV.lock(), lba as u64, data) {
+        println!("[WFS] Could not write block {}: {}", lba, e);
+    }
+}
+
+fn write_raw(dev: usize, lba: u64, data: &[u8]) -> Result<(), block::Error> {
+    let spb = (block_size() / 512) as u64;
+    for i in 0..spb {
+        let start = (i as usize * 512).min(data.len());
+        let end = (start + 512).min(data.len());
+        let mut sec = [0u8; 512];
+        sec[..end - start].copy_from_slice(&data[start..end]);
+
+        cache::write(dev, lba * spb + i, &sec)?;
+    }
+    Ok(())
+}
+
+fn read_info() -> Result<(), DecodeError> {
+    let info = InfoBlock::decode(&read_head(0))?;
+    *WFS_INFO.lock() = info;
+    *WFS_VERSION.lock() = info.version;
+    *WFS_BLOCK_SIZE.lock() = info.bytes_per_block as usize;
+    Ok(())
+}
+
+fn update_info() {
+    let info = WFS_INFO.lock().encode();
+    write_block(0, &info);
+}
+
+pub fn demo() {
+    println!("[Demo] Creating and opening file 'test'...");
+    let mut n = vfs::create_node(0, String::from("test"), 0, 0, 0).unwrap();
+    n.open();
+
+    println!("[Demo] Writing to file...");
+    n.write(b"Hello, world!\n".to_vec()).unwrap();
+
+    println!("[Demo] Reading file...\n");
+    let buffer = n.read().unwrap();
+
+    vga_buffer::set_color(vga_buffer::Color::LightBlue, vga_buffer::Color::Black);
+    for b in buffer {
+        print!("{}", b as char);
+    } 
+    vga_buffer::set_color(vga_buffer::Color::White, vga_buffer::Color::Black);
+
+    println!("\n[Demo] Appending data...");
+    n.append(b"This is another line!\n".to_vec()).unwrap();
+
+    println!("[Demo] Reading again...\n");
+    let buffer = n.read().unwrap();
+
+    vga_buffer::set_color(vga_buffer::Color::LightBlue, vga_buffer::Color::Black);
+    for b in buffer {
+        print!("{}", b as char);   
+    }
+    vga_buffer::set_color(vga_buffer::Color::White, vga_buffer::Color::Black);
+
+    println!("\n[Demo] Closing file...");
+    n.close();
+}
+
@@ -0,0 +1,99 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use alloc::string::String;
+use bit_field::BitField;
+use os::{serial_print, serial_println};
+use os::{console, ramfs, vfs};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use os::allocator;
+    use os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    // Without disks A: is a ramfs volume, owned by the system like on wFS.
+    ramfs::init();
+    let dev = vfs::find_device("A:").expect("A: not mounted");
+    console::set_cdir(vfs::get_root(dev).unwrap());
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os::test_panic_handler(info)
+}
+
+fn root() -> vfs::FsNode {
+    vfs::get_root(vfs::find_device("A:").unwrap()).unwrap()
+}
+
+#[test_case]
+fn user_creates_in_system_directory() {
+    serial_print!("user_creates_in_system_directory... ");
+
+    let a = root();
+    let home = vfs::create_node(a.id, String::from("Home"), *0u8.set_bit(vfs::ATTR_DIR, true), vfs::SYSTEM_USER, a.device).unwrap();
+
+    vfs::set_user(1);
+    let mine = vfs::create_node(home.id, String::from("mine.txt"), 0, vfs::current_user(), home.device).unwrap();
+    assert_eq!(mine.owner, 1);
+    vfs::remove(&mine, false).unwrap();
+    vfs::set_user(vfs::SYSTEM_USER);
+
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn user_keeps_off_others_files() {
+    serial_print!("user_keeps_off_others_files... ");
+
+    let a = root();
+    let theirs = vfs::create_node(a.id, String::from("theirs.txt"), 0, vfs::SYSTEM_USER, a.device).unwrap();
+
+    vfs::set_user(1);
+    assert_eq!(vfs::remove(&theirs, false), Err(vfs::Error::PermissionDenied));
+    let mut n = theirs.clone();
+    n.open().unwrap();
+    assert_eq!(n.write(b"mine now".to_vec()), Err(vfs::Error::PermissionDenied));
+    n.close().unwrap();
+    vfs::set_user(vfs::SYSTEM_USER);
+
+    vfs::remove(&theirs, false).unwrap();
+
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn read_only_directory_takes_nothing() {
+    serial_print!("read_only_directory_takes_nothing... ");
+
+    let a = root();
+    let mut locked = vfs::create_node(a.id, String::from("locked"), *0u8.set_bit(vfs::ATTR_DIR, true), vfs::SYSTEM_USER, a.device).unwrap();
+    locked.set_attributes(*0u8.set_bit(vfs::ATTR_DIR, true).set_bit(vfs::ATTR_RO, true)).unwrap();
+
+    vfs::set_user(1);
+    assert_eq!(vfs::create_node(locked.id, String::from("x"), 0, 1, locked.device).err(), Some(vfs::Error::PermissionDenied));
+    vfs::set_user(vfs::SYSTEM_USER);
+
+    serial_println!("[ok]");
+}
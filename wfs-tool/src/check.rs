@@ -0,0 +1,80 @@
+//Host side of `wfs::check`: the passes in disk::scan_volume over an image file.
+
+use crate::disk::*;
+use crate::volume::{Error, Result, Volume};
+
+impl BlockReader for Volume {
+    type Error = Error;
+
+    fn read_block(&mut self, lba: u64) -> Result<Vec<u8>> {
+        Volume::read_block(self, lba)
+    }
+
+    fn read_head(&mut self, lba: u64) -> Result<[u8; SECTOR_SIZE]> {
+        Volume::read_head(self, lba)
+    }
+
+    fn read_data(&mut self, entry: &FileEntry) -> Result<Vec<u8>> {
+        Volume::read_data(self, entry)
+    }
+
+    fn read_bitmap(&mut self, index: u64) -> Result<Vec<u8>> {
+        Ok(self.bitmap_block(index).to_vec())
+    }
+
+    fn report(&mut self, msg: &str) {
+        println!("{}", msg);
+    }
+}
+
+pub fn check(v: &mut Volume, repair: bool) -> Result<CheckReport> {
+    if v.info.state == STATE_MOUNTED {
+        println!("volume was not cleanly unmounted");
+    }
+    let pending = v.journal_pending()?;
+
+    let info = v.info;
+    let mut scan = scan_volume(v, &info, repair)?;
+    if pending {
+        scan.report.problem(v, false, false, String::from("journal holds an unreplayed transaction"));
+    }
+    if !repair || scan.entries.is_empty() {
+        return Ok(scan.report);
+    }
+
+    v.info.files = scan.files;
+    v.info.blocks_in_use = scan.blocks_in_use;
+    v.info.final_entry = scan.final_entry;
+    v.info.state = STATE_CLEAN;
+
+    for lba in scan.leaked {
+        v.write_block(lba, &[])?;
+    }
+    for (lba, used) in scan.bitmap {
+        v.set_used(lba, used);
+    }
+    for lba in scan.chain_ends {
+        let mut raw = v.read_block(lba)?;
+        raw[4..12].copy_from_slice(&END_OF_CHAIN.to_le_bytes());
+        v.write_block(lba, &raw)?;
+    }
+
+    let entries = &mut scan.entries;
+    for (i, extents, free) in scan.extents {
+        v.set_extents(&mut entries[i], &extents)?;
+        v.free(&free);
+    }
+
+    for (i, e) in entries.iter().enumerate() {
+        if scan.dirty[i] {
+            v.write_block(e.location, &e.encode(v.info.version))?;
+        }
+    }
+
+    for (i, records) in scan.listings {
+        v.write_records(&mut entries[i], &records)?;
+    }
+
+    v.write_info()?;
+    Ok(scan.report)
+}
@@ -0,0 +1,437 @@
+//wfs-tool: build and inspect wFS disk images on the host.
+//The on-disk structures are shared with the kernel through os/src/wfs/disk.rs.
+
+extern crate alloc;
+
+#[path = "../../os/src/wfs/disk.rs"]
+#[allow(dead_code)]
+mod disk;
+mod check;
+mod volume;
+
+use disk::*;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process;
+use volume::{name_string, Error, Result, Volume};
+
+const USAGE: &str = "usage: wfs-tool <command> <image> [args]
+
+commands:
+    format <image> <size> [block]     create an empty wFS image, sizes in bytes or with K|M|G,
+                                      blocks default to 4K
+    info <image>                      dump the InfoBlock
+    entries <image>                   dump the entry chain and the data of every entry
+    ls <image> [path] [-r]            list a directory, recursively with -r
+    put <image> <host path> [dir]     copy a host file or directory tree into dir (default A:)
+    get <image> <path> <host path>    extract a file or directory tree
+    check <image> [-r]                check consistency, repair with -r
+
+paths inside the image are relative to A:, e.g. A:/Home/hello.txt";
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 3 {
+        eprintln!("{}", USAGE);
+        process::exit(2);
+    }
+
+    let image = Path::new(&args[2]);
+    let rest = &args[3..];
+    let res = match args[1].as_str() {
+        "format" if rest.len() == 1 || rest.len() == 2 => format(image, &rest[0], rest.get(1).map(|s| s.as_str())),
+        "info" => info(image),
+        "entries" => entries(image),
+        "ls" => ls(image, rest),
+        "put" if !rest.is_empty() => put(image, &rest[0], rest.get(1).map(|s| s.as_str()).unwrap_or("A:")),
+        "get" if rest.len() == 2 => get(image, &rest[0], Path::new(&rest[1])),
+        "check" => check(image, rest.iter().any(|a| a == "-r")),
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        },
+    };
+
+    match res {
+        Ok(true) => {},
+        Ok(false) => process::exit(1),
+        Err(e) => {
+            eprintln!("wfs-tool: {}", e);
+            process::exit(1);
+        },
+    }
+}
+
+fn parse_bytes(s: &str) -> Option<u64> {
+    let (num, mult) = match s.chars().last()? {
+        'K' | 'k' => (&s[..s.len() - 1], 1024),
+        'M' | 'm' => (&s[..s.len() - 1], 1024 * 1024),
+        'G' | 'g' => (&s[..s.len() - 1], 1024 * 1024 * 1024),
+        _ => (s, 1),
+    };
+    num.parse::<u64>().ok().map(|n| n * mult)
+}
+
+fn attr_string(a: u8) -> String {
+    let mut s = String::new();
+    s.push(if a & (1 << ATTR_DIR) != 0 {
+        'd'
+    } else if a & (1 << ATTR_LNK) != 0 {
+        'l'
+    } else {
+        '-'
+    });
+    s.push(if a & (1 << ATTR_RO) != 0 { 'r' } else { '-' });
+    s.push(if a & (1 << ATTR_SYS) != 0 { 's' } else { '-' });
+    s.push(if a & (1 << ATTR_HDN) != 0 { 'h' } else { '-' });
+    s
+}
+
+fn format(image: &Path, size: &str, block: Option<&str>) -> Result<bool> {
+    let (bytes, block_size) = match (parse_bytes(size), block.map_or(Some(DEFAULT_BLOCK_SIZE), parse_bytes)) {
+        (Some(b), Some(bs)) => (b, bs),
+        _ => {
+            eprintln!("bad size: {} {}", size, block.unwrap_or(""));
+            return Ok(false);
+        },
+    };
+    let v = Volume::format(image, bytes / SECTOR_SIZE as u64, block_size)?;
+    println!("{}: {} blocks of {}B, journal at {}", image.display(), { v.info.blocks }, block_size, { v.info.journal_start });
+    Ok(true)
+}
+
+fn info(image: &Path) -> Result<bool> {
+    let mut v = Volume::open(image, false)?;
+    let i = v.info;
+    println!("state:           {}", match i.state {
+        STATE_CLEAN => "clean",
+        STATE_MOUNTED => "mounted",
+        _ => "upgrading",
+    });
+    println!("signature:       {}", String::from_utf8_lossy(&i.signature));
+    println!("format version:  {}", i.version);
+    println!("blocks:          {}", i.blocks);
+    println!("blocks in use:   {}", i.blocks_in_use);
+    println!("files:           {}", i.files);
+    println!("bytes per block: {}", i.bytes_per_block);
+    println!("final entry:     {}", i.final_entry);
+    println!("journal:         {} blocks at {}{}", i.journal_len, i.journal_start,
+        if v.journal_pending()? { " (transaction pending)" } else { "" });
+    if i.version >= 3 {
+        println!("bitmap:          {} blocks at {}", i.bitmap_len, i.bitmap_start);
+    }
+    Ok(true)
+}
+
+fn entries(image: &Path) -> Result<bool> {
+    let mut v = Volume::open(image, false)?;
+    println!("{:>8} {:>6} {:>6} {:4} {:>10} {:>8} {:>8}  name / data", "block", "id", "parent", "attr", "size", "prev", "next");
+
+    let mut lba = 1;
+    while lba != END_OF_CHAIN {
+        let e = v.entry_at(lba)?;
+        let link = |l: u64| if l == END_OF_CHAIN { String::from("-") } else { l.to_string() };
+        let names = if e.links > 1 { format!(" ({} names)", e.links) } else { String::new() };
+        println!("{:>8} {:>6} {:>6} {:4} {:>10} {:>8} {:>8}  {}{}", lba, { e.id }, { e.parent_id }, attr_string(e.attributes),
+            { e.size }, link(e.prev_entry), link(e.next_entry), name_string(e.name), names);
+
+        // Extents as start+length, version 2 chains as a -> b -> c.
+        let data = if e.uses_chain() {
+            v.chain(e.start_sec).map(|c| c.iter().map(|l| l.to_string()).collect::<Vec<String>>().join(" -> "))
+        } else {
+            v.extents(&e).map(|(x, blocks)| {
+                let mut s: Vec<String> = x.iter().map(|x| format!("{}+{}", x.start, x.len)).collect();
+                if !blocks.is_empty() {
+                    s.push(format!("(extent blocks {:?})", blocks));
+                }
+                s.join(" ")
+            })
+        };
+        match data {
+            Ok(d) if !d.is_empty() => println!("{:>58}{}", "", d),
+            Ok(_) => {},
+            Err(err) => println!("{:>58}{}", "", err),
+        }
+        lba = e.next_entry;
+    }
+    Ok(true)
+}
+
+fn ls(image: &Path, args: &[String]) -> Result<bool> {
+    let mut v = Volume::open(image, false)?;
+    let recursive = args.iter().any(|a| a == "-r");
+    let path = args.iter().find(|a| *a != "-r").map(|s| s.as_str()).unwrap_or("A:");
+
+    let dir = v.lookup(path)?;
+    list(&mut v, &dir, "", recursive)?;
+    Ok(true)
+}
+
+fn list(v: &mut Volume, dir: &FileEntry, prefix: &str, recursive: bool) -> Result<()> {
+    for c in v.children(dir)? {
+        let name = format!("{}{}", prefix, name_string(c.name));
+        println!("{} {:>10}  {}{}", attr_string(c.attributes), { c.size }, name, if c.is_dir() { "/" } else { "" });
+        if recursive && c.is_dir() {
+            list(v, &c, &format!("{}/", name), recursive)?;
+        }
+    }
+    Ok(())
+}
+
+fn put(image: &Path, host: &str, dest: &str) -> Result<bool> {
+    let mut v = Volume::open(image, true)?;
+    let dir = v.lookup(dest)?;
+    if !dir.is_dir() {
+        return Err(Error::NotDirectory(dest.to_string()));
+    }
+
+    let src = Path::new(host);
+    let ok = copy_in(&mut v, src, dir.id)?;
+    println!("{} files, {} of {} blocks in use", { v.info.files }, { v.info.blocks_in_use }, { v.info.blocks });
+    Ok(ok)
+}
+
+// Copies a host file or directory tree. Entries whose names can't be stored
+// are skipped with a warning rather than aborting the whole copy.
+fn copy_in(v: &mut Volume, src: &Path, parent_id: u64) -> Result<bool> {
+    let name = src.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    if !v.valid_name(&name) {
+        eprintln!("skipping {}: {}", src.display(), Error::BadName(name));
+        return Ok(false);
+    }
+
+    if src.is_dir() {
+        let dir = v.create(parent_id, &name, 1 << ATTR_DIR, &[])?;
+        let mut paths: Vec<_> = fs::read_dir(src)?.filter_map(|e| e.ok()).map(|e| e.path()).collect();
+        paths.sort();
+
+        let mut ok = true;
+        for p in paths {
+            ok &= copy_in(v, &p, dir.id)?;
+        }
+        Ok(ok)
+    } else {
+        let data = fs::read(src)?;
+        v.create(parent_id, &name, 0, &data)?;
+        Ok(true)
+    }
+}
+
+fn get(image: &Path, path: &str, host: &Path) -> Result<bool> {
+    let mut v = Volume::open(image, false)?;
+    let e = v.lookup(path)?;
+    copy_out(&mut v, &e, host)?;
+    Ok(true)
+}
+
+fn copy_out(v: &mut Volume, e: &FileEntry, host: &Path) -> Result<()> {
+    if e.is_dir() {
+        fs::create_dir_all(host)?;
+        for c in v.children(e)? {
+            copy_out(v, &c, &host.join(name_string(c.name)))?;
+        }
+    } else {
+        let data = v.read_data(e)?;
+        fs::write(host, data)?;
+    }
+    Ok(())
+}
+
+fn check(image: &Path, repair: bool) -> Result<bool> {
+    let mut v = Volume::open(image, repair)?;
+    let r = check::check(&mut v, repair)?;
+
+    if r.problems == 0 {
+        println!("{} entries checked, no problems found.", r.entries);
+    } else if repair {
+        println!("{} entries checked, {} problems found, {} fixed.", r.entries, r.problems, r.fixed);
+    } else {
+        println!("{} entries checked, {} problems found. Run check -r to repair.", r.entries, r.problems);
+    }
+    Ok(r.problems == r.fixed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn temp(name: &str) -> PathBuf {
+        let mut p = env::temp_dir();
+        p.push(format!("wfs-tool-{}-{}", process::id(), name));
+        p
+    }
+
+    #[test]
+    fn format_put_get_round_trip() {
+        for &bs in &[MIN_BLOCK_SIZE, DEFAULT_BLOCK_SIZE, MAX_BLOCK_SIZE] {
+            round_trip(bs);
+        }
+    }
+
+    fn round_trip(block_size: u64) {
+        let img = temp(&format!("rt-{}.img", block_size));
+        let src = temp(&format!("rt-src-{}", block_size));
+        let out = temp(&format!("rt-out-{}", block_size));
+        fs::create_dir_all(src.join("sub")).unwrap();
+        let big: Vec<u8> = (0..5000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(src.join("hello.txt"), b"Hello from the host").unwrap();
+        fs::write(src.join("notes \u{fc}ber wOS.txt"), b"long name").unwrap();
+        fs::write(src.join("sub").join("big.bin"), &big).unwrap();
+
+        // Sectors for 1024 blocks.
+        Volume::format(&img, 1024 * block_size / SECTOR_SIZE as u64, block_size).unwrap();
+        assert!(put(&img, src.to_str().unwrap(), "A:").unwrap());
+
+        let path = format!("A:/{}", src.file_name().unwrap().to_str().unwrap());
+        let mut v = Volume::open(&img, false).unwrap();
+        let e = v.lookup(&format!("{}/sub/big.bin", path)).unwrap();
+        assert_eq!(v.read_data(&e).unwrap(), big);
+        drop(v);
+
+        assert!(get(&img, &path, &out).unwrap());
+        assert_eq!(fs::read(out.join("hello.txt")).unwrap(), b"Hello from the host");
+        assert_eq!(fs::read(out.join("notes \u{fc}ber wOS.txt")).unwrap(), b"long name");
+        assert_eq!(fs::read(out.join("sub").join("big.bin")).unwrap(), big);
+
+        let mut v = Volume::open(&img, false).unwrap();
+        let r = check::check(&mut v, false).unwrap();
+        assert_eq!(r.problems, 0);
+        assert_eq!(r.entries, 7);
+
+        fs::remove_file(&img).unwrap();
+        fs::remove_dir_all(&src).unwrap();
+        fs::remove_dir_all(&out).unwrap();
+    }
+
+    #[test]
+    fn check_repairs_leaks_and_links() {
+        let img = temp("fsck.img");
+        let mut v = Volume::format(&img, 1024, MIN_BLOCK_SIZE).unwrap();
+        let a = v.create(1, "a.txt", 0, &[1; 1200]).unwrap();
+        v.create(1, "b.txt", 0, b"b").unwrap();
+
+        // Leak a block, break a back link and skew a counter.
+        v.set_used(700, true);
+        let mut e = v.entry_at(a.location).unwrap();
+        e.prev_entry = 42;
+        v.write_block(e.location, &e.encode(v.info.version)).unwrap();
+        v.info.files = 9;
+        v.write_info().unwrap();
+
+        let r = check::check(&mut v, true).unwrap();
+        assert_eq!(r.problems, 3);
+        assert_eq!(r.fixed, 3);
+
+        let r = check::check(&mut v, false).unwrap();
+        assert_eq!(r.problems, 0);
+        let a = v.lookup("a.txt").unwrap();
+        assert_eq!(v.read_data(&a).unwrap(), vec![1; 1200]);
+        assert!(!v.is_used(700));
+
+        fs::remove_file(&img).unwrap();
+    }
+
+    #[test]
+    fn fragmented_files_use_extent_blocks() {
+        let img = temp("frag.img");
+        let mut v = Volume::format(&img, 2048, MIN_BLOCK_SIZE).unwrap();
+
+        // Take every other free block, so a 40 block file needs 40 extents.
+        for lba in 2..v.info.blocks {
+            if lba % 2 == 0 && !v.is_used(lba) {
+                v.set_used(lba, true);
+            }
+        }
+
+        let data: Vec<u8> = (0..40 * 512u32).map(|i| (i % 253) as u8).collect();
+        let f = v.create(1, "frag.bin", 0, &data).unwrap();
+        assert_eq!({ f.extent_count }, 40);
+        let (x, blocks) = v.extents(&f).unwrap();
+        assert_eq!(x.len(), 40);
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(v.read_data(&f).unwrap(), data);
+
+        // Nothing refers to the taken blocks; a repair frees them and leaves
+        // the file alone.
+        let r = check::check(&mut v, true).unwrap();
+        assert!(r.problems > 0);
+        assert_eq!(r.problems, r.fixed);
+        assert_eq!(check::check(&mut v, false).unwrap().problems, 0);
+        let f = v.lookup("frag.bin").unwrap();
+        assert_eq!(v.read_data(&f).unwrap(), data);
+
+        // A contiguous file is a single extent.
+        let mut v = Volume::format(&img, 2048, MIN_BLOCK_SIZE).unwrap();
+        let c = v.create(1, "contig.bin", 0, &data).unwrap();
+        assert_eq!({ c.extent_count }, 1);
+        assert_eq!(v.read_data(&c).unwrap(), data);
+        assert_eq!(check::check(&mut v, false).unwrap().problems, 0);
+
+        fs::remove_file(&img).unwrap();
+    }
+
+    #[test]
+    fn large_directories_are_hashed() {
+        let img = temp("bigdir.img");
+        let mut v = Volume::format(&img, 4096, MIN_BLOCK_SIZE).unwrap();
+        for i in 0..100 {
+            v.create(1, &format!("file {}", i), 0, &[i as u8]).unwrap();
+        }
+
+        let a = v.lookup("A:").unwrap();
+        let data = v.read_data(&a).unwrap();
+        assert!(DirHeader::decode(&data).unwrap().buckets > 1);
+        assert_eq!(v.records(&a).unwrap().len(), 100);
+        let f = v.lookup("file 57").unwrap();
+        assert_eq!(v.read_data(&f).unwrap(), vec![57]);
+        assert_eq!(check::check(&mut v, false).unwrap().problems, 0);
+
+        // A record that disagrees with its entry is rewritten.
+        let mut e = v.entry_at(f.location).unwrap();
+        e.attributes |= 1 << ATTR_HDN;
+        v.write_block(e.location, &e.encode(v.info.version)).unwrap();
+        let r = check::check(&mut v, true).unwrap();
+        assert_eq!(r.problems, 1);
+        assert_eq!(r.fixed, 1);
+        assert_eq!(check::check(&mut v, false).unwrap().problems, 0);
+        let a = v.lookup("A:").unwrap();
+        let r = v.records(&a).unwrap().into_iter().find(|r| r.location == f.location).unwrap();
+        assert_eq!(r.attributes, 1 << ATTR_HDN);
+
+        fs::remove_file(&img).unwrap();
+    }
+
+    #[test]
+    fn hard_links_are_counted() {
+        let img = temp("links.img");
+        let mut v = Volume::format(&img, 1024, MIN_BLOCK_SIZE).unwrap();
+        let a = v.create(1, "a.txt", 0, b"shared").unwrap();
+        let sub = v.create(1, "sub", 1 << ATTR_DIR, &[]).unwrap();
+
+        // Name a.txt in sub as well.
+        let mut sub = v.entry_at(sub.location).unwrap();
+        let mut records = v.records(&sub).unwrap();
+        records.push(DirRecord { name: Name::new("b.txt").unwrap(), ..DirRecord::from_entry(&a) });
+        v.write_records(&mut sub, &records).unwrap();
+        let mut e = v.entry_at(a.location).unwrap();
+        e.links = 2;
+        v.write_block(e.location, &e.encode(v.info.version)).unwrap();
+
+        assert_eq!(check::check(&mut v, false).unwrap().problems, 0);
+        let b = v.lookup("sub/b.txt").unwrap();
+        assert_eq!(b.location, a.location);
+        assert_eq!(v.read_data(&b).unwrap(), b"shared");
+
+        // Dropping the link without the count is repaired.
+        v.write_records(&mut sub, &[]).unwrap();
+        let r = check::check(&mut v, true).unwrap();
+        assert_eq!(r.problems, 1);
+        assert_eq!(r.fixed, 1);
+        assert_eq!(v.entry_at(a.location).unwrap().links, 1);
+        assert_eq!(check::check(&mut v, false).unwrap().problems, 0);
+
+        fs::remove_file(&img).unwrap();
+    }
+}
@@ -0,0 +1,541 @@
+//A wFS volume inside a host image file. Mirrors what the kernel does in
+//os/src/wfs, minus the journal: the tool is the only writer while it runs
+//and leaves the volume cleanly unmounted. Only volumes in the current format
+//are written to.
+
+use crate::disk::*;
+use std::convert::TryInto;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    BadInfo(DecodeError),
+    TooSmall,
+    BadBlockSize(u64),
+    JournalPending,
+    UpgradePending,
+    OldFormat(u32),
+    NotFound(String),
+    NotDirectory(String),
+    AlreadyExists(String),
+    BadName(String),
+    NoSpace,
+    Corrupt(u64),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::BadInfo(e) => write!(f, "invalid InfoBlock: {:?}", e),
+            Error::TooSmall => write!(f, "image too small"),
+            Error::BadBlockSize(n) => write!(f, "bad block size {}, must be a power of two from {} to {}", n, MIN_BLOCK_SIZE, MAX_BLOCK_SIZE),
+            Error::JournalPending => write!(f, "journal holds an unreplayed transaction, mount the volume in wOS first"),
+            Error::UpgradePending => write!(f, "an upgrade of the volume was interrupted, mount it in wOS first"),
+            Error::OldFormat(v) => write!(f, "volume has format version {}, upgrade it with 'wfs upgrade' in wOS before writing to it", v),
+            Error::NotFound(p) => write!(f, "{}: not found", p),
+            Error::NotDirectory(p) => write!(f, "{}: not a directory", p),
+            Error::AlreadyExists(p) => write!(f, "{}: already exists", p),
+            Error::BadName(n) => write!(f, "{}: names must be 1-{} bytes without '/'", n, NAME_MAX),
+            Error::NoSpace => write!(f, "no space left on volume"),
+            Error::Corrupt(lba) => write!(f, "broken chain at block {}", lba),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+pub struct Volume {
+    file: File,
+    pub info: InfoBlock,
+    next_free: u64,
+    // The free block bitmap, written back with the InfoBlock.
+    bitmap: Vec<u8>,
+}
+
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+pub fn name_string(name: Name) -> String {
+    name.as_str().to_string()
+}
+
+impl Volume {
+    /// Creates (or truncates) `path` to `sectors` sectors and formats it the
+    /// way `wfs::install` does: InfoBlock, root entry, the `A:` directory and
+    /// a journal at the end.
+    pub fn format(path: &Path, sectors: u64, block_size: u64) -> Result<Volume> {
+        if !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&block_size) || !block_size.is_power_of_two() {
+            return Err(Error::BadBlockSize(block_size));
+        }
+        let blocks = sectors / (block_size / SECTOR_SIZE as u64);
+        if blocks < JOURNAL_LEN * 4 {
+            return Err(Error::TooSmall);
+        }
+        let bitmap_len = bitmap_blocks(blocks, block_size);
+
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path)?;
+        file.set_len(sectors * SECTOR_SIZE as u64)?;
+
+        let mut v = Volume {
+            file,
+            info: InfoBlock {
+                state: STATE_CLEAN,
+                signature: WFS_SIG,
+                blocks,
+                blocks_in_use: 2 + bitmap_len,
+                files: 0,
+                bytes_per_block: block_size,
+                final_entry: 1,
+                journal_start: blocks - JOURNAL_LEN,
+                journal_len: JOURNAL_LEN,
+                version: FORMAT_VERSION,
+                bitmap_start: 2,
+                bitmap_len,
+            },
+            next_free: 2 + bitmap_len,
+            bitmap: vec![0; (bitmap_len * block_size) as usize],
+        };
+        for lba in (0..2 + bitmap_len).chain(blocks - JOURNAL_LEN..blocks) {
+            v.set_used(lba, true);
+        }
+
+        let t = now();
+        let root = FileEntry {
+            signature: DATA_SIG,
+            name: Name::default(),
+            parent_id: 0,
+            id: 0,
+            attributes: (1 << ATTR_RO) | (1 << ATTR_SYS) | (1 << ATTR_DIR),
+            t_creation: t,
+            t_edit: t,
+            owner: 0,
+            size: 0,
+            start_sec: END_OF_CHAIN,
+            next_entry: END_OF_CHAIN,
+            prev_entry: END_OF_CHAIN,
+            location: 1,
+            extent_count: 0,
+            extents: Default::default(),
+            links: 1,
+        };
+        v.write_block(1, &root.encode(v.info.version))?;
+        v.write_info()?;
+
+        v.create(0, "A:", (1 << ATTR_DIR) | (1 << ATTR_SYS), &[])?;
+        Ok(v)
+    }
+
+    pub fn open(path: &Path, writable: bool) -> Result<Volume> {
+        let file = OpenOptions::new().read(true).write(writable).open(path)?;
+        let mut v = Volume {
+            file,
+            info: InfoBlock { bytes_per_block: MIN_BLOCK_SIZE, ..Default::default() },
+            next_free: 2,
+            bitmap: Vec::new(),
+        };
+
+        v.info = InfoBlock::decode(&v.read_head(0)?).map_err(Error::BadInfo)?;
+        if v.info.state == STATE_UPGRADING {
+            return Err(Error::UpgradePending);
+        }
+        if writable && v.info.version < FORMAT_VERSION {
+            return Err(Error::OldFormat(v.info.version));
+        }
+        if writable && v.journal_pending()? {
+            return Err(Error::JournalPending);
+        }
+        for i in 0..v.info.bitmap_len {
+            let block = v.read_block(v.info.bitmap_start + i)?;
+            v.bitmap.extend_from_slice(&block);
+        }
+        v.next_free = 2;
+        Ok(v)
+    }
+
+    pub fn is_used(&self, lba: u64) -> bool {
+        self.bitmap[lba as usize / 8] & (1 << (lba % 8)) != 0
+    }
+
+    /// Block `index` of the bitmap as held in memory.
+    pub fn bitmap_block(&self, index: u64) -> &[u8] {
+        let bs = self.block_size();
+        &self.bitmap[index as usize * bs..(index as usize + 1) * bs]
+    }
+
+    pub fn set_used(&mut self, lba: u64, used: bool) {
+        if used {
+            self.bitmap[lba as usize / 8] |= 1 << (lba % 8);
+        } else {
+            self.bitmap[lba as usize / 8] &= !(1 << (lba % 8));
+        }
+    }
+
+    // Names have to fit the entry layout of this volume's format version.
+    pub fn valid_name(&self, name: &str) -> bool {
+        !name.is_empty() && name != "." && name != ".." && !name.contains('/') && name_fits(name, self.info.version)
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.info.bytes_per_block as usize
+    }
+
+    /// The first sector of block `lba`, which holds entries, the InfoBlock
+    /// and data headers.
+    pub fn read_head(&mut self, lba: u64) -> Result<[u8; SECTOR_SIZE]> {
+        let mut sec = [0u8; SECTOR_SIZE];
+        self.file.seek(SeekFrom::Start(lba * self.info.bytes_per_block))?;
+        self.file.read_exact(&mut sec)?;
+        Ok(sec)
+    }
+
+    pub fn read_block(&mut self, lba: u64) -> Result<Vec<u8>> {
+        let mut block = vec![0u8; self.block_size()];
+        self.file.seek(SeekFrom::Start(lba * self.info.bytes_per_block))?;
+        self.file.read_exact(&mut block)?;
+        Ok(block)
+    }
+
+    /// Writes `data` to block `lba`, padded with zeros to a whole block.
+    pub fn write_block(&mut self, lba: u64, data: &[u8]) -> Result<()> {
+        let mut block = data.to_vec();
+        block.resize(self.block_size(), 0);
+        self.file.seek(SeekFrom::Start(lba * self.info.bytes_per_block))?;
+        self.file.write_all(&block)?;
+        Ok(())
+    }
+
+    /// Writes the InfoBlock and the bitmap.
+    pub fn write_info(&mut self) -> Result<()> {
+        let sec = self.info.encode();
+        self.write_block(0, &sec)?;
+        let bitmap = std::mem::take(&mut self.bitmap);
+        for (i, block) in bitmap.chunks(self.block_size()).enumerate() {
+            self.write_block(self.info.bitmap_start + i as u64, block)?;
+        }
+        self.bitmap = bitmap;
+        Ok(())
+    }
+
+    pub fn journal_pending(&mut self) -> Result<bool> {
+        if self.info.journal_len == 0 {
+            return Ok(false);
+        }
+        let head = self.read_head(self.info.journal_start)?;
+        Ok(head[0..4] == JOURNAL_DESC_SIG)
+    }
+
+    /// Every entry, in chain order.
+    pub fn entries(&mut self) -> Result<Vec<FileEntry>> {
+        let mut res: Vec<FileEntry> = Vec::new();
+        let mut lba = 1;
+        while lba != END_OF_CHAIN {
+            if lba >= self.info.blocks || res.len() as u64 > self.info.blocks {
+                return Err(Error::Corrupt(lba));
+            }
+            let e = self.entry_at(lba)?;
+            lba = e.next_entry;
+            res.push(e);
+        }
+        Ok(res)
+    }
+
+    pub fn entry_at(&mut self, lba: u64) -> Result<FileEntry> {
+        FileEntry::decode(&self.read_head(lba)?, self.info.version).map_err(|_| Error::Corrupt(lba))
+    }
+
+    pub fn find_id(&mut self, id: u64) -> Result<FileEntry> {
+        self.entries()?.into_iter().find(|e| { e.id } == id).ok_or(Error::NotFound(format!("#{}", id)))
+    }
+
+    /// Blocks of the version 2 data chain starting at `start`.
+    pub fn chain(&mut self, start: u64) -> Result<Vec<u64>> {
+        let mut res: Vec<u64> = Vec::new();
+        let mut lba = start;
+        while lba != END_OF_CHAIN && lba != FREE {
+            if lba >= self.info.blocks || res.contains(&lba) {
+                return Err(Error::Corrupt(lba));
+            }
+            let d = DataHeader::decode(&self.read_head(lba)?).map_err(|_| Error::Corrupt(lba))?;
+            res.push(lba);
+            lba = d.next_sec;
+        }
+        Ok(res)
+    }
+
+    /// The extents of `e` and the extent blocks holding those past the
+    /// inline ones.
+    pub fn extents(&mut self, e: &FileEntry) -> Result<(Vec<Extent>, Vec<u64>)> {
+        let count = e.extent_count as usize;
+        let mut extents: Vec<Extent> = e.extents[..e.inline_extents()].to_vec();
+        let mut blocks: Vec<u64> = Vec::new();
+        let mut lba = e.start_sec;
+        while extents.len() < count {
+            if lba == END_OF_CHAIN || lba >= self.info.blocks || blocks.contains(&lba) {
+                return Err(Error::Corrupt(lba));
+            }
+            let b = ExtentBlock::decode(&self.read_block(lba)?).map_err(|_| Error::Corrupt(lba))?;
+            blocks.push(lba);
+            extents.extend_from_slice(&b.extents);
+            lba = b.next;
+        }
+        extents.truncate(count);
+        Ok((extents, blocks))
+    }
+
+    // Reads `n` consecutive blocks at once.
+    fn read_run(&mut self, lba: u64, n: u64) -> Result<Vec<u8>> {
+        let mut buf = vec![0u8; (n * self.info.bytes_per_block) as usize];
+        self.file.seek(SeekFrom::Start(lba * self.info.bytes_per_block))?;
+        self.file.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    pub fn read_data(&mut self, e: &FileEntry) -> Result<Vec<u8>> {
+        let size = e.size as usize;
+        let mut res: Vec<u8> = Vec::with_capacity(size);
+
+        if e.uses_chain() {
+            let payload = data_payload(self.info.bytes_per_block);
+            for lba in self.chain(e.start_sec)? {
+                if res.len() >= size {
+                    break;
+                }
+                let block = self.read_block(lba)?;
+                let n = (size - res.len()).min(payload);
+                res.extend_from_slice(&block[DATA_HEADER..DATA_HEADER + n]);
+            }
+        } else {
+            let bs = self.block_size();
+            for x in self.extents(e)?.0 {
+                if res.len() >= size {
+                    break;
+                }
+                if !x.within(self.info.blocks) {
+                    return Err(Error::Corrupt(x.start));
+                }
+                let n = ((size - res.len()).div_ceil(bs) as u64).min(x.len as u64);
+                let run = self.read_run(x.start, n)?;
+                let n = (size - res.len()).min(run.len());
+                res.extend_from_slice(&run[..n]);
+            }
+        }
+
+        if res.len() < size {
+            return Err(Error::Corrupt(e.location));
+        }
+        Ok(res)
+    }
+
+    /// What `dir` lists. Before version 4 a directory only held the
+    /// locations of its children, the rest comes from their entries.
+    pub fn records(&mut self, dir: &FileEntry) -> Result<Vec<DirRecord>> {
+        if !dir.is_dir() {
+            return Err(Error::NotDirectory(name_string(dir.name)));
+        }
+        let data = self.read_data(dir)?;
+        if self.info.version < 4 {
+            let mut res: Vec<DirRecord> = Vec::new();
+            for c in data.chunks_exact(8) {
+                let lba = u64::from_le_bytes(c.try_into().unwrap());
+                res.push(DirRecord::from_entry(&self.entry_at(lba)?));
+            }
+            return Ok(res);
+        }
+        decode_dir(&data).map_err(|_| Error::Corrupt(dir.location))
+    }
+
+    pub fn write_records(&mut self, dir: &mut FileEntry, records: &[DirRecord]) -> Result<()> {
+        let data = encode_dir(records, self.info.bytes_per_block);
+        self.write_data(dir, &data)
+    }
+
+    pub fn children(&mut self, dir: &FileEntry) -> Result<Vec<FileEntry>> {
+        let mut res: Vec<FileEntry> = Vec::new();
+        for r in self.records(dir)? {
+            res.push(self.entry_at(r.location)?);
+        }
+        Ok(res)
+    }
+
+    /// Resolves `A:/dir/file`, `/dir/file` or `dir/file`, all relative to `A:`.
+    pub fn lookup(&mut self, path: &str) -> Result<FileEntry> {
+        let rest = path.strip_prefix("A:").unwrap_or(path);
+        let mut cur = self.find_id(1)?;
+        for part in rest.split('/').filter(|p| !p.is_empty() && *p != ".") {
+            let next = self.records(&cur)?.into_iter().find(|r| r.name.as_str() == part);
+            cur = match next {
+                Some(r) => self.entry_at(r.location)?,
+                None => return Err(Error::NotFound(path.to_string())),
+            };
+        }
+        Ok(cur)
+    }
+
+    // Takes `n` free blocks from the bitmap, contiguous where possible.
+    fn alloc(&mut self, n: usize) -> Result<Vec<u64>> {
+        let mut res: Vec<u64> = Vec::with_capacity(n);
+        let blocks = self.info.blocks;
+        let mut lba = self.next_free.max(2);
+        let mut scanned = 0;
+        while res.len() < n && scanned < blocks {
+            if lba >= blocks {
+                lba = 2;
+            }
+            if !self.is_used(lba) {
+                res.push(lba);
+            }
+            lba += 1;
+            scanned += 1;
+        }
+        if res.len() < n {
+            return Err(Error::NoSpace);
+        }
+        for &b in &res {
+            self.set_used(b, true);
+        }
+        self.info.blocks_in_use += n as u64;
+        self.next_free = lba;
+        Ok(res)
+    }
+
+    pub fn free(&mut self, lbas: &[u64]) {
+        for &b in lbas {
+            self.set_used(b, false);
+        }
+        self.info.blocks_in_use -= lbas.len() as u64;
+    }
+
+    fn write_extents(&mut self, buf: &[u8]) -> Result<Vec<Extent>> {
+        if buf.is_empty() {
+            return Ok(Vec::new());
+        }
+        let bs = self.block_size();
+        let blocks = self.alloc(buf.len().div_ceil(bs))?;
+        let extents = extents_from_blocks(&blocks);
+
+        let mut off = 0;
+        for x in &extents {
+            let end = (off + x.len as usize * bs).min(buf.len());
+            let mut run = buf[off..end].to_vec();
+            run.resize(x.len as usize * bs, 0);
+            self.file.seek(SeekFrom::Start(x.start * self.info.bytes_per_block))?;
+            self.file.write_all(&run)?;
+            off = end;
+        }
+        Ok(extents)
+    }
+
+    /// Points `e` at `extents`, moving those past the inline ones to new
+    /// extent blocks. The entry itself is not written.
+    pub fn set_extents(&mut self, e: &mut FileEntry, extents: &[Extent]) -> Result<()> {
+        let inline = extents.len().min(INLINE_EXTENTS);
+        e.extent_count = extents.len() as u32;
+        e.extents = Default::default();
+        e.extents[..inline].copy_from_slice(&extents[..inline]);
+        e.start_sec = END_OF_CHAIN;
+
+        let rest = &extents[inline..];
+        if rest.is_empty() {
+            return Ok(());
+        }
+        let per = extents_per_block(self.info.bytes_per_block);
+        let blocks = self.alloc(rest.len().div_ceil(per))?;
+        for (i, chunk) in rest.chunks(per).enumerate() {
+            let next = blocks.get(i + 1).cloned().unwrap_or(END_OF_CHAIN);
+            let mut block = vec![0u8; self.block_size()];
+            ExtentBlock { next, extents: chunk.to_vec() }.encode_into(&mut block);
+            self.write_block(blocks[i], &block)?;
+        }
+        e.start_sec = blocks[0];
+        Ok(())
+    }
+
+    // Every block the data of `e` takes, extent blocks included.
+    fn data_blocks(&mut self, e: &FileEntry) -> Result<Vec<u64>> {
+        if e.uses_chain() {
+            return self.chain(e.start_sec);
+        }
+        let (extents, mut blocks) = self.extents(e)?;
+        for x in extents {
+            blocks.extend(x.start..x.end());
+        }
+        Ok(blocks)
+    }
+
+    /// Replaces the data of `e` and writes the entry back.
+    pub fn write_data(&mut self, e: &mut FileEntry, buf: &[u8]) -> Result<()> {
+        let old = self.data_blocks(e)?;
+        let extents = self.write_extents(buf)?;
+        self.set_extents(e, &extents)?;
+        e.size = buf.len() as u64;
+        e.t_edit = now();
+        self.write_block(e.location, &e.encode(self.info.version))?;
+        self.free(&old);
+        Ok(())
+    }
+
+    /// Creates an entry under the directory with id `parent_id`.
+    pub fn create(&mut self, parent_id: u64, name: &str, attributes: u8, data: &[u8]) -> Result<FileEntry> {
+        if !self.valid_name(name) {
+            return Err(Error::BadName(name.to_string()));
+        }
+        let mut parent = self.find_id(parent_id)?;
+        let mut records = self.records(&parent)?;
+        if records.iter().any(|r| r.name.as_str() == name) {
+            return Err(Error::AlreadyExists(name.to_string()));
+        }
+
+        let entries = self.entries()?;
+        let id = entries.iter().map(|e| e.id).max().unwrap_or(0) + 1;
+        let location = self.alloc(1)?[0];
+
+        let t = now();
+        let mut e = FileEntry {
+            signature: DATA_SIG,
+            name: Name::new(name).map_err(|_| Error::BadName(name.to_string()))?,
+            parent_id,
+            id,
+            attributes,
+            t_creation: t,
+            t_edit: t,
+            owner: 0,
+            size: 0,
+            start_sec: END_OF_CHAIN,
+            next_entry: END_OF_CHAIN,
+            prev_entry: self.info.final_entry,
+            location,
+            extent_count: 0,
+            extents: Default::default(),
+            links: 1,
+        };
+        self.write_data(&mut e, data)?;
+
+        let mut prev = self.entry_at(self.info.final_entry)?;
+        prev.next_entry = location;
+        self.write_block(prev.location, &prev.encode(self.info.version))?;
+        self.info.final_entry = location;
+        self.info.files += 1;
+
+        if { parent.location } == { prev.location } {
+            parent = prev;
+        }
+        records.push(DirRecord::from_entry(&e));
+        self.write_records(&mut parent, &records)?;
+
+        self.write_info()?;
+        Ok(e)
+    }
+}